@@ -0,0 +1,8 @@
+-- pending email changes, the old address stays active until the new one is confirmed
+CREATE TABLE email_changes(
+    user_id UUID PRIMARY KEY,
+    new_email VARCHAR(255) NOT NULL,
+    code UUID NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,8 @@
+-- server-side session state, used when SESSION_STORE=postgres
+CREATE TABLE session_states(
+    session_key VARCHAR(255) PRIMARY KEY,
+    state TEXT NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX session_states_expires_at_idx ON session_states(expires_at);
@@ -0,0 +1,9 @@
+-- enum for user roles, each one can do everything the previous one can:
+-- USER: regular account
+-- MODERATOR: can act on reported content and users
+-- ADMIN: can manage every account and listing
+-- the first admin has to be promoted by hand:
+-- UPDATE users SET role = 'ADMIN' WHERE nickname = '...';
+CREATE TYPE user_role AS ENUM ('USER', 'MODERATOR', 'ADMIN');
+
+ALTER TABLE users ADD COLUMN role user_role NOT NULL DEFAULT 'USER';
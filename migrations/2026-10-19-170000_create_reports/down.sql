@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS moderation_actions;
+DROP TYPE IF EXISTS moderation_action;
+ALTER TABLE listings DROP COLUMN IF EXISTS hidden;
+DROP TABLE IF EXISTS reports;
+DROP TYPE IF EXISTS report_reason;
@@ -0,0 +1,40 @@
+-- enum for why something was reported, mirrors the community guidelines
+CREATE TYPE report_reason AS ENUM ('SPAM', 'SALE', 'OFFENSIVE', 'PROHIBITED', 'SCAM', 'OTHER');
+
+-- a report points at either a listing or a user, never both
+CREATE TABLE reports(
+    id UUID PRIMARY KEY,
+    reporter_id UUID NOT NULL,
+    listing_id UUID,
+    reported_user_id UUID,
+    reason report_reason NOT NULL,
+    details VARCHAR(1024) NOT NULL DEFAULT '',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    resolved_at TIMESTAMP WITH TIME ZONE,
+    FOREIGN KEY (reporter_id) REFERENCES users(id),
+    FOREIGN KEY (listing_id) REFERENCES listings(id),
+    FOREIGN KEY (reported_user_id) REFERENCES users(id),
+    CHECK ((listing_id IS NULL) <> (reported_user_id IS NULL))
+);
+
+CREATE INDEX reports_open_idx ON reports(created_at) WHERE resolved_at IS NULL;
+
+-- hidden listings are kept but no longer shown to anyone
+ALTER TABLE listings ADD COLUMN hidden BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- enum for what a moderator did
+CREATE TYPE moderation_action AS ENUM ('HIDE_LISTING', 'SHOW_LISTING', 'WARN_USER', 'DISABLE_USER', 'DISMISS_REPORTS');
+
+-- audit trail of moderation, targets are not foreign keys so the history outlives them
+CREATE TABLE moderation_actions(
+    id UUID PRIMARY KEY,
+    moderator_id UUID,
+    action moderation_action NOT NULL,
+    listing_id UUID,
+    target_user_id UUID,
+    note VARCHAR(1024) NOT NULL DEFAULT '',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (moderator_id) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX moderation_actions_created_at_idx ON moderation_actions(created_at);
@@ -0,0 +1,4 @@
+-- postgres can't remove values from an enum, only the rows using them are removed
+DELETE FROM reports WHERE reporter_id IS NULL OR reason = 'FILTER';
+ALTER TABLE reports ALTER COLUMN reporter_id SET NOT NULL;
+DELETE FROM moderation_actions WHERE action IN ('FILTER_FLAG', 'FILTER_HIDE', 'FILTER_REJECT');
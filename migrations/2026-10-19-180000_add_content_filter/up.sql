@@ -0,0 +1,8 @@
+-- listings caught by the content filter are reported automatically, without a reporter
+ALTER TYPE report_reason ADD VALUE 'FILTER';
+ALTER TABLE reports ALTER COLUMN reporter_id DROP NOT NULL;
+
+-- decisions made by the content filter show up in the moderation history
+ALTER TYPE moderation_action ADD VALUE 'FILTER_FLAG';
+ALTER TYPE moderation_action ADD VALUE 'FILTER_HIDE';
+ALTER TYPE moderation_action ADD VALUE 'FILTER_REJECT';
@@ -0,0 +1,28 @@
+-- enum for the security- and moderation-relevant events kept in the audit log
+CREATE TYPE audit_event AS ENUM ('LOGIN', 'LOGIN_FAILED', 'PASSWORD_CHANGE', 'NICKNAME_CHANGE', 'AVATAR_REGENERATION', 'ACCOUNT_DELETION', 'LISTING_REMOVAL');
+
+-- users are not foreign keys so the log outlives deleted accounts, old entries are removed
+-- according to the retention period
+CREATE TABLE audit_log(
+    id UUID PRIMARY KEY,
+    event audit_event NOT NULL,
+    user_id UUID,
+    actor_id UUID,
+    ip VARCHAR(64) NOT NULL DEFAULT '',
+    details VARCHAR(1024) NOT NULL DEFAULT '',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX audit_log_user_id_idx ON audit_log(user_id, created_at);
+CREATE INDEX audit_log_created_at_idx ON audit_log(created_at);
+
+-- entries are never changed once written
+CREATE FUNCTION audit_log_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_no_update
+    BEFORE UPDATE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
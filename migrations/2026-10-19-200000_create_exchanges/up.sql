@@ -0,0 +1,16 @@
+-- a listing handed over to someone, recorded by its creator once the donation, loan or
+-- exchange is done. completed listings are no longer active
+CREATE TABLE exchanges(
+    id UUID PRIMARY KEY,
+    listing_id UUID NOT NULL UNIQUE,
+    owner_id UUID NOT NULL,
+    partner_id UUID NOT NULL,
+    completed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (listing_id) REFERENCES listings(id),
+    FOREIGN KEY (owner_id) REFERENCES users(id),
+    FOREIGN KEY (partner_id) REFERENCES users(id),
+    CHECK (owner_id <> partner_id)
+);
+
+CREATE INDEX exchanges_owner_id_idx ON exchanges(owner_id);
+CREATE INDEX exchanges_partner_id_idx ON exchanges(partner_id);
@@ -0,0 +1,3 @@
+-- postgres can't remove values from an enum, only the rows using them are removed
+DELETE FROM moderation_actions WHERE action = 'REMOVE_REVIEW';
+DROP TABLE IF EXISTS reviews;
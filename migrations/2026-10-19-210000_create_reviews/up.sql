@@ -0,0 +1,20 @@
+-- each side of an exchange can review the other once
+CREATE TABLE reviews(
+    id UUID PRIMARY KEY,
+    exchange_id UUID NOT NULL,
+    reviewer_id UUID NOT NULL,
+    reviewed_id UUID NOT NULL,
+    rating SMALLINT NOT NULL,
+    comment VARCHAR(500) NOT NULL DEFAULT '',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (exchange_id) REFERENCES exchanges(id),
+    FOREIGN KEY (reviewer_id) REFERENCES users(id),
+    FOREIGN KEY (reviewed_id) REFERENCES users(id),
+    UNIQUE (exchange_id, reviewer_id),
+    CHECK (rating BETWEEN 1 AND 5)
+);
+
+CREATE INDEX reviews_reviewed_id_idx ON reviews(reviewed_id, created_at);
+
+-- moderators can remove abusive reviews
+ALTER TYPE moderation_action ADD VALUE 'REMOVE_REVIEW';
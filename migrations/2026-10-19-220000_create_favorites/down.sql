@@ -0,0 +1,2 @@
+ALTER TABLE listings DROP COLUMN IF EXISTS reserved;
+DROP TABLE IF EXISTS favorites;
@@ -0,0 +1,14 @@
+-- listings a user saved to look at later
+CREATE TABLE favorites(
+    user_id UUID NOT NULL,
+    listing_id UUID NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, listing_id),
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    FOREIGN KEY (listing_id) REFERENCES listings(id)
+);
+
+CREATE INDEX favorites_listing_id_idx ON favorites(listing_id);
+
+-- the creator marks a listing as reserved while the handover is being arranged
+ALTER TABLE listings ADD COLUMN reserved BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,28 @@
+-- enum for how often a saved search sends the listings it matched
+CREATE TYPE search_frequency AS ENUM ('IMMEDIATE', 'DAILY');
+
+-- searches users want to hear about. campus and type are optional filters
+CREATE TABLE saved_searches(
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    keywords VARCHAR(255) NOT NULL,
+    campus listing_campus,
+    listing_type listing_type,
+    frequency search_frequency NOT NULL DEFAULT 'IMMEDIATE',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE INDEX saved_searches_user_id_idx ON saved_searches(user_id);
+
+-- matches of daily searches waiting for the next digest
+CREATE TABLE saved_search_matches(
+    search_id UUID NOT NULL,
+    listing_id UUID NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (search_id, listing_id),
+    FOREIGN KEY (search_id) REFERENCES saved_searches(id),
+    FOREIGN KEY (listing_id) REFERENCES listings(id)
+);
+
+CREATE INDEX saved_search_matches_listing_id_idx ON saved_search_matches(listing_id);
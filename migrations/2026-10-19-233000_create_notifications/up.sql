@@ -0,0 +1,25 @@
+-- enum for the kinds of notification users can turn off
+CREATE TYPE notification_kind AS ENUM ('MESSAGE', 'RESERVATION', 'SAVED_SEARCH', 'MODERATION');
+
+-- in-app notifications, shown in the notification center until the user reads them
+CREATE TABLE notifications(
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    kind notification_kind NOT NULL,
+    message VARCHAR(512) NOT NULL,
+    link VARCHAR(255) NOT NULL,
+    read_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE INDEX notifications_user_id_idx ON notifications(user_id, created_at);
+
+-- kinds the user changed from the default. kinds without a row are enabled
+CREATE TABLE notification_preferences(
+    user_id UUID NOT NULL,
+    kind notification_kind NOT NULL,
+    in_app BOOLEAN NOT NULL DEFAULT TRUE,
+    PRIMARY KEY (user_id, kind),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,2 @@
+-- whether the user also gets each kind of notification by email
+ALTER TABLE notification_preferences ADD COLUMN email BOOLEAN NOT NULL DEFAULT TRUE;
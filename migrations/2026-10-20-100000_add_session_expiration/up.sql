@@ -0,0 +1,5 @@
+-- logins already recorded keep working for the longest time a login can last
+ALTER TABLE user_sessions ADD COLUMN expires_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP + INTERVAL '30 days';
+ALTER TABLE user_sessions ALTER COLUMN expires_at DROP DEFAULT;
+
+CREATE INDEX user_sessions_expires_at_idx ON user_sessions(expires_at);
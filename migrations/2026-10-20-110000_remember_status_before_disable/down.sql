@@ -0,0 +1,3 @@
+-- postgres can't remove values from an enum, only the rows using them are removed
+DELETE FROM moderation_actions WHERE action = 'ENABLE_USER';
+ALTER TABLE users DROP COLUMN status_before_disable;
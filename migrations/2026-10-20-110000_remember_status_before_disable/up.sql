@@ -0,0 +1,5 @@
+-- the status a disabled account goes back to when it's enabled again
+ALTER TABLE users ADD COLUMN status_before_disable user_status;
+
+-- admins enabling accounts is recorded along with the moderators' actions
+ALTER TYPE moderation_action ADD VALUE 'ENABLE_USER';
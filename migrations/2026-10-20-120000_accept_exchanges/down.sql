@@ -0,0 +1,12 @@
+-- postgres can't remove values from an enum, only the rows using them are removed
+DELETE FROM notifications WHERE kind = 'EXCHANGE';
+DELETE FROM notification_preferences WHERE kind = 'EXCHANGE';
+
+DROP INDEX IF EXISTS exchanges_pending_idx;
+DELETE FROM reviews WHERE exchange_id IN (
+    SELECT id FROM exchanges WHERE listing_id IS NULL OR accepted_at IS NULL
+);
+DELETE FROM exchanges WHERE listing_id IS NULL OR accepted_at IS NULL;
+ALTER TABLE exchanges ALTER COLUMN listing_id SET NOT NULL;
+ALTER TABLE exchanges DROP COLUMN listing_title;
+ALTER TABLE exchanges DROP COLUMN accepted_at;
@@ -0,0 +1,15 @@
+-- the partner named by the creator of the listing has to accept the exchange before it
+-- counts or can be reviewed. the ones already recorded are kept as accepted
+ALTER TABLE exchanges ADD COLUMN accepted_at TIMESTAMP WITH TIME ZONE;
+UPDATE exchanges SET accepted_at = completed_at;
+
+-- exchanges and their reviews outlive the listing, which only leaves its title behind
+ALTER TABLE exchanges ADD COLUMN listing_title VARCHAR(255) NOT NULL DEFAULT '';
+UPDATE exchanges SET listing_title = listings.title FROM listings WHERE listings.id = exchanges.listing_id;
+ALTER TABLE exchanges ALTER COLUMN listing_title DROP DEFAULT;
+ALTER TABLE exchanges ALTER COLUMN listing_id DROP NOT NULL;
+
+CREATE INDEX exchanges_pending_idx ON exchanges(partner_id) WHERE accepted_at IS NULL;
+
+-- partners are told there's an exchange waiting for them
+ALTER TYPE notification_kind ADD VALUE 'EXCHANGE';
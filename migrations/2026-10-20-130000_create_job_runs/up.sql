@@ -0,0 +1,5 @@
+-- when each periodic job last ran, so restarts and other instances don't run it again early
+CREATE TABLE job_runs(
+    name VARCHAR(64) PRIMARY KEY,
+    last_run_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
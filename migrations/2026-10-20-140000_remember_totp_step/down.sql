@@ -0,0 +1 @@
+ALTER TABLE totp_credentials DROP COLUMN last_used_step;
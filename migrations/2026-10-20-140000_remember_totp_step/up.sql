@@ -0,0 +1,2 @@
+-- the time step of the last code accepted, so a code can't be used twice while it's valid
+ALTER TABLE totp_credentials ADD COLUMN last_used_step BIGINT;
@@ -0,0 +1,154 @@
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{get, http::StatusCode, post, web, HttpRequest, HttpResponse};
+use coisando_coisas::{
+    captcha::{Challenge, ChallengeParameters, ChallengeResponse},
+    csrf::CsrfToken,
+    rate_limit::RateLimiter,
+    DbPool,
+};
+use serde::{Deserialize, Serialize};
+
+use super::ApiError;
+use crate::pages::{
+    auth::{attempt_login, logout, LoginAttempt, LoginOutcome},
+    two_factor::{verify_pending_login, VerificationOutcome},
+};
+
+#[derive(Serialize)]
+struct CsrfJson {
+    csrf_token: String,
+}
+
+// the first request of a client, it also starts the session the token belongs to
+#[get("/auth/csrf")]
+async fn csrf_token(csrf: CsrfToken) -> web::Json<CsrfJson> {
+    web::Json(CsrfJson {
+        csrf_token: csrf.value().to_string(),
+    })
+}
+
+// a new challenge for each login, the previous one stops being valid
+#[get("/auth/challenge")]
+async fn login_challenge(
+    session: Session,
+    challenge: web::Data<dyn Challenge>,
+) -> web::Json<ChallengeParameters> {
+    web::Json(challenge.parameters(&session))
+}
+
+#[derive(Deserialize)]
+struct LoginJson {
+    // nickname or email
+    login: String,
+    password: String,
+    #[serde(default)]
+    remember: bool,
+    #[serde(flatten)]
+    challenge: ChallengeResponse,
+}
+
+#[derive(Serialize)]
+struct LoginResultJson {
+    /// when set, the code of the authenticator app goes to `/auth/two-factor` next
+    two_factor_required: bool,
+}
+
+#[post("/auth/login")]
+async fn login(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    limiter: web::Data<RateLimiter>,
+    challenge: web::Data<dyn Challenge>,
+    details: web::Json<LoginJson>,
+) -> Result<web::Json<LoginResultJson>, ApiError> {
+    let attempt = LoginAttempt {
+        login: &details.login,
+        password: &details.password,
+        remember: details.remember,
+        challenge: &details.challenge,
+    };
+    let two_factor_required =
+        match attempt_login(&req, &session, &pool, &limiter, &**challenge, attempt).await? {
+            LoginOutcome::LoggedIn => false,
+            LoginOutcome::TwoFactorRequired => true,
+            LoginOutcome::Locked => {
+                return Err(ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "locked",
+                    "Muitas tentativas. Tente novamente mais tarde",
+                ))
+            }
+            LoginOutcome::ChallengeFailed => {
+                return Err(ApiError::bad_request(
+                    "challenge_failed",
+                    "Não foi possível verificar que você não é um robô",
+                ))
+            }
+            LoginOutcome::InvalidCredentials => {
+                return Err(ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "invalid_credentials",
+                    "Apelido, email ou senha incorretos",
+                ))
+            }
+            LoginOutcome::Disabled => {
+                return Err(ApiError::forbidden("disabled", "Conta desativada"))
+            }
+        };
+    Ok(web::Json(LoginResultJson {
+        two_factor_required,
+    }))
+}
+
+#[derive(Deserialize)]
+struct CodeJson {
+    code: String,
+}
+
+#[post("/auth/two-factor")]
+async fn verify_two_factor(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    limiter: web::Data<RateLimiter>,
+    details: web::Json<CodeJson>,
+) -> Result<HttpResponse, ApiError> {
+    match verify_pending_login(&req, &session, &pool, &limiter, &details.code)? {
+        VerificationOutcome::LoggedIn => Ok(HttpResponse::NoContent().finish()),
+        VerificationOutcome::NoPendingLogin => Err(ApiError::bad_request(
+            "no_pending_login",
+            "Entre com sua senha antes de enviar o código",
+        )),
+        VerificationOutcome::Locked => Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "locked",
+            "Muitas tentativas. Tente novamente mais tarde",
+        )),
+        VerificationOutcome::InvalidCode => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_code",
+            "Código incorreto",
+        )),
+        VerificationOutcome::Disabled => Err(ApiError::forbidden("disabled", "Conta desativada")),
+    }
+}
+
+#[post("/auth/logout")]
+async fn logout_user(
+    id: Option<Identity>,
+    session: Session,
+    pool: web::Data<DbPool>,
+) -> HttpResponse {
+    logout(id, &session, &pool);
+    HttpResponse::NoContent().finish()
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(csrf_token)
+        .service(login_challenge)
+        .service(login)
+        .service(verify_two_factor)
+        .service(logout_user);
+}
@@ -0,0 +1,519 @@
+use std::{collections::HashSet, env};
+
+use actix_multipart::form::{tempfile::TempFile, text::Text, MultipartForm};
+use actix_web::{delete, get, http::StatusCode, patch, post, web, HttpRequest, HttpResponse};
+use aws_sdk_s3::Client;
+use chrono::{DateTime, Utc};
+use coisando_coisas::{
+    content_filter::ContentFilter,
+    favorites,
+    listings::{
+        self, ListingDetails, ListingFilter, ListingSummary, Submission, DESCRIPTION_MAX_LENGTH,
+        MAX_IMAGES, TITLE_MAX_LENGTH,
+    },
+    reviews, saved_searches, storage, Campus, DbConn, DbPool, LocalUser, Role, Type,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{page, require_user, ApiError, UserSummary};
+
+#[derive(Serialize)]
+pub struct ListingJson {
+    id: Uuid,
+    title: String,
+    description: String,
+    #[serde(rename = "type")]
+    listing_type: &'static str,
+    campus: &'static str,
+    reserved: bool,
+    /// only the poster and moderators see hidden listings
+    hidden: bool,
+    /// whether the logged in user saved it
+    saved: bool,
+    images: Vec<String>,
+    created_at: DateTime<Utc>,
+    creator: UserSummary,
+}
+
+/// the listings as the api shows them, along with their images and posters
+pub fn listings_json(
+    conn: &mut DbConn,
+    results: Vec<ListingSummary>,
+    viewer_id: Option<Uuid>,
+) -> diesel::QueryResult<Vec<ListingJson>> {
+    let creator_ids: Vec<Uuid> = results.iter().map(|row| row.creator_id).collect();
+    let listing_ids: Vec<Uuid> = results.iter().map(|row| row.id).collect();
+    let reputations = reviews::reputations(conn, &creator_ids)?;
+    let mut attachments = listings::attachment_ids_among(conn, &listing_ids)?;
+    let saved = match viewer_id {
+        Some(viewer_id) => favorites::saved_among(conn, viewer_id, &listing_ids)?,
+        None => HashSet::new(),
+    };
+
+    let mut listings = Vec::with_capacity(results.len());
+    for row in results {
+        let images = attachments
+            .remove(&row.id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|attachment_id| storage::attachment_url(row.creator_id, attachment_id))
+            .collect();
+        listings.push(ListingJson {
+            id: row.id,
+            saved: saved.contains(&row.id),
+            images,
+            creator: UserSummary::new(
+                row.creator_nickname,
+                row.creator_avatar_seed,
+                reputations.get(&row.creator_id).copied(),
+            ),
+            title: row.title,
+            description: row.description,
+            listing_type: row.listing_type.code(),
+            campus: row.campus.code(),
+            reserved: row.reserved,
+            hidden: row.hidden,
+            created_at: row.created_at,
+        });
+    }
+    Ok(listings)
+}
+
+// the single listing of a response
+fn listing_json(
+    conn: &mut DbConn,
+    listing: ListingSummary,
+    viewer_id: Option<Uuid>,
+) -> Result<ListingJson, ApiError> {
+    let Ok(mut results) = listings_json(conn, vec![listing], viewer_id) else {
+        return Err(ApiError::internal("Não foi possível obter o item"));
+    };
+    results
+        .pop()
+        .ok_or_else(|| ApiError::internal("Não foi possível obter o item"))
+}
+
+fn parse_campus(code: &str) -> Result<Campus, ApiError> {
+    Campus::from_code(code)
+        .ok_or_else(|| ApiError::bad_request("invalid_campus", "Campus inválido"))
+}
+
+fn parse_type(code: &str) -> Result<Type, ApiError> {
+    Type::from_code(code)
+        .ok_or_else(|| ApiError::bad_request("invalid_type", "Tipo de anúncio inválido"))
+}
+
+// the limits of the columns, checked here so the client gets a proper error
+fn validate_details(details: &ListingDetails) -> Result<(), ApiError> {
+    if details.title.trim().is_empty() || details.title.chars().count() > TITLE_MAX_LENGTH {
+        return Err(ApiError::bad_request(
+            "invalid_title",
+            format!(
+                "O título deve ter entre 1 e {} caracteres",
+                TITLE_MAX_LENGTH
+            ),
+        ));
+    }
+    if details.description.chars().count() > DESCRIPTION_MAX_LENGTH {
+        return Err(ApiError::bad_request(
+            "invalid_description",
+            format!(
+                "A descrição deve ter no máximo {} caracteres",
+                DESCRIPTION_MAX_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn content_rejected() -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "content_rejected",
+        "O anúncio não segue as diretrizes da comunidade. Itens são doados, emprestados ou trocados, nunca vendidos, e o contato acontece pela plataforma.",
+    )
+}
+
+fn too_many_images() -> ApiError {
+    ApiError::bad_request(
+        "too_many_images",
+        format!("Um item pode ter no máximo {} imagens", MAX_IMAGES),
+    )
+}
+
+// the listing, when it belongs to the user
+fn owned_listing(
+    conn: &mut DbConn,
+    listing_id: Uuid,
+    user_id: Uuid,
+) -> Result<ListingSummary, ApiError> {
+    let Ok(listing) = listings::find(conn, listing_id) else {
+        return Err(ApiError::internal("Não foi possível obter o item"));
+    };
+    let Some(listing) = listing else {
+        return Err(ApiError::not_found("Item não encontrado"));
+    };
+    if listing.creator_id != user_id {
+        return Err(ApiError::forbidden(
+            "not_owner",
+            "Só quem publicou o item pode alterá-lo",
+        ));
+    }
+    Ok(listing)
+}
+
+#[derive(Deserialize)]
+struct ListingsQuery {
+    /// words looked for in the title and the description
+    q: Option<String>,
+    campus: Option<String>,
+    #[serde(rename = "type")]
+    listing_type: Option<String>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ListingsPage {
+    listings: Vec<ListingJson>,
+    offset: i64,
+    limit: i64,
+}
+
+#[get("/listings")]
+async fn list_listings(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    query: web::Query<ListingsQuery>,
+) -> Result<web::Json<ListingsPage>, ApiError> {
+    let query = query.into_inner();
+    let filter = ListingFilter {
+        keywords: query.q.filter(|q| !q.trim().is_empty()),
+        campus: query.campus.as_deref().map(parse_campus).transpose()?,
+        listing_type: query.listing_type.as_deref().map(parse_type).transpose()?,
+        creator_id: None,
+    };
+    let (offset, limit) = page(query.offset, query.limit);
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let viewer_id = require_user(&local_user).ok();
+    let Ok(listings) = listings::search(&mut conn, &filter, limit, offset)
+        .and_then(|results| listings_json(&mut conn, results, viewer_id))
+    else {
+        return Err(ApiError::internal("Não foi possível obter os itens"));
+    };
+
+    Ok(web::Json(ListingsPage {
+        listings,
+        offset,
+        limit,
+    }))
+}
+
+#[get("/listings/{listing_id}")]
+async fn get_listing(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> Result<web::Json<ListingJson>, ApiError> {
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(listing) = listings::find(&mut conn, path.into_inner()) else {
+        return Err(ApiError::internal("Não foi possível obter o item"));
+    };
+    let viewer_id = require_user(&local_user).ok();
+
+    // hidden listings look the same as missing ones to everyone else
+    let Some(listing) = listing.filter(|listing| {
+        !listing.hidden
+            || viewer_id == Some(listing.creator_id)
+            || local_user.has_role(Role::Moderator)
+    }) else {
+        return Err(ApiError::not_found("Item não encontrado"));
+    };
+
+    Ok(web::Json(listing_json(&mut conn, listing, viewer_id)?))
+}
+
+#[derive(MultipartForm)]
+struct NewListingForm {
+    title: Text<String>,
+    description: Option<Text<String>>,
+    #[multipart(rename = "type")]
+    listing_type: Text<String>,
+    campus: Text<String>,
+    #[multipart(limit = "10MB")]
+    images: Vec<TempFile>,
+}
+
+// sent as multipart along with the images, like the site's form: a listing always has at least
+// one. more can be added later through `/listings/{id}/attachments`
+#[post("/listings")]
+async fn create_listing(
+    req: HttpRequest,
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    s3_client: web::Data<Client>,
+    content_filter: web::Data<ContentFilter>,
+    MultipartForm(form): MultipartForm<NewListingForm>,
+) -> Result<HttpResponse, ApiError> {
+    let creator_id = require_user(&local_user)?;
+    if form.images.is_empty() {
+        return Err(ApiError::bad_request(
+            "no_images",
+            "Adicione pelo menos uma imagem",
+        ));
+    }
+    if form.images.len() > MAX_IMAGES {
+        return Err(too_many_images());
+    }
+    let details = ListingDetails {
+        listing_type: parse_type(&form.listing_type)?,
+        campus: parse_campus(&form.campus)?,
+        title: form.title.into_inner(),
+        description: form.description.map(Text::into_inner).unwrap_or_default(),
+    };
+    validate_details(&details)?;
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let (listing_id, hidden) =
+        match listings::create_listing(&mut conn, &content_filter, creator_id, &details) {
+            Ok(Submission::Accepted { listing_id, hidden }) => (listing_id, hidden),
+            Ok(Submission::Rejected) => return Err(content_rejected()),
+            Err(e) => {
+                log::error!("Não foi possível criar o item: {:?}", e);
+                return Err(ApiError::internal("Não foi possível criar o item"));
+            }
+        };
+
+    // upload images to cloudflare r2. a listing none of them reached is removed again
+    let mut uploaded = 0;
+    for image in form.images {
+        if storage::upload_attachment(&s3_client, &mut conn, creator_id, listing_id, image)
+            .await
+            .is_some()
+        {
+            uploaded += 1;
+        }
+    }
+    if uploaded == 0 {
+        let mg_api_key =
+            env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+        if let Err(e) = listings::delete_listing(
+            &mut conn,
+            &req,
+            &s3_client,
+            &mg_api_key,
+            listing_id,
+            creator_id,
+        )
+        .await
+        {
+            log::error!(
+                "Não foi possível remover o item {} sem imagens: {:?}",
+                listing_id,
+                e
+            );
+        }
+        return Err(ApiError::internal("Não foi possível enviar as imagens"));
+    }
+
+    // let the users whose saved searches match know, hidden listings wait for moderation
+    if !hidden {
+        let mg_api_key =
+            env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+        saved_searches::announce_listing(pool.get_ref().clone(), mg_api_key, listing_id);
+    }
+
+    let Ok(Some(listing)) = listings::find(&mut conn, listing_id) else {
+        return Err(ApiError::internal("Não foi possível obter o item"));
+    };
+    Ok(HttpResponse::Created()
+        .append_header(("Location", format!("/api/v1/listings/{}", listing_id)))
+        .json(listing_json(&mut conn, listing, Some(creator_id))?))
+}
+
+#[derive(Deserialize)]
+struct ListingChangesJson {
+    title: Option<String>,
+    description: Option<String>,
+    #[serde(rename = "type")]
+    listing_type: Option<String>,
+    campus: Option<String>,
+}
+
+// only the fields that are sent change
+#[patch("/listings/{listing_id}")]
+async fn update_listing(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    content_filter: web::Data<ContentFilter>,
+    path: web::Path<Uuid>,
+    changes: web::Json<ListingChangesJson>,
+) -> Result<web::Json<ListingJson>, ApiError> {
+    let user_id = require_user(&local_user)?;
+    let listing_id = path.into_inner();
+    let changes = changes.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let listing = owned_listing(&mut conn, listing_id, user_id)?;
+    let details = ListingDetails {
+        listing_type: match changes.listing_type {
+            Some(code) => parse_type(&code)?,
+            None => listing.listing_type,
+        },
+        campus: match changes.campus {
+            Some(code) => parse_campus(&code)?,
+            None => listing.campus,
+        },
+        title: changes.title.unwrap_or(listing.title),
+        description: changes.description.unwrap_or(listing.description),
+    };
+    validate_details(&details)?;
+
+    match listings::update_listing(&mut conn, &content_filter, listing_id, user_id, &details) {
+        Ok(Some(Submission::Accepted { .. })) => {}
+        Ok(Some(Submission::Rejected)) => return Err(content_rejected()),
+        Ok(None) => return Err(ApiError::not_found("Item não encontrado")),
+        Err(e) => {
+            log::error!("Não foi possível alterar o item {}: {:?}", listing_id, e);
+            return Err(ApiError::internal("Não foi possível alterar o item"));
+        }
+    }
+
+    let Ok(Some(listing)) = listings::find(&mut conn, listing_id) else {
+        return Err(ApiError::internal("Não foi possível obter o item"));
+    };
+    Ok(web::Json(listing_json(&mut conn, listing, Some(user_id))?))
+}
+
+#[delete("/listings/{listing_id}")]
+async fn delete_listing(
+    req: HttpRequest,
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    s3_client: web::Data<Client>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = require_user(&local_user)?;
+    let listing_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    owned_listing(&mut conn, listing_id, user_id)?;
+
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    if listings::delete_listing(
+        &mut conn,
+        &req,
+        &s3_client,
+        &mg_api_key,
+        listing_id,
+        user_id,
+    )
+    .await
+    .is_err()
+    {
+        return Err(ApiError::internal("Não foi possível remover o anúncio"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(MultipartForm)]
+struct ImagesForm {
+    #[multipart(limit = "10MB")]
+    images: Vec<TempFile>,
+}
+
+// the images are added to the ones the listing already has
+#[post("/listings/{listing_id}/attachments")]
+async fn upload_attachments(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    s3_client: web::Data<Client>,
+    path: web::Path<Uuid>,
+    MultipartForm(form): MultipartForm<ImagesForm>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = require_user(&local_user)?;
+    let listing_id = path.into_inner();
+    if form.images.is_empty() {
+        return Err(ApiError::bad_request(
+            "no_images",
+            "Adicione pelo menos uma imagem",
+        ));
+    }
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    owned_listing(&mut conn, listing_id, user_id)?;
+    let Ok(existing) = listings::attachment_ids(&mut conn, listing_id) else {
+        return Err(ApiError::internal("Não foi possível obter as imagens"));
+    };
+    if existing.len() + form.images.len() > MAX_IMAGES {
+        return Err(too_many_images());
+    }
+
+    // upload images to cloudflare r2
+    let mut uploaded = 0;
+    for image in form.images {
+        if storage::upload_attachment(&s3_client, &mut conn, user_id, listing_id, image)
+            .await
+            .is_some()
+        {
+            uploaded += 1;
+        }
+    }
+    if uploaded == 0 {
+        return Err(ApiError::internal("Não foi possível enviar as imagens"));
+    }
+
+    let Ok(Some(listing)) = listings::find(&mut conn, listing_id) else {
+        return Err(ApiError::internal("Não foi possível obter o item"));
+    };
+    Ok(HttpResponse::Created().json(listing_json(&mut conn, listing, Some(user_id))?))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_listings)
+        .service(get_listing)
+        .service(create_listing)
+        .service(update_listing)
+        .service(delete_listing)
+        .service(upload_attachments);
+}
@@ -0,0 +1,194 @@
+// versioned json api under `/api/v1`, for the mobile app and scripts. it uses the same
+// session cookie as the site: log in through `/auth/login` and send the token from
+// `/auth/csrf` in the `X-CSRF-Token` header of every request that changes something
+
+use std::fmt;
+
+use actix_multipart::form::MultipartFormConfig;
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use coisando_coisas::{reviews::Reputation, LocalUser};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::pages::components::avatar_url;
+
+pub mod auth;
+pub mod listings;
+pub mod users;
+
+// how many results a list returns when the client doesn't say, and at most
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// every error is answered as `{"error": {"code": "...", "message": "..."}}`. the code is
+/// meant for programs and doesn't change, the message is shown to the user
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, code, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetails<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetails<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(ErrorBody {
+            error: ErrorDetails {
+                code: self.code,
+                message: &self.message,
+            },
+        })
+    }
+}
+
+// the functions shared with `pages` fail with plain text errors, passed along keeping the
+// status
+impl From<actix_web::Error> for ApiError {
+    fn from(error: actix_web::Error) -> Self {
+        let status = error.as_response_error().status_code();
+        let code = if status.is_server_error() {
+            "internal"
+        } else {
+            "bad_request"
+        };
+        Self::new(status, code, error.to_string())
+    }
+}
+
+/// the id of the logged in user, or the error telling why there's none
+pub fn require_user(local_user: &LocalUser) -> Result<Uuid, ApiError> {
+    match local_user {
+        LocalUser::Authenticated { id, .. } => Ok(*id),
+        LocalUser::Anonymous => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthenticated",
+            "Usuário não autenticado",
+        )),
+        LocalUser::Pending => Err(ApiError::forbidden(
+            "unconfirmed",
+            "Confirme seu email para continuar",
+        )),
+        LocalUser::Disabled => Err(ApiError::forbidden("disabled", "Conta desativada")),
+    }
+}
+
+/// the offset and the number of results asked for, limited to `MAX_PAGE_SIZE`
+fn page(offset: Option<i64>, limit: Option<i64>) -> (i64, i64) {
+    (
+        offset.unwrap_or(0).max(0),
+        limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+    )
+}
+
+#[derive(Serialize)]
+pub struct ReputationJson {
+    pub average: f64,
+    pub count: i64,
+}
+
+impl From<Reputation> for ReputationJson {
+    fn from(reputation: Reputation) -> Self {
+        Self {
+            average: reputation.average(),
+            count: reputation.count,
+        }
+    }
+}
+
+/// how another user is shown next to what they posted
+#[derive(Serialize)]
+pub struct UserSummary {
+    pub nickname: String,
+    pub avatar_url: String,
+    pub reputation: Option<ReputationJson>,
+}
+
+impl UserSummary {
+    pub fn new(nickname: String, avatar_seed: Uuid, reputation: Option<Reputation>) -> Self {
+        Self {
+            nickname,
+            avatar_url: avatar_url(avatar_seed),
+            reputation: reputation.map(ReputationJson::from),
+        }
+    }
+}
+
+async fn not_found() -> HttpResponse {
+    ApiError::not_found("Rota não encontrada").error_response()
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    // requests that can't be read are answered in the same format as every other error
+    cfg.service(
+        web::scope("/api/v1")
+            .app_data(
+                web::JsonConfig::default().error_handler(|e, _| {
+                    ApiError::bad_request("invalid_body", e.to_string()).into()
+                }),
+            )
+            .app_data(
+                web::QueryConfig::default().error_handler(|e, _| {
+                    ApiError::bad_request("invalid_query", e.to_string()).into()
+                }),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|e, _| ApiError::not_found(e.to_string()).into()),
+            )
+            .app_data(
+                MultipartFormConfig::default().error_handler(|e, _| {
+                    ApiError::bad_request("invalid_body", e.to_string()).into()
+                }),
+            )
+            .configure(auth::config)
+            .configure(listings::config)
+            .configure(users::config)
+            .default_service(web::to(not_found)),
+    );
+}
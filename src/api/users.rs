@@ -0,0 +1,160 @@
+use actix_web::{get, web};
+use chrono::{DateTime, Utc};
+use coisando_coisas::{
+    exchanges,
+    listings::{self, ListingFilter},
+    lower, reviews,
+    schema::users,
+    two_factor, AccountStatus, DbPool, LocalUser, Role,
+};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{
+    listings::{listings_json, ListingJson},
+    require_user, ApiError, ReputationJson, MAX_PAGE_SIZE,
+};
+use crate::pages::components::avatar_url;
+
+#[derive(Serialize)]
+struct ProfileJson {
+    nickname: String,
+    avatar_url: String,
+    member_since: DateTime<Utc>,
+    reputation: Option<ReputationJson>,
+    completed_exchanges: i64,
+    /// the ones still available, newest first
+    listings: Vec<ListingJson>,
+}
+
+#[get("/users/{nickname}")]
+async fn get_profile(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> Result<web::Json<ProfileJson>, ApiError> {
+    let nickname = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // disabled and unconfirmed accounts have no public profile
+    let Ok(user) = users::table
+        .filter(lower(users::nickname).eq(nickname.to_lowercase()))
+        .filter(users::status.eq(AccountStatus::CONFIRMED))
+        .select((
+            users::id,
+            users::nickname,
+            users::avatar_seed,
+            users::created_at,
+        ))
+        .first::<(Uuid, String, Uuid, DateTime<Utc>)>(&mut conn)
+        .optional()
+    else {
+        return Err(ApiError::internal("Não foi possível obter o perfil"));
+    };
+    let Some((user_id, nickname, avatar_seed, created_at)) = user else {
+        return Err(ApiError::not_found("Usuário não encontrado"));
+    };
+
+    let Ok(completed_exchanges) = exchanges::completed_count(&mut conn, user_id) else {
+        return Err(ApiError::internal("Não foi possível obter as trocas"));
+    };
+    let Ok(reputation) = reviews::reputation(&mut conn, user_id) else {
+        return Err(ApiError::internal("Não foi possível obter as avaliações"));
+    };
+
+    let filter = ListingFilter {
+        creator_id: Some(user_id),
+        ..Default::default()
+    };
+    let viewer_id = require_user(&local_user).ok();
+    let Ok(listings) = listings::search(&mut conn, &filter, MAX_PAGE_SIZE, 0)
+        .and_then(|results| listings_json(&mut conn, results, viewer_id))
+    else {
+        return Err(ApiError::internal("Não foi possível obter os anúncios"));
+    };
+
+    Ok(web::Json(ProfileJson {
+        nickname,
+        avatar_url: avatar_url(avatar_seed),
+        member_since: created_at,
+        reputation: reputation.map(ReputationJson::from),
+        completed_exchanges,
+        listings,
+    }))
+}
+
+#[derive(Serialize)]
+struct AccountJson {
+    id: Uuid,
+    nickname: String,
+    email: String,
+    avatar_url: String,
+    /// `user`, `moderator` or `admin`
+    role: &'static str,
+    two_factor_enabled: bool,
+    unread_notifications: i64,
+}
+
+#[get("/me")]
+async fn get_account(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+) -> Result<web::Json<AccountJson>, ApiError> {
+    let user_id = require_user(&local_user)?;
+    let LocalUser::Authenticated {
+        nickname,
+        avatar_seed,
+        role,
+        unread_notifications,
+        ..
+    } = local_user
+    else {
+        return Err(ApiError::internal(
+            "Não foi possível obter suas informações",
+        ));
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ApiError::internal(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let (Ok(email), Ok(two_factor_enabled)) = (
+        users::table
+            .find(user_id)
+            .select(users::email)
+            .first::<String>(&mut conn),
+        two_factor::is_enabled(&mut conn, user_id),
+    ) else {
+        return Err(ApiError::internal(
+            "Não foi possível obter suas informações",
+        ));
+    };
+
+    Ok(web::Json(AccountJson {
+        id: user_id,
+        nickname,
+        email,
+        avatar_url: avatar_url(avatar_seed),
+        role: match role {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        },
+        two_factor_enabled,
+        unread_notifications,
+    }))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_profile).service(get_account);
+}
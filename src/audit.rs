@@ -0,0 +1,138 @@
+// append-only log of security- and moderation-relevant events, kept for the period set by the
+// privacy policy and then removed
+
+use std::{fmt, io::Write};
+
+use actix_web::HttpRequest;
+use chrono::{Duration, Utc};
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::Pg,
+    serialize::{IsNull, ToSql},
+    ExpressionMethods, QueryDsl, QueryResult, RunQueryDsl,
+};
+use uuid::Uuid;
+
+use crate::{
+    client_ip, env_or,
+    schema::{audit_log, sql_types::AuditEvent as AuditEventType},
+    DbConn,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression)]
+#[diesel(sql_type = AuditEventType)]
+pub enum AuditEvent {
+    Login,
+    LoginFailed,
+    PasswordChange,
+    NicknameChange,
+    AvatarRegeneration,
+    AccountDeletion,
+    ListingRemoval,
+}
+
+impl AuditEvent {
+    /// value used in exports
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuditEvent::Login => "login",
+            AuditEvent::LoginFailed => "login-falhou",
+            AuditEvent::PasswordChange => "senha-alterada",
+            AuditEvent::NicknameChange => "apelido-alterado",
+            AuditEvent::AvatarRegeneration => "avatar-gerado",
+            AuditEvent::AccountDeletion => "conta-deletada",
+            AuditEvent::ListingRemoval => "anuncio-removido",
+        }
+    }
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditEvent::Login => write!(f, "Login"),
+            AuditEvent::LoginFailed => write!(f, "Login recusado"),
+            AuditEvent::PasswordChange => write!(f, "Senha alterada"),
+            AuditEvent::NicknameChange => write!(f, "Apelido alterado"),
+            AuditEvent::AvatarRegeneration => write!(f, "Avatar gerado"),
+            AuditEvent::AccountDeletion => write!(f, "Conta deletada"),
+            AuditEvent::ListingRemoval => write!(f, "Anúncio removido"),
+        }
+    }
+}
+
+impl ToSql<AuditEventType, Pg> for AuditEvent {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            AuditEvent::Login => out.write_all(b"LOGIN")?,
+            AuditEvent::LoginFailed => out.write_all(b"LOGIN_FAILED")?,
+            AuditEvent::PasswordChange => out.write_all(b"PASSWORD_CHANGE")?,
+            AuditEvent::NicknameChange => out.write_all(b"NICKNAME_CHANGE")?,
+            AuditEvent::AvatarRegeneration => out.write_all(b"AVATAR_REGENERATION")?,
+            AuditEvent::AccountDeletion => out.write_all(b"ACCOUNT_DELETION")?,
+            AuditEvent::ListingRemoval => out.write_all(b"LISTING_REMOVAL")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<AuditEventType, Pg> for AuditEvent {
+    fn from_sql(
+        bytes: <Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"LOGIN" => Ok(AuditEvent::Login),
+            b"LOGIN_FAILED" => Ok(AuditEvent::LoginFailed),
+            b"PASSWORD_CHANGE" => Ok(AuditEvent::PasswordChange),
+            b"NICKNAME_CHANGE" => Ok(AuditEvent::NicknameChange),
+            b"AVATAR_REGENERATION" => Ok(AuditEvent::AvatarRegeneration),
+            b"ACCOUNT_DELETION" => Ok(AuditEvent::AccountDeletion),
+            b"LISTING_REMOVAL" => Ok(AuditEvent::ListingRemoval),
+            _ => Err("Unknown audit event".into()),
+        }
+    }
+}
+
+/// how long entries are kept, read from `AUDIT_LOG_RETENTION_DAYS`
+pub fn retention() -> Duration {
+    Duration::days(env_or("AUDIT_LOG_RETENTION_DAYS", 180))
+}
+
+/// appends an entry to the log. `user_id` is the account the event is about and `actor_id`
+/// whoever caused it when that's someone else, like the admin who removed a listing
+pub fn record(
+    conn: &mut DbConn,
+    req: &HttpRequest,
+    event: AuditEvent,
+    user_id: Option<Uuid>,
+    actor_id: Option<Uuid>,
+    details: &str,
+) -> QueryResult<()> {
+    let ip = client_ip(req)
+        .unwrap_or_default()
+        .chars()
+        .take(64)
+        .collect::<String>();
+    let details = details.chars().take(1024).collect::<String>();
+
+    diesel::insert_into(audit_log::table)
+        .values((
+            audit_log::id.eq(Uuid::new_v4()),
+            audit_log::event.eq(event),
+            audit_log::user_id.eq(user_id),
+            audit_log::actor_id.eq(actor_id),
+            audit_log::ip.eq(ip),
+            audit_log::details.eq(details),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// removes the entries older than the retention period, returning how many were removed
+pub fn purge_expired(conn: &mut DbConn) -> QueryResult<usize> {
+    diesel::delete(audit_log::table.filter(audit_log::created_at.lt(Utc::now() - retention())))
+        .execute(conn)
+}
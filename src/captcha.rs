@@ -0,0 +1,435 @@
+// challenges shown on the login and registration forms to slow down bots. the built-in
+// proof-of-work needs no external service, hCaptcha and Turnstile can be used instead
+
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use actix_session::Session;
+use chrono::Utc;
+use maud::{html, Markup, PreEscaped};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::env_or;
+
+// session key entry holding the proof-of-work challenge issued with the last form
+const POW_CHALLENGE_KEY: &str = "pow_challenge";
+// how long a proof-of-work challenge can be solved for
+const POW_EXPIRATION_SECONDS: i64 = 600;
+
+/// fields sent by the form, each provider only looks at its own
+#[derive(Debug, Default, Deserialize)]
+pub struct ChallengeResponse {
+    pub pow_nonce: Option<String>,
+    #[serde(rename = "h-captcha-response")]
+    pub hcaptcha: Option<String>,
+    #[serde(rename = "cf-turnstile-response")]
+    pub turnstile: Option<String>,
+}
+
+/// what a client without the form needs to answer the challenge, sent by the api
+#[derive(Debug, Serialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum ChallengeParameters {
+    /// find `pow_nonce` as the form's script does
+    Pow { challenge: String, difficulty: u32 },
+    /// send the widget's token as `h-captcha-response`
+    Hcaptcha { site_key: String },
+    /// send the widget's token as `cf-turnstile-response`
+    Turnstile { site_key: String },
+}
+
+pub type VerifyFuture<'a> = Pin<Box<dyn Future<Output = bool> + 'a>>;
+
+/// a challenge that can be put in a form and checked when the form comes back
+pub trait Challenge: Send + Sync {
+    /// markup inserted in the form, before the submit button
+    fn render(&self, session: &Session) -> Markup;
+
+    /// the same challenge for clients that don't render the form
+    fn parameters(&self, session: &Session) -> ChallengeParameters;
+
+    /// checks the answer, `remote_ip` is forwarded to the providers that use it
+    fn verify<'a>(
+        &'a self,
+        session: &'a Session,
+        response: &'a ChallengeResponse,
+        remote_ip: Option<&'a str>,
+    ) -> VerifyFuture<'a>;
+}
+
+/// picks the challenge with `CAPTCHA_PROVIDER`: `pow` (default), `hcaptcha` or `turnstile`
+pub fn from_env() -> Arc<dyn Challenge> {
+    let provider = env::var("CAPTCHA_PROVIDER").unwrap_or_else(|_| "pow".to_string());
+    match provider.as_str() {
+        "pow" => Arc::new(ProofOfWork::new(env_or("POW_DIFFICULTY", 16))),
+        "hcaptcha" => Arc::new(RemoteCaptcha::hcaptcha(
+            env::var("HCAPTCHA_SITE_KEY").expect("HCAPTCHA_SITE_KEY must be set"),
+            env::var("HCAPTCHA_SECRET").expect("HCAPTCHA_SECRET must be set"),
+        )),
+        "turnstile" => Arc::new(RemoteCaptcha::turnstile(
+            env::var("TURNSTILE_SITE_KEY").expect("TURNSTILE_SITE_KEY must be set"),
+            env::var("TURNSTILE_SECRET").expect("TURNSTILE_SECRET must be set"),
+        )),
+        _ => panic!("Unknown CAPTCHA_PROVIDER: {}", provider),
+    }
+}
+
+/// the browser has to find a nonce such that sha256("{challenge}:{nonce}") starts with
+/// `difficulty` zero bits. cheap for one login, expensive for thousands
+pub struct ProofOfWork {
+    difficulty: u32,
+    // challenges already answered, with when they were issued. the session may live in a
+    // cookie the client can send again, so they're also remembered here until they expire.
+    // kept in memory, so each instance keeps its own
+    spent: Mutex<HashMap<String, i64>>,
+}
+
+impl ProofOfWork {
+    pub fn new(difficulty: u32) -> Self {
+        Self {
+            difficulty,
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn leading_zero_bits(digest: &[u8]) -> u32 {
+        let mut bits = 0;
+        for byte in digest {
+            if *byte == 0 {
+                bits += 8;
+            } else {
+                bits += byte.leading_zeros();
+                break;
+            }
+        }
+        bits
+    }
+
+    fn check(&self, challenge: &str, nonce: &str) -> bool {
+        let digest = Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes());
+        Self::leading_zero_bits(&digest) >= self.difficulty
+    }
+
+    // a new challenge for every form, the previous one stops being valid
+    fn issue(&self, session: &Session) -> String {
+        let challenge = Uuid::new_v4().simple().to_string();
+        if let Err(e) = session.insert(POW_CHALLENGE_KEY, (&challenge, Utc::now().timestamp())) {
+            log::error!("Não foi possível salvar o desafio na sessão: {:?}", e);
+        }
+        challenge
+    }
+
+    // marks the challenge as answered, false when it already was
+    fn spend(&self, challenge: &str, issued_at: i64) -> bool {
+        let now = Utc::now().timestamp();
+        let mut spent = self.spent.lock().unwrap_or_else(|e| e.into_inner());
+        spent.retain(|_, issued_at| *issued_at + POW_EXPIRATION_SECONDS >= now);
+        spent.insert(challenge.to_string(), issued_at).is_none()
+    }
+}
+
+const POW_SCRIPT: &str = r#"
+(async function () {
+    const input = document.getElementById("pow_nonce");
+    const button = input.form.querySelector("button[type=submit]");
+    const status = document.getElementById("pow_status");
+    const difficulty = parseInt(input.dataset.difficulty);
+    const encoder = new TextEncoder();
+    const leadingZeroBits = (digest) => {
+        let bits = 0;
+        for (const byte of digest) {
+            if (byte === 0) { bits += 8; continue; }
+            bits += Math.clz32(byte) - 24;
+            break;
+        }
+        return bits;
+    };
+    button.disabled = true;
+    for (let nonce = 0; ; nonce++) {
+        const data = encoder.encode(input.dataset.challenge + ":" + nonce);
+        const digest = new Uint8Array(await crypto.subtle.digest("SHA-256", data));
+        if (leadingZeroBits(digest) >= difficulty) {
+            input.value = nonce;
+            break;
+        }
+    }
+    status.textContent = "Verificação concluída.";
+    button.disabled = false;
+})();
+"#;
+
+impl Challenge for ProofOfWork {
+    fn render(&self, session: &Session) -> Markup {
+        let challenge = self.issue(session);
+        html! {
+            input type="hidden" id="pow_nonce" name="pow_nonce" data-challenge=(challenge) data-difficulty=(self.difficulty);
+            small .text-muted #pow_status { "Verificando que você não é um robô..." }
+            script { (PreEscaped(POW_SCRIPT)) }
+        }
+    }
+
+    fn parameters(&self, session: &Session) -> ChallengeParameters {
+        ChallengeParameters::Pow {
+            challenge: self.issue(session),
+            difficulty: self.difficulty,
+        }
+    }
+
+    fn verify<'a>(
+        &'a self,
+        session: &'a Session,
+        response: &'a ChallengeResponse,
+        _remote_ip: Option<&'a str>,
+    ) -> VerifyFuture<'a> {
+        Box::pin(async move {
+            // challenges can only be used once
+            let Ok(Some((challenge, issued_at))) = session
+                .remove_as::<(String, i64)>(POW_CHALLENGE_KEY)
+                .transpose()
+            else {
+                return false;
+            };
+            let Some(ref nonce) = response.pow_nonce else {
+                return false;
+            };
+            if issued_at + POW_EXPIRATION_SECONDS < Utc::now().timestamp() {
+                return false;
+            }
+            if !self.spend(&challenge, issued_at) {
+                return false;
+            }
+            self.check(&challenge, nonce)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+/// hCaptcha and Turnstile share the same widget and verification API, only the urls and
+/// field names change
+pub struct RemoteCaptcha {
+    script_url: &'static str,
+    widget_class: &'static str,
+    verify_url: String,
+    site_key: String,
+    secret: String,
+}
+
+impl RemoteCaptcha {
+    pub fn hcaptcha(site_key: String, secret: String) -> Self {
+        Self {
+            script_url: "https://js.hcaptcha.com/1/api.js",
+            widget_class: "h-captcha",
+            verify_url: "https://api.hcaptcha.com/siteverify".to_string(),
+            site_key,
+            secret,
+        }
+    }
+
+    pub fn turnstile(site_key: String, secret: String) -> Self {
+        Self {
+            script_url: "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            widget_class: "cf-turnstile",
+            verify_url: "https://challenges.cloudflare.com/turnstile/v0/siteverify".to_string(),
+            site_key,
+            secret,
+        }
+    }
+
+    /// checks the answers somewhere else than the provider's own API
+    pub fn with_verify_url(mut self, verify_url: impl Into<String>) -> Self {
+        self.verify_url = verify_url.into();
+        self
+    }
+}
+
+impl Challenge for RemoteCaptcha {
+    fn render(&self, _session: &Session) -> Markup {
+        html! {
+            div class=(self.widget_class) data-sitekey=(self.site_key) {}
+            script src=(self.script_url) async defer {}
+        }
+    }
+
+    fn parameters(&self, _session: &Session) -> ChallengeParameters {
+        let site_key = self.site_key.clone();
+        if self.widget_class == "h-captcha" {
+            ChallengeParameters::Hcaptcha { site_key }
+        } else {
+            ChallengeParameters::Turnstile { site_key }
+        }
+    }
+
+    fn verify<'a>(
+        &'a self,
+        _session: &'a Session,
+        response: &'a ChallengeResponse,
+        remote_ip: Option<&'a str>,
+    ) -> VerifyFuture<'a> {
+        Box::pin(async move {
+            let token = if self.widget_class == "h-captcha" {
+                &response.hcaptcha
+            } else {
+                &response.turnstile
+            };
+            let Some(token) = token else {
+                return false;
+            };
+
+            let mut data = vec![
+                ("secret", self.secret.as_str()),
+                ("response", token.as_str()),
+            ];
+            if let Some(remote_ip) = remote_ip {
+                data.push(("remoteip", remote_ip));
+            }
+
+            let client = reqwest::Client::new();
+            let Ok(response) = client.post(&self.verify_url).form(&data).send().await else {
+                log::error!(
+                    "Não foi possível verificar o captcha em {}",
+                    self.verify_url
+                );
+                return false;
+            };
+            let Ok(body) = response.text().await else {
+                return false;
+            };
+            match serde_json::from_str::<SiteVerifyResponse>(&body) {
+                Ok(result) => result.success,
+                Err(e) => {
+                    log::error!("Resposta inválida de {}: {:?}", self.verify_url, e);
+                    false
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_session::SessionExt;
+    use actix_web::{test::TestRequest, web, App, HttpResponse, HttpServer};
+
+    use super::*;
+
+    fn new_session() -> Session {
+        TestRequest::default().to_http_request().get_session()
+    }
+
+    fn solve(pow: &ProofOfWork, challenge: &str) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| pow.check(challenge, nonce))
+            .unwrap()
+    }
+
+    fn pow_response(nonce: String) -> ChallengeResponse {
+        ChallengeResponse {
+            pow_nonce: Some(nonce),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn leading_zero_bits() {
+        assert_eq!(ProofOfWork::leading_zero_bits(&[0x00, 0x00, 0xff]), 16);
+        assert_eq!(ProofOfWork::leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(ProofOfWork::leading_zero_bits(&[0x80]), 0);
+    }
+
+    #[test]
+    fn check_needs_enough_zero_bits() {
+        let pow = ProofOfWork::new(8);
+        let nonce = solve(&pow, "desafio");
+        assert!(pow.check("desafio", &nonce));
+        assert!(ProofOfWork::new(0).check("desafio", "qualquer"));
+        assert!(!ProofOfWork::new(257).check("desafio", &nonce));
+    }
+
+    #[actix_web::test]
+    async fn challenges_are_answered_once() {
+        let pow = ProofOfWork::new(4);
+        let session = new_session();
+        let ChallengeParameters::Pow { challenge, .. } = pow.parameters(&session) else {
+            panic!("proof of work parameters expected");
+        };
+        let nonce = solve(&pow, &challenge);
+
+        // an old cookie sent again still holds the challenge
+        let replayed = new_session();
+        replayed
+            .insert(POW_CHALLENGE_KEY, (&challenge, Utc::now().timestamp()))
+            .unwrap();
+
+        assert!(
+            pow.verify(&session, &pow_response(nonce.clone()), None)
+                .await
+        );
+        assert!(
+            !pow.verify(&session, &pow_response(nonce.clone()), None)
+                .await
+        );
+        assert!(!pow.verify(&replayed, &pow_response(nonce), None).await);
+    }
+
+    #[actix_web::test]
+    async fn expired_challenges_fail() {
+        let pow = ProofOfWork::new(0);
+        let session = new_session();
+        let issued_at = Utc::now().timestamp() - POW_EXPIRATION_SECONDS - 1;
+        session
+            .insert(POW_CHALLENGE_KEY, ("desafio", issued_at))
+            .unwrap();
+        assert!(!pow.verify(&session, &pow_response("0".into()), None).await);
+    }
+
+    // answers like the providers do, accepting only the token "valido"
+    async fn site_verify(data: web::Form<HashMap<String, String>>) -> HttpResponse {
+        let success = data.get("secret").map(String::as_str) == Some("segredo")
+            && data.get("response").map(String::as_str) == Some("valido");
+        HttpResponse::Ok().json(serde_json::json!({ "success": success }))
+    }
+
+    #[actix_web::test]
+    async fn remote_captcha_asks_the_verify_url() {
+        let server =
+            HttpServer::new(|| App::new().route("/siteverify", web::post().to(site_verify)))
+                .workers(1)
+                .bind(("127.0.0.1", 0))
+                .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let captcha = RemoteCaptcha::turnstile("site".into(), "segredo".into())
+            .with_verify_url(format!("http://{}/siteverify", address));
+        let session = new_session();
+        let response = |token: &str| ChallengeResponse {
+            turnstile: Some(token.to_string()),
+            ..Default::default()
+        };
+
+        assert!(captcha.verify(&session, &response("valido"), None).await);
+        assert!(!captcha.verify(&session, &response("invalido"), None).await);
+        // the hCaptcha field isn't looked at by Turnstile
+        let wrong_field = ChallengeResponse {
+            hcaptcha: Some("valido".to_string()),
+            ..Default::default()
+        };
+        assert!(!captcha.verify(&session, &wrong_field, None).await);
+
+        handle.stop(false).await;
+    }
+}
@@ -0,0 +1,342 @@
+// checks the text of new listings before they go up. the platform is for giving, lending and
+// swapping, so besides banned words it looks for signs of sales and of deals being taken
+// elsewhere (links and phone numbers). what happens on a match is configured per rule
+
+use std::{env, fmt, str::FromStr};
+
+/// what to do with a listing that matched a rule, from the mildest to the strictest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilterAction {
+    /// publish it as usual
+    Allow,
+    /// publish it and put it in the moderation queue
+    Flag,
+    /// keep it hidden until a moderator shows it
+    Hide,
+    /// don't accept it
+    Reject,
+}
+
+impl FromStr for FilterAction {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "allow" => Ok(FilterAction::Allow),
+            "flag" => Ok(FilterAction::Flag),
+            "hide" => Ok(FilterAction::Hide),
+            "reject" => Ok(FilterAction::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// a rule that matched, with the text that triggered it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterMatch {
+    BannedWord(String),
+    Link(String),
+    PhoneNumber(String),
+    SaleWording(String),
+}
+
+impl fmt::Display for FilterMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterMatch::BannedWord(word) => write!(f, "palavra proibida \"{}\"", word),
+            FilterMatch::Link(link) => write!(f, "link \"{}\"", link),
+            FilterMatch::PhoneNumber(number) => write!(f, "telefone \"{}\"", number),
+            FilterMatch::SaleWording(word) => write!(f, "termo de venda \"{}\"", word),
+        }
+    }
+}
+
+/// the strictest action among the rules that matched, and the matches themselves
+#[derive(Debug)]
+pub struct Verdict {
+    pub action: FilterAction,
+    pub matches: Vec<FilterMatch>,
+}
+
+impl Verdict {
+    /// the matches as one line, for notes and reports
+    pub fn summary(&self) -> String {
+        self.matches
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// words that suggest the item is being sold, matched after removing accents
+const DEFAULT_SALE_WORDS: &[&str] = &[
+    "vendo",
+    "venda",
+    "vende",
+    "preco",
+    "r$",
+    "reais",
+    "pix",
+    "pagamento",
+    "parcelo",
+    "negociavel",
+];
+
+// top-level domains that give away a link even without "http" or "www"
+const LINK_SUFFIXES: &[&str] = &[
+    ".com", ".br", ".net", ".org", ".io", ".me", ".ly", ".gg", ".app", ".link",
+];
+
+// a run with at least this many digits is treated as a phone number
+const PHONE_MIN_DIGITS: usize = 8;
+
+/// rules and actions read from the environment, shared between workers through `web::Data`
+pub struct ContentFilter {
+    banned_words: Vec<String>,
+    sale_words: Vec<String>,
+    banned_word_action: FilterAction,
+    link_action: FilterAction,
+    phone_action: FilterAction,
+    sale_action: FilterAction,
+}
+
+fn word_list(name: &str, default: &[&str]) -> Vec<String> {
+    match env::var(name) {
+        Ok(list) => list
+            .split(',')
+            .map(normalize)
+            .filter(|word| !word.is_empty())
+            .collect(),
+        Err(_) => default.iter().map(|word| normalize(word)).collect(),
+    }
+}
+
+fn action(name: &str, default: FilterAction) -> FilterAction {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be allow, flag, hide or reject", name)),
+        Err(_) => default,
+    }
+}
+
+// lowercase without accents, so "Preço" and "preco" are the same word
+fn normalize(text: &str) -> String {
+    text.trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'á' | 'à' | 'â' | 'ã' | 'ä' => 'a',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'í' | 'ì' | 'î' | 'ï' => 'i',
+            'ó' | 'ò' | 'ô' | 'õ' | 'ö' => 'o',
+            'ú' | 'ù' | 'û' | 'ü' => 'u',
+            'ç' => 'c',
+            _ => c,
+        })
+        .collect()
+}
+
+// splits on anything that can't be part of a word, keeping '$' so "r$" survives
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '$'))
+        .filter(|word| !word.is_empty())
+}
+
+impl ContentFilter {
+    /// banned words come from `CONTENT_FILTER_BANNED_WORDS` and sale words from
+    /// `CONTENT_FILTER_SALE_WORDS`, both comma-separated. the actions (`allow`, `flag`, `hide`
+    /// or `reject`) are read from `CONTENT_FILTER_BANNED_ACTION` (default reject),
+    /// `CONTENT_FILTER_LINK_ACTION` (flag), `CONTENT_FILTER_PHONE_ACTION` (flag) and
+    /// `CONTENT_FILTER_SALE_ACTION` (hide)
+    pub fn from_env() -> Self {
+        Self {
+            banned_words: word_list("CONTENT_FILTER_BANNED_WORDS", &[]),
+            sale_words: word_list("CONTENT_FILTER_SALE_WORDS", DEFAULT_SALE_WORDS),
+            banned_word_action: action("CONTENT_FILTER_BANNED_ACTION", FilterAction::Reject),
+            link_action: action("CONTENT_FILTER_LINK_ACTION", FilterAction::Flag),
+            phone_action: action("CONTENT_FILTER_PHONE_ACTION", FilterAction::Flag),
+            sale_action: action("CONTENT_FILTER_SALE_ACTION", FilterAction::Hide),
+        }
+    }
+
+    fn action_for(&self, filter_match: &FilterMatch) -> FilterAction {
+        match filter_match {
+            FilterMatch::BannedWord(_) => self.banned_word_action,
+            FilterMatch::Link(_) => self.link_action,
+            FilterMatch::PhoneNumber(_) => self.phone_action,
+            FilterMatch::SaleWording(_) => self.sale_action,
+        }
+    }
+
+    /// checks every rule against the text
+    pub fn check(&self, text: &str) -> Verdict {
+        let normalized = normalize(text);
+        let mut matches = Vec::new();
+
+        for word in words(&normalized) {
+            if self.banned_words.iter().any(|banned| banned == word) {
+                matches.push(FilterMatch::BannedWord(word.to_string()));
+            } else if self.sale_words.iter().any(|sale| sale == word) {
+                matches.push(FilterMatch::SaleWording(word.to_string()));
+            }
+        }
+
+        for token in normalized.split_whitespace() {
+            let token = token.trim_matches(|c: char| !c.is_alphanumeric());
+            let is_link = token.contains("http://")
+                || token.contains("https://")
+                || token.starts_with("www.")
+                || LINK_SUFFIXES.iter().any(|suffix| {
+                    token.ends_with(suffix) || token.contains(&format!("{}/", suffix))
+                });
+            // "1.5" or "10.000" aren't links, a domain needs letters before the dot
+            let has_name = token
+                .split('.')
+                .next()
+                .is_some_and(|name| name.chars().any(char::is_alphabetic));
+            if is_link && has_name {
+                matches.push(FilterMatch::Link(token.to_string()));
+            }
+        }
+
+        matches.extend(
+            phone_numbers(text)
+                .into_iter()
+                .map(FilterMatch::PhoneNumber),
+        );
+
+        let action = matches
+            .iter()
+            .map(|filter_match| self.action_for(filter_match))
+            .max()
+            .unwrap_or(FilterAction::Allow);
+        Verdict { action, matches }
+    }
+}
+
+// runs of digits, possibly broken up by the separators people use when writing phone numbers
+fn phone_numbers(text: &str) -> Vec<String> {
+    let mut numbers = Vec::new();
+    let mut current = String::new();
+    let mut digits = 0;
+
+    for c in text.chars().chain(std::iter::once('\n')) {
+        if c.is_ascii_digit() {
+            current.push(c);
+            digits += 1;
+        } else if matches!(c, '(' | '+')
+            || (matches!(c, ' ' | '-' | '.' | ')') && !current.is_empty())
+        {
+            current.push(c);
+        } else {
+            if digits >= PHONE_MIN_DIGITS {
+                numbers.push(current.trim().to_string());
+            }
+            current.clear();
+            digits = 0;
+        }
+    }
+    numbers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> ContentFilter {
+        ContentFilter {
+            banned_words: vec!["arma".to_string()],
+            sale_words: DEFAULT_SALE_WORDS
+                .iter()
+                .map(|word| normalize(word))
+                .collect(),
+            banned_word_action: FilterAction::Reject,
+            link_action: FilterAction::Flag,
+            phone_action: FilterAction::Flag,
+            sale_action: FilterAction::Hide,
+        }
+    }
+
+    #[test]
+    fn normalize_removes_accents_and_case() {
+        assert_eq!(normalize("  Preço Negociável "), "preco negociavel");
+        assert_eq!(normalize("AÇÃO"), "acao");
+    }
+
+    #[test]
+    fn clean_text_is_allowed() {
+        let verdict = filter().check("Doação de livros de cálculo, retirar no ICC");
+        assert_eq!(verdict.action, FilterAction::Allow);
+        assert!(verdict.matches.is_empty());
+    }
+
+    #[test]
+    fn sale_wording_ignores_accents() {
+        let verdict = filter().check("Bicicleta, preço a combinar");
+        assert_eq!(verdict.action, FilterAction::Hide);
+        assert_eq!(
+            verdict.matches,
+            vec![FilterMatch::SaleWording("preco".to_string())]
+        );
+        assert_eq!(
+            filter().check("Só R$ 20").matches,
+            vec![FilterMatch::SaleWording("r$".to_string())]
+        );
+        // only whole words count
+        assert_eq!(
+            filter().check("Vendedor de sonhos").action,
+            FilterAction::Allow
+        );
+    }
+
+    #[test]
+    fn strictest_action_wins() {
+        let verdict = filter().check("Vendo arma");
+        assert_eq!(verdict.action, FilterAction::Reject);
+        assert_eq!(verdict.matches.len(), 2);
+        assert_eq!(
+            verdict.summary(),
+            "termo de venda \"vendo\", palavra proibida \"arma\""
+        );
+    }
+
+    #[test]
+    fn links() {
+        for text in [
+            "veja https://exemplo.com",
+            "www.exemplo",
+            "fotos em meusite.com.br/cadeira",
+            "chama no insta.gg",
+        ] {
+            let verdict = filter().check(text);
+            assert_eq!(verdict.action, FilterAction::Flag, "{}", text);
+            assert!(
+                matches!(verdict.matches[..], [FilterMatch::Link(_)]),
+                "{}",
+                text
+            );
+        }
+        assert_eq!(filter().check("tem 1.5 metros").action, FilterAction::Allow);
+        assert_eq!(filter().check("10.000 páginas").action, FilterAction::Allow);
+    }
+
+    #[test]
+    fn phone_numbers_need_enough_digits() {
+        assert_eq!(
+            phone_numbers("liga (61) 99999-8888 ou +55 61 3333.4444"),
+            vec!["(61) 99999-8888", "+55 61 3333.4444"]
+        );
+        assert!(phone_numbers("edição de 2024, 3 volumes").is_empty());
+        assert_eq!(filter().check("zap 61999998888").action, FilterAction::Flag);
+    }
+
+    #[test]
+    fn actions_parse() {
+        assert_eq!("hide".parse(), Ok(FilterAction::Hide));
+        assert_eq!("Hide".parse::<FilterAction>(), Err(()));
+        assert!(FilterAction::Reject > FilterAction::Hide);
+        assert!(FilterAction::Flag > FilterAction::Allow);
+    }
+}
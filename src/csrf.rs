@@ -0,0 +1,213 @@
+// anti-forgery tokens for every state-changing request. each session gets one token, forms
+// carry it in a hidden field and `csrf_protect` rejects requests without it
+
+use std::{
+    cell::RefCell,
+    future::{ready, Ready},
+    rc::Rc,
+    task::Poll,
+};
+
+use actix_http::BoxedPayloadStream;
+use actix_multipart::Multipart;
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::{
+        header::{self, HeaderMap},
+        Method,
+    },
+    middleware::Next,
+    web::{self, Bytes},
+    FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::{stream, Stream, StreamExt};
+use maud::{html, Markup, Render};
+use serde::Deserialize;
+use uuid::Uuid;
+
+// session key entry holding the token
+const CSRF_TOKEN_KEY: &str = "csrf_token";
+/// name of the form field and header (as `X-CSRF-Token`). never read from the url, where it
+/// would leak through the Referer header and access logs
+pub const CSRF_FIELD: &str = "csrf_token";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+// posted to by mail clients without a session, the signed token in the url is checked instead
+const EXEMPT_PATHS: &[&str] = &["/unsubscribe"];
+// longest first field of a multipart body read looking for the token
+const MULTIPART_FIELD_MAX_LENGTH: usize = 256;
+
+fn get_or_create_token(session: &Session) -> String {
+    if let Ok(Some(token)) = session.get::<String>(CSRF_TOKEN_KEY) {
+        return token;
+    }
+
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    if let Err(e) = session.insert(CSRF_TOKEN_KEY, &token) {
+        log::error!("Não foi possível salvar o token CSRF na sessão: {:?}", e);
+    }
+    token
+}
+
+/// the session's token, rendered as the hidden field every post form needs:
+/// `form method="post" { (csrf) ... }`. in multipart forms it has to be the first field
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn value(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Render for CsrfToken {
+    fn render(&self) -> Markup {
+        html! {
+            input type="hidden" name=(CSRF_FIELD) value=(self.0);
+        }
+    }
+}
+
+impl FromRequest for CsrfToken {
+    type Error = actix_web::Error;
+    type Future = Ready<actix_web::Result<CsrfToken>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(CsrfToken(get_or_create_token(&req.get_session()))))
+    }
+}
+
+#[derive(Deserialize)]
+struct CsrfField {
+    csrf_token: Option<String>,
+}
+
+// compares every byte so the time taken doesn't tell how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn forbidden(path: &str) -> HttpResponse {
+    // api clients get the token from `/api/v1/auth/csrf` instead of a form
+    if path.starts_with("/api/") {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": {
+                "code": "csrf",
+                "message": "Token CSRF ausente ou inválido",
+            }
+        }));
+    }
+    HttpResponse::Forbidden()
+        .body("O formulário expirou ou é inválido. Volte, recarregue a página e tente novamente.")
+}
+
+// the value of the first field of a multipart body when it's the token
+async fn first_field_token(
+    headers: &HeaderMap,
+    body: impl Stream<Item = Result<Bytes, PayloadError>> + 'static,
+) -> Option<String> {
+    let mut multipart = Multipart::new(headers, body);
+    let mut field = multipart.next().await?.ok()?;
+    if field.name() != Some(CSRF_FIELD) {
+        return None;
+    }
+    let mut value = Vec::new();
+    while let Some(chunk) = field.next().await {
+        value.extend_from_slice(&chunk.ok()?);
+        if value.len() > MULTIPART_FIELD_MAX_LENGTH {
+            return None;
+        }
+    }
+    String::from_utf8(value).ok()
+}
+
+// reads the token from a multipart body without buffering the files after it. the chunks
+// read are put back in front of the rest of the body so the handler still gets all of it
+async fn multipart_token(req: &mut ServiceRequest) -> Option<String> {
+    let payload = Rc::new(RefCell::new(req.take_payload()));
+    let read = Rc::new(RefCell::new(Vec::<Bytes>::new()));
+
+    let recorded = {
+        let payload = payload.clone();
+        let read = read.clone();
+        stream::poll_fn(move |cx| {
+            let poll = payload.borrow_mut().poll_next_unpin(cx);
+            if let Poll::Ready(Some(Ok(ref chunk))) = poll {
+                read.borrow_mut().push(chunk.clone());
+            }
+            poll
+        })
+    };
+    let token = first_field_token(req.headers(), recorded).await;
+
+    let rest = stream::poll_fn(move |cx| payload.borrow_mut().poll_next_unpin(cx));
+    let replayed = stream::iter(read.take().into_iter().map(Ok)).chain(rest);
+    req.set_payload(Payload::from(Box::pin(replayed) as BoxedPayloadStream));
+    token
+}
+
+/// middleware checking the token on every request that isn't GET, HEAD or OPTIONS. the token
+/// is looked for in the `X-CSRF-Token` header, urlencoded bodies and the first field of
+/// multipart bodies. must be wrapped inside the session middleware
+pub async fn csrf_protect(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || EXEMPT_PATHS.contains(&req.path())
+    {
+        return next.call(req).await.map(|res| res.map_into_boxed_body());
+    }
+
+    let Ok(Some(expected)) = req.get_session().get::<String>(CSRF_TOKEN_KEY) else {
+        log::warn!(
+            "Requisição {} {} sem sessão para CSRF",
+            req.method(),
+            req.path()
+        );
+        let response = forbidden(req.path());
+        return Ok(req.into_response(response));
+    };
+
+    let mut received = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    // read the form body and put it back so the handler can still extract it
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if received.is_none() && content_type.starts_with("multipart/form-data") {
+        received = multipart_token(&mut req).await;
+    }
+    if received.is_none() && content_type.starts_with("application/x-www-form-urlencoded") {
+        let body = req.extract::<web::Bytes>().await?;
+        received = serde_urlencoded::from_bytes::<CsrfField>(&body)
+            .ok()
+            .and_then(|form| form.csrf_token);
+
+        let (_, mut payload) = actix_http::h1::Payload::create(true);
+        payload.unread_data(body);
+        req.set_payload(payload.into());
+    }
+
+    match received {
+        Some(received) if constant_time_eq(received.as_bytes(), expected.as_bytes()) => {
+            next.call(req).await.map(|res| res.map_into_boxed_body())
+        }
+        _ => {
+            log::warn!("Token CSRF inválido em {} {}", req.method(), req.path());
+            let response = forbidden(req.path());
+            Ok(req.into_response(response))
+        }
+    }
+}
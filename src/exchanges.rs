@@ -0,0 +1,131 @@
+// donations, loans and exchanges that were carried out. the creator of the listing names who
+// received it and the exchange only counts once that person accepts it
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    dsl::{auto_type, exists, not},
+    BoolExpressionMethods, ExpressionMethods, JoinOnDsl, NullableExpressionMethods,
+    OptionalExtension, QueryDsl, QueryResult, RunQueryDsl,
+};
+use uuid::Uuid;
+
+use crate::{
+    schema::{exchanges, listings, users},
+    DbConn,
+};
+
+/// records that the listing was handed over to `partner_id`, waiting for them to accept it.
+/// returns `false` when the listing doesn't belong to `owner_id` or was already completed
+pub fn complete_listing(
+    conn: &mut DbConn,
+    listing_id: Uuid,
+    owner_id: Uuid,
+    partner_id: Uuid,
+) -> QueryResult<bool> {
+    let Some(title) = listings::table
+        .filter(
+            listings::id
+                .eq(listing_id)
+                .and(listings::creator_id.eq(owner_id)),
+        )
+        .select(listings::title)
+        .first::<String>(conn)
+        .optional()?
+    else {
+        return Ok(false);
+    };
+
+    let inserted = diesel::insert_into(exchanges::table)
+        .values((
+            exchanges::id.eq(Uuid::new_v4()),
+            exchanges::listing_id.eq(listing_id),
+            exchanges::listing_title.eq(title),
+            exchanges::owner_id.eq(owner_id),
+            exchanges::partner_id.eq(partner_id),
+        ))
+        .on_conflict(exchanges::listing_id)
+        .do_nothing()
+        .execute(conn)?;
+    Ok(inserted > 0)
+}
+
+/// an exchange the user was named in and didn't answer yet
+pub struct PendingExchange {
+    pub id: Uuid,
+    pub listing_title: String,
+    pub owner: String,
+    pub completed_at: DateTime<Utc>,
+}
+
+/// exchanges waiting for the user to accept them, newest first
+pub fn awaiting(conn: &mut DbConn, partner_id: Uuid) -> QueryResult<Vec<PendingExchange>> {
+    Ok(exchanges::table
+        .inner_join(users::table.on(exchanges::owner_id.eq(users::id)))
+        .filter(exchanges::partner_id.eq(partner_id))
+        .filter(exchanges::accepted_at.is_null())
+        .order_by(exchanges::completed_at.desc())
+        .select((
+            exchanges::id,
+            exchanges::listing_title,
+            users::nickname,
+            exchanges::completed_at,
+        ))
+        .load::<(Uuid, String, String, DateTime<Utc>)>(conn)?
+        .into_iter()
+        .map(|(id, listing_title, owner, completed_at)| PendingExchange {
+            id,
+            listing_title,
+            owner,
+            completed_at,
+        })
+        .collect())
+}
+
+/// the partner confirms they received the listing. returns `false` when the exchange isn't
+/// waiting for them
+pub fn accept(conn: &mut DbConn, exchange_id: Uuid, partner_id: Uuid) -> QueryResult<bool> {
+    let updated = diesel::update(
+        exchanges::table
+            .filter(exchanges::id.eq(exchange_id))
+            .filter(exchanges::partner_id.eq(partner_id))
+            .filter(exchanges::accepted_at.is_null()),
+    )
+    .set(exchanges::accepted_at.eq(Utc::now()))
+    .execute(conn)?;
+    Ok(updated > 0)
+}
+
+/// the partner says they didn't take part, which puts the listing back up. returns `false`
+/// when the exchange isn't waiting for them
+pub fn decline(conn: &mut DbConn, exchange_id: Uuid, partner_id: Uuid) -> QueryResult<bool> {
+    let deleted = diesel::delete(
+        exchanges::table
+            .filter(exchanges::id.eq(exchange_id))
+            .filter(exchanges::partner_id.eq(partner_id))
+            .filter(exchanges::accepted_at.is_null()),
+    )
+    .execute(conn)?;
+    Ok(deleted > 0)
+}
+
+/// how many accepted exchanges the user took part in, on either side
+pub fn completed_count(conn: &mut DbConn, user_id: Uuid) -> QueryResult<i64> {
+    exchanges::table
+        .filter(
+            exchanges::owner_id
+                .eq(user_id)
+                .or(exchanges::partner_id.eq(user_id)),
+        )
+        .filter(exchanges::accepted_at.is_not_null())
+        .count()
+        .get_result(conn)
+}
+
+/// filter for listings that weren't completed yet, to be used on `listings::table` queries.
+/// a listing waiting for its partner to accept is no longer active either
+#[auto_type]
+pub fn is_active() -> _ {
+    not(exists(
+        exchanges::table.filter(exchanges::listing_id.eq(listings::id.nullable())),
+    ))
+}
@@ -0,0 +1,158 @@
+// listings users saved to look at later, and the notifications sent when one of them stops
+// being available
+
+use std::{collections::HashSet, fmt};
+
+use diesel::{ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl, QueryResult, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::{
+    mail::send_notification_email,
+    notifications::{self, NotificationKind},
+    schema::{favorites, listings, users},
+    DbConn,
+};
+
+/// saves the listing for the user, or removes it when it was already saved. returns whether
+/// it's saved now
+pub fn toggle(conn: &mut DbConn, user_id: Uuid, listing_id: Uuid) -> QueryResult<bool> {
+    let removed = diesel::delete(
+        favorites::table
+            .filter(favorites::user_id.eq(user_id))
+            .filter(favorites::listing_id.eq(listing_id)),
+    )
+    .execute(conn)?;
+    if removed > 0 {
+        return Ok(false);
+    }
+
+    // only listings that can be seen can be saved
+    let visible = listings::table
+        .find(listing_id)
+        .filter(listings::hidden.eq(false))
+        .select(listings::id)
+        .first::<Uuid>(conn)
+        .optional()?;
+    if visible.is_none() {
+        return Ok(false);
+    }
+
+    diesel::insert_into(favorites::table)
+        .values((
+            favorites::user_id.eq(user_id),
+            favorites::listing_id.eq(listing_id),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// which of the listings the user saved, to show the toggle in the right state
+pub fn saved_among(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    listing_ids: &[Uuid],
+) -> QueryResult<HashSet<Uuid>> {
+    Ok(favorites::table
+        .filter(favorites::user_id.eq(user_id))
+        .filter(favorites::listing_id.eq_any(listing_ids))
+        .select(favorites::listing_id)
+        .load::<Uuid>(conn)?
+        .into_iter()
+        .collect())
+}
+
+/// why the users who saved a listing are being told about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FavoriteEvent {
+    Reserved,
+    Unavailable,
+}
+
+impl fmt::Display for FavoriteEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FavoriteEvent::Reserved => write!(f, "foi reservado"),
+            FavoriteEvent::Unavailable => write!(f, "não está mais disponível"),
+        }
+    }
+}
+
+/// someone to tell about a listing they saved
+pub struct Notice {
+    pub user_id: Uuid,
+    pub listing_id: Uuid,
+    pub listing_title: String,
+    pub nickname: String,
+}
+
+/// who saved the listings, loaded before they are changed or deleted. the creators aren't
+/// told about their own listings
+pub fn notices(conn: &mut DbConn, listing_ids: &[Uuid]) -> QueryResult<Vec<Notice>> {
+    Ok(favorites::table
+        .inner_join(listings::table)
+        .inner_join(users::table.on(favorites::user_id.eq(users::id)))
+        .filter(favorites::listing_id.eq_any(listing_ids))
+        .filter(listings::creator_id.ne(favorites::user_id))
+        .select((users::id, listings::id, listings::title, users::nickname))
+        .load::<(Uuid, Uuid, String, String)>(conn)?
+        .into_iter()
+        .map(|(user_id, listing_id, listing_title, nickname)| Notice {
+            user_id,
+            listing_id,
+            listing_title,
+            nickname,
+        })
+        .collect())
+}
+
+/// notifies everyone in `notices`, in the app and by email. failures are only logged, the
+/// change already happened
+pub async fn send_notices(
+    conn: &mut DbConn,
+    mg_api_key: &str,
+    notices: &[Notice],
+    event: FavoriteEvent,
+) {
+    let message = event.to_string();
+    for notice in notices {
+        // a listing that's gone has no page to link to
+        let link = match event {
+            FavoriteEvent::Reserved => format!("/item/{}", notice.listing_id),
+            FavoriteEvent::Unavailable => "/salvos".to_string(),
+        };
+        if let Err(e) = notifications::notify(
+            conn,
+            notice.user_id,
+            NotificationKind::Reservation,
+            &format!("\"{}\" {}", notice.listing_title, message),
+            &link,
+        ) {
+            log::error!("Não foi possível notificar {}: {:?}", notice.nickname, e);
+        }
+
+        let subject = format!("Um item que você salvou {}", message);
+        if send_notification_email(
+            conn,
+            mg_api_key,
+            notice.user_id,
+            NotificationKind::Reservation,
+            "item salvo",
+            &subject,
+            &[
+                ("nickname", notice.nickname.as_str()),
+                ("title", notice.listing_title.as_str()),
+                ("message", message.as_str()),
+            ],
+        )
+        .await
+        .is_err()
+        {
+            log::error!(
+                "Não foi possível avisar {} sobre o item \"{}\"",
+                notice.nickname,
+                notice.listing_title
+            );
+        }
+    }
+}
@@ -2,31 +2,96 @@ use std::{
     fmt,
     future::{ready, Ready},
     io::Write,
+    sync::OnceLock,
 };
 
 use actix_identity::Identity;
-use actix_web::{web, FromRequest};
+use actix_session::Session;
+use actix_web::{web, FromRequest, HttpRequest};
 use diesel::{
+    define_sql_function,
     deserialize::{FromSql, FromSqlRow},
     expression::AsExpression,
     pg::Pg,
-    query_dsl::methods::{FindDsl, SelectDsl},
+    query_dsl::methods::{FilterDsl, FindDsl, SelectDsl},
     r2d2::ConnectionManager,
     serialize::{IsNull, ToSql},
-    PgConnection, RunQueryDsl,
+    sql_types::Text,
+    Connection, ExpressionMethods, NullableExpressionMethods, OptionalExtension, PgConnection,
+    QueryResult, RunQueryDsl,
 };
 use r2d2_postgres::r2d2;
 use schema::{
-    sql_types::{ListingCampus, ListingType, UserStatus},
+    sql_types::{ListingCampus, ListingType, UserRole, UserStatus},
     users,
 };
 use uuid::Uuid;
 
+pub mod audit;
+pub mod captcha;
+pub mod content_filter;
+pub mod csrf;
+pub mod exchanges;
+pub mod favorites;
+pub mod listings;
+pub mod mail;
+pub mod moderation;
+pub mod notifications;
+pub mod permissions;
+pub mod policy;
+pub mod rate_limit;
+pub mod realtime;
+pub mod reviews;
+pub mod saved_searches;
 pub mod schema;
+pub mod session;
+pub mod storage;
+pub mod two_factor;
+pub mod unsubscribe;
 
 pub type DbConn = PgConnection;
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
+// postgres' lower(), for case-insensitive comparisons
+define_sql_function!(fn lower(x: Text) -> Text);
+
+/// turns what was typed into an ilike pattern, so % and _ are matched literally
+pub fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .trim()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// reads a setting (number or boolean) from the environment, falling back to the default when unset
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value", name)),
+        Err(_) => default,
+    }
+}
+
+/// whether the client address is taken from the `Forwarded` and `X-Forwarded-For` headers
+/// (`TRUST_PROXY_HEADERS`). only safe behind a reverse proxy that overwrites them, otherwise
+/// anyone can claim any address
+pub fn trust_proxy_headers() -> bool {
+    static TRUST: OnceLock<bool> = OnceLock::new();
+    *TRUST.get_or_init(|| env_or("TRUST_PROXY_HEADERS", false))
+}
+
+/// the address the request came from, used for rate limits, lockouts and logs
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    if trust_proxy_headers() {
+        req.connection_info().realip_remote_addr().map(String::from)
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    }
+}
+
 // map db enum to rust enum
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression)]
 #[diesel(sql_type = UserStatus)]
@@ -63,6 +128,102 @@ impl FromSql<UserStatus, Pg> for AccountStatus {
     }
 }
 
+/// what an account is allowed to do, each role can do everything the previous ones can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, FromSqlRow, AsExpression)]
+#[diesel(sql_type = UserRole)]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::User => write!(f, "Usuário"),
+            Role::Moderator => write!(f, "Moderador"),
+            Role::Admin => write!(f, "Administrador"),
+        }
+    }
+}
+
+impl ToSql<UserRole, Pg> for Role {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            Role::User => out.write_all(b"USER")?,
+            Role::Moderator => out.write_all(b"MODERATOR")?,
+            Role::Admin => out.write_all(b"ADMIN")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<UserRole, Pg> for Role {
+    fn from_sql(
+        bytes: <Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"USER" => Ok(Role::User),
+            b"MODERATOR" => Ok(Role::Moderator),
+            b"ADMIN" => Ok(Role::Admin),
+            _ => Err("Unknown user role".into()),
+        }
+    }
+}
+
+/// changes the status of an account. disabling it also ends every login it has, so the user
+/// is logged out everywhere instead of keeping the sessions they already had
+pub fn set_account_status(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    status: AccountStatus,
+) -> QueryResult<()> {
+    conn.transaction(|conn| {
+        if status == AccountStatus::DISABLED {
+            // the status it had is kept for `enable_account`
+            diesel::update(
+                users::table
+                    .filter(users::id.eq(user_id))
+                    .filter(users::status.ne(AccountStatus::DISABLED)),
+            )
+            .set((
+                users::status_before_disable.eq(users::status.nullable()),
+                users::status.eq(status),
+            ))
+            .execute(conn)?;
+            session::end_all_logins(conn, user_id)?;
+        } else {
+            diesel::update(users::table.filter(users::id.eq(user_id)))
+                .set((
+                    users::status.eq(status),
+                    users::status_before_disable.eq(None::<AccountStatus>),
+                ))
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+}
+
+/// enables a disabled account again with the status it had before, so an account disabled
+/// before confirming its email still has to confirm it. other accounts are left alone
+pub fn enable_account(conn: &mut DbConn, user_id: Uuid) -> QueryResult<()> {
+    conn.transaction(|conn| {
+        let previous = users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::status.eq(AccountStatus::DISABLED))
+            .select(users::status_before_disable)
+            .first::<Option<AccountStatus>>(conn)
+            .optional()?;
+        if let Some(previous) = previous {
+            set_account_status(conn, user_id, previous.unwrap_or(AccountStatus::CONFIRMED))?;
+        }
+        Ok(())
+    })
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression)]
 #[diesel(sql_type = ListingCampus)]
 pub enum Campus {
@@ -72,6 +233,29 @@ pub enum Campus {
     Gama,
 }
 
+impl Campus {
+    pub const ALL: [Campus; 4] = [
+        Campus::DarcyRibeiro,
+        Campus::Planaltina,
+        Campus::Ceilandia,
+        Campus::Gama,
+    ];
+
+    /// value used in urls and in the api
+    pub fn code(&self) -> &'static str {
+        match self {
+            Campus::DarcyRibeiro => "darcy-ribeiro",
+            Campus::Planaltina => "planaltina",
+            Campus::Ceilandia => "ceilandia",
+            Campus::Gama => "gama",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|campus| campus.code() == code)
+    }
+}
+
 impl fmt::Display for Campus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -121,6 +305,26 @@ pub enum Type {
     Request,
 }
 
+impl Type {
+    pub const ALL: [Type; 4] = [Type::Donation, Type::Loan, Type::Exchange, Type::Request];
+
+    /// value used in urls and in the api
+    pub fn code(&self) -> &'static str {
+        match self {
+            Type::Donation => "doacao",
+            Type::Loan => "emprestimo",
+            Type::Exchange => "troca",
+            Type::Request => "pedido",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|listing_type| listing_type.code() == code)
+    }
+}
+
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -164,13 +368,33 @@ impl FromSql<ListingType, Pg> for Type {
 pub enum LocalUser {
     Anonymous,
     Pending,
+    /// the account was disabled, the user is logged out as soon as this is seen
+    Disabled,
     Authenticated {
         id: Uuid,
         nickname: String,
         avatar_seed: Uuid,
+        role: Role,
+        /// shown on the bell in the navbar
+        unread_notifications: i64,
     },
 }
 
+impl LocalUser {
+    /// the role of a confirmed, logged in user
+    pub fn role(&self) -> Option<Role> {
+        match self {
+            LocalUser::Authenticated { role, .. } => Some(*role),
+            _ => None,
+        }
+    }
+
+    /// whether the user is logged in with at least the given role
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role().is_some_and(|own| own >= role)
+    }
+}
+
 impl FromRequest for LocalUser {
     type Error = actix_web::Error;
     type Future = Ready<actix_web::Result<LocalUser>>;
@@ -187,6 +411,23 @@ impl FromRequest for LocalUser {
             return ready(Ok(LocalUser::Anonymous));
         };
 
+        // logins that were not remembered expire before the session cookie does
+        let Ok(session) = Session::from_request(req, payload).into_inner() else {
+            log::debug!("No session");
+            return ready(Ok(LocalUser::Anonymous));
+        };
+        if session::is_expired(&session) {
+            log::debug!("Expired session");
+            // the recorded login stops being listed along with the cookie
+            if let Some(Ok(mut conn)) = req.app_data::<web::Data<DbPool>>().map(|pool| pool.get()) {
+                if let Err(e) = session::end_login(&mut conn, &session) {
+                    log::error!("Não foi possível encerrar o login: {:?}", e);
+                }
+            }
+            identity.logout();
+            return ready(Ok(LocalUser::Anonymous));
+        }
+
         // get the user id from the identity
         let Ok(id) = identity.id() else {
             println!("No id");
@@ -211,28 +452,56 @@ impl FromRequest for LocalUser {
         };
 
         // get the user from the database
-        let Ok((user_id, nickname, avatar_seed, status)) = users::table
+        let Ok((user_id, nickname, avatar_seed, status, role)) = users::table
             .find(id)
             .select((
                 users::id,
                 users::nickname,
                 users::avatar_seed,
                 users::status,
+                users::role,
             ))
-            .first::<(Uuid, String, Uuid, AccountStatus)>(&mut conn)
+            .first::<(Uuid, String, Uuid, AccountStatus, Role)>(&mut conn)
         else {
             println!("No user");
             return ready(Ok(LocalUser::Anonymous));
         };
 
+        // disabled accounts lose their sessions, checked before the login itself since
+        // disabling the account also revokes its logins
+        if let AccountStatus::DISABLED = status {
+            log::debug!("Disabled account");
+            if let Err(e) = session::end_login(&mut conn, &session) {
+                log::error!("Não foi possível encerrar o login: {:?}", e);
+            }
+            identity.logout();
+            return ready(Ok(LocalUser::Disabled));
+        }
+
+        // the login may have been revoked from the sessions page
+        if !session::touch_login(&mut conn, &session, user_id) {
+            log::debug!("Revoked session");
+            identity.logout();
+            return ready(Ok(LocalUser::Anonymous));
+        }
+
         if let AccountStatus::PENDING = status {
             return ready(Ok(LocalUser::Pending));
         }
 
+        // a missing counter isn't worth failing the page over
+        let unread_notifications =
+            notifications::unread_count(&mut conn, user_id).unwrap_or_else(|e| {
+                log::error!("Não foi possível contar as notificações: {:?}", e);
+                0
+            });
+
         ready(Ok(LocalUser::Authenticated {
             id: user_id,
             nickname,
             avatar_seed,
+            role,
+            unread_notifications,
         }))
     }
 }
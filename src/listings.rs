@@ -0,0 +1,428 @@
+// operations on listings shared by the pages and the api
+
+use std::collections::HashMap;
+
+use actix_web::HttpRequest;
+use aws_sdk_s3::Client;
+use chrono::{DateTime, Utc};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, JoinOnDsl, OptionalExtension,
+    PgTextExpressionMethods, QueryDsl, QueryResult, RunQueryDsl,
+};
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditEvent},
+    content_filter::{ContentFilter, FilterAction, Verdict},
+    favorites::FavoriteEvent,
+    like_pattern,
+    moderation::{self, ModerationAction},
+    schema::{attachments, exchanges, favorites, listings, reports, saved_search_matches, users},
+    storage, Campus, DbConn, Type,
+};
+
+pub const TITLE_MAX_LENGTH: usize = 255;
+pub const DESCRIPTION_MAX_LENGTH: usize = 4096;
+/// most images a listing can have, counting the ones added after it was created
+pub const MAX_IMAGES: usize = 10;
+
+/// a listing along with who posted it, as shown in lists
+pub struct ListingSummary {
+    pub id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub listing_type: Type,
+    pub campus: Campus,
+    pub reserved: bool,
+    pub hidden: bool,
+    pub created_at: DateTime<Utc>,
+    pub creator_id: Uuid,
+    pub creator_nickname: String,
+    pub creator_avatar_seed: Uuid,
+}
+
+type SummaryRow = (
+    Uuid,
+    String,
+    String,
+    Type,
+    Campus,
+    bool,
+    bool,
+    DateTime<Utc>,
+    Uuid,
+    String,
+    Uuid,
+);
+
+fn from_row(
+    (
+        id,
+        title,
+        description,
+        listing_type,
+        campus,
+        reserved,
+        hidden,
+        created_at,
+        creator_id,
+        creator_nickname,
+        creator_avatar_seed,
+    ): SummaryRow,
+) -> ListingSummary {
+    ListingSummary {
+        id,
+        title,
+        description,
+        listing_type,
+        campus,
+        reserved,
+        hidden,
+        created_at,
+        creator_id,
+        creator_nickname,
+        creator_avatar_seed,
+    }
+}
+
+/// narrows down `search`, only the filters that are set are applied
+#[derive(Default)]
+pub struct ListingFilter {
+    /// every word has to be in the title or the description
+    pub keywords: Option<String>,
+    pub campus: Option<Campus>,
+    pub listing_type: Option<Type>,
+    pub creator_id: Option<Uuid>,
+}
+
+/// the listings anyone can see, visible and not completed yet, newest first
+pub fn search(
+    conn: &mut DbConn,
+    filter: &ListingFilter,
+    limit: i64,
+    offset: i64,
+) -> QueryResult<Vec<ListingSummary>> {
+    let mut query = listings::table
+        .inner_join(users::table.on(listings::creator_id.eq(users::id)))
+        .filter(listings::hidden.eq(false))
+        .filter(crate::exchanges::is_active())
+        .into_boxed();
+    if let Some(ref keywords) = filter.keywords {
+        for keyword in keywords.split_whitespace() {
+            let pattern = like_pattern(keyword);
+            query = query.filter(
+                listings::title
+                    .ilike(pattern.clone())
+                    .or(listings::description.ilike(pattern)),
+            );
+        }
+    }
+    if let Some(campus) = filter.campus {
+        query = query.filter(listings::campus.eq(campus));
+    }
+    if let Some(listing_type) = filter.listing_type {
+        query = query.filter(listings::type_.eq(listing_type));
+    }
+    if let Some(creator_id) = filter.creator_id {
+        query = query.filter(listings::creator_id.eq(creator_id));
+    }
+
+    Ok(query
+        .order_by(listings::created_at.desc())
+        .limit(limit)
+        .offset(offset)
+        .select((
+            listings::id,
+            listings::title,
+            listings::description,
+            listings::type_,
+            listings::campus,
+            listings::reserved,
+            listings::hidden,
+            listings::created_at,
+            users::id,
+            users::nickname,
+            users::avatar_seed,
+        ))
+        .load::<SummaryRow>(conn)?
+        .into_iter()
+        .map(from_row)
+        .collect())
+}
+
+/// a single listing, hidden or not. who gets to see it is up to the caller
+pub fn find(conn: &mut DbConn, listing_id: Uuid) -> QueryResult<Option<ListingSummary>> {
+    Ok(listings::table
+        .inner_join(users::table.on(listings::creator_id.eq(users::id)))
+        .filter(listings::id.eq(listing_id))
+        .select((
+            listings::id,
+            listings::title,
+            listings::description,
+            listings::type_,
+            listings::campus,
+            listings::reserved,
+            listings::hidden,
+            listings::created_at,
+            users::id,
+            users::nickname,
+            users::avatar_seed,
+        ))
+        .first::<SummaryRow>(conn)
+        .optional()?
+        .map(from_row))
+}
+
+/// ids of the listing's images, in the order they were added
+pub fn attachment_ids(conn: &mut DbConn, listing_id: Uuid) -> QueryResult<Vec<Uuid>> {
+    attachments::table
+        .filter(attachments::listing_id.eq(listing_id))
+        .select(attachments::id)
+        .load::<Uuid>(conn)
+}
+
+/// ids of the images of each listing, in the order they were added, loaded in a single query.
+/// listings without images are left out
+pub fn attachment_ids_among(
+    conn: &mut DbConn,
+    listing_ids: &[Uuid],
+) -> QueryResult<HashMap<Uuid, Vec<Uuid>>> {
+    let mut images: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for (listing_id, attachment_id) in attachments::table
+        .filter(attachments::listing_id.eq_any(listing_ids))
+        .select((attachments::listing_id, attachments::id))
+        .load::<(Uuid, Uuid)>(conn)?
+    {
+        images.entry(listing_id).or_default().push(attachment_id);
+    }
+    Ok(images)
+}
+
+/// what the poster writes about a listing
+pub struct ListingDetails {
+    pub title: String,
+    pub description: String,
+    pub listing_type: Type,
+    pub campus: Campus,
+}
+
+/// what the content filter made of a new or edited listing
+pub enum Submission {
+    /// it doesn't follow the community guidelines and wasn't saved
+    Rejected,
+    /// it was saved, hidden until a moderator shows it when `hidden` is set
+    Accepted { listing_id: Uuid, hidden: bool },
+}
+
+// sends flagged and hidden listings to the moderation queue. failures are only logged, the
+// listing was already saved
+fn queue_for_moderation(conn: &mut DbConn, listing_id: Uuid, creator_id: Uuid, verdict: &Verdict) {
+    let filter_action = match verdict.action {
+        FilterAction::Flag => ModerationAction::FilterFlag,
+        FilterAction::Hide => ModerationAction::FilterHide,
+        _ => return,
+    };
+    let summary = verdict.summary();
+    if let Err(e) = moderation::flag_listing(conn, listing_id, &summary).and_then(|_| {
+        moderation::record_action(
+            conn,
+            None,
+            filter_action,
+            Some(listing_id),
+            Some(creator_id),
+            &summary,
+        )
+    }) {
+        log::error!(
+            "Não foi possível registrar a decisão do filtro sobre o item {}: {:?}",
+            listing_id,
+            e
+        );
+    }
+}
+
+// checks the text against the community guidelines, recording the listings turned down
+fn check_content(
+    conn: &mut DbConn,
+    content_filter: &ContentFilter,
+    creator_id: Uuid,
+    details: &ListingDetails,
+) -> Verdict {
+    let verdict = content_filter.check(&format!("{}\n{}", details.title, details.description));
+    if verdict.action == FilterAction::Reject {
+        if let Err(e) = moderation::record_action(
+            conn,
+            None,
+            ModerationAction::FilterReject,
+            None,
+            Some(creator_id),
+            &format!("\"{}\": {}", details.title, verdict.summary()),
+        ) {
+            log::error!("Não foi possível registrar a decisão do filtro: {:?}", e);
+        }
+    }
+    verdict
+}
+
+/// saves a new listing once the content filter lets it through
+pub fn create_listing(
+    conn: &mut DbConn,
+    content_filter: &ContentFilter,
+    creator_id: Uuid,
+    details: &ListingDetails,
+) -> QueryResult<Submission> {
+    let verdict = check_content(conn, content_filter, creator_id, details);
+    if verdict.action == FilterAction::Reject {
+        return Ok(Submission::Rejected);
+    }
+
+    let hidden = verdict.action == FilterAction::Hide;
+    let listing_id = diesel::insert_into(listings::table)
+        .values((
+            listings::id.eq(Uuid::new_v4()),
+            listings::title.eq(&details.title),
+            listings::description.eq(&details.description),
+            listings::type_.eq(details.listing_type),
+            listings::campus.eq(details.campus),
+            listings::creator_id.eq(creator_id),
+            listings::hidden.eq(hidden),
+        ))
+        .returning(listings::id)
+        .get_result::<Uuid>(conn)?;
+
+    queue_for_moderation(conn, listing_id, creator_id, &verdict);
+    Ok(Submission::Accepted { listing_id, hidden })
+}
+
+/// replaces the details of one of the user's listings, checked like a new one. a listing the
+/// filter hides stays hidden, but editing never shows one a moderator hid. `None` when the
+/// listing isn't the user's
+pub fn update_listing(
+    conn: &mut DbConn,
+    content_filter: &ContentFilter,
+    listing_id: Uuid,
+    owner_id: Uuid,
+    details: &ListingDetails,
+) -> QueryResult<Option<Submission>> {
+    let Some(was_hidden) = listings::table
+        .filter(listings::id.eq(listing_id))
+        .filter(listings::creator_id.eq(owner_id))
+        .select(listings::hidden)
+        .first::<bool>(conn)
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    let verdict = check_content(conn, content_filter, owner_id, details);
+    if verdict.action == FilterAction::Reject {
+        return Ok(Some(Submission::Rejected));
+    }
+
+    let hidden = was_hidden || verdict.action == FilterAction::Hide;
+    diesel::update(listings::table.find(listing_id))
+        .set((
+            listings::title.eq(&details.title),
+            listings::description.eq(&details.description),
+            listings::type_.eq(details.listing_type),
+            listings::campus.eq(details.campus),
+            listings::hidden.eq(hidden),
+            listings::updated_at.eq(Utc::now()),
+        ))
+        .execute(conn)?;
+
+    queue_for_moderation(conn, listing_id, owner_id, &verdict);
+    Ok(Some(Submission::Accepted { listing_id, hidden }))
+}
+
+/// what's left of a listing after `delete_listing`
+pub struct DeletedListing {
+    pub creator_id: Uuid,
+    pub title: String,
+    pub attachment_ids: Vec<Uuid>,
+}
+
+/// removes a listing, its images and everything pointing to it, records the removal by
+/// `actor_id` in the audit log and tells the users who saved it. exchanges made through it are
+/// kept without the listing. the audit entry, files and notices are only logged when they
+/// fail, the listing is already gone. `None` when the listing doesn't exist
+pub async fn delete_listing(
+    conn: &mut DbConn,
+    req: &HttpRequest,
+    s3_client: &Client,
+    mg_api_key: &str,
+    listing_id: Uuid,
+    actor_id: Uuid,
+) -> QueryResult<Option<DeletedListing>> {
+    // loaded before the listing is deleted, the emails are only sent once that's done
+    let notices = crate::favorites::notices(conn, &[listing_id]).unwrap_or_else(|e| {
+        log::error!("Não foi possível obter quem salvou o anúncio: {:?}", e);
+        Vec::new()
+    });
+
+    let Some(deleted) = delete_rows(conn, listing_id)? else {
+        return Ok(None);
+    };
+
+    if let Err(e) = audit::record(
+        conn,
+        req,
+        AuditEvent::ListingRemoval,
+        Some(deleted.creator_id),
+        Some(actor_id),
+        &deleted.title,
+    ) {
+        log::error!("Não foi possível registrar a auditoria: {:?}", e);
+    }
+
+    let failures =
+        storage::delete_attachments(s3_client, deleted.creator_id, &deleted.attachment_ids).await;
+    if failures > 0 {
+        log::warn!(
+            "{} anexo(s) do anúncio {} não foram removidos do armazenamento",
+            failures,
+            listing_id
+        );
+    }
+
+    crate::favorites::send_notices(conn, mg_api_key, &notices, FavoriteEvent::Unavailable).await;
+    Ok(Some(deleted))
+}
+
+// removes the listing and its attachment rows in a transaction, returning the attachment ids so
+// the files can be removed from storage once it commits
+fn delete_rows(conn: &mut DbConn, listing_id: Uuid) -> QueryResult<Option<DeletedListing>> {
+    conn.transaction(|conn| {
+        let Some((creator_id, title)) = listings::table
+            .find(listing_id)
+            .select((listings::creator_id, listings::title))
+            .first::<(Uuid, String)>(conn)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let attachment_ids =
+            diesel::delete(attachments::table.filter(attachments::listing_id.eq(listing_id)))
+                .returning(attachments::id)
+                .get_results::<Uuid>(conn)?;
+        diesel::delete(reports::table.filter(reports::listing_id.eq(listing_id))).execute(conn)?;
+        diesel::delete(favorites::table.filter(favorites::listing_id.eq(listing_id)))
+            .execute(conn)?;
+        diesel::delete(
+            saved_search_matches::table.filter(saved_search_matches::listing_id.eq(listing_id)),
+        )
+        .execute(conn)?;
+        // the exchange and its reviews are part of the users' reputation, they stay
+        diesel::update(exchanges::table.filter(exchanges::listing_id.eq(listing_id)))
+            .set(exchanges::listing_id.eq(None::<Uuid>))
+            .execute(conn)?;
+        diesel::delete(listings::table.find(listing_id)).execute(conn)?;
+
+        Ok(Some(DeletedListing {
+            creator_id,
+            title,
+            attachment_ids,
+        }))
+    })
+}
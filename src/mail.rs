@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::{
+    notifications::{self, NotificationKind},
+    schema::users,
+    unsubscribe, AccountStatus, DbConn,
+};
+
+// posts one of the templates stored on mailgun, with any extra headers
+async fn send(
+    mg_api_key: &str,
+    email: &str,
+    template: &str,
+    subject: &str,
+    variables: &[(&str, &str)],
+    headers: &[(&str, &str)],
+) -> Result<(), ()> {
+    let client = reqwest::Client::new();
+    let variables = variables
+        .iter()
+        .map(|(name, value)| (format!("v:{}", name), *value))
+        .chain(
+            headers
+                .iter()
+                .map(|(name, value)| (format!("h:{}", name), *value)),
+        )
+        .collect::<Vec<_>>();
+    let mut data = HashMap::new();
+    data.insert(
+        "from",
+        "Coisando Coisas <naoresponder@mg.coisandocoisas.cc>",
+    );
+    data.insert("to", email);
+    data.insert("template", template);
+    data.insert("subject", subject);
+    for (name, value) in &variables {
+        data.insert(name.as_str(), value);
+    }
+
+    let Ok(_response) = client
+        .post("https://api.mailgun.net/v3/mg.coisandocoisas.cc/messages")
+        .basic_auth("api", Some(mg_api_key))
+        .form(&data)
+        .send()
+        .await
+    else {
+        return Err(());
+    };
+    Ok(())
+}
+
+/// sends one of the templates stored on mailgun to the given address. only for emails about
+/// the account itself, which can't be turned off
+pub async fn send_template_email(
+    mg_api_key: &str,
+    email: &str,
+    template: &str,
+    subject: &str,
+    variables: &[(&str, &str)],
+) -> Result<(), ()> {
+    send(mg_api_key, email, template, subject, variables, &[]).await
+}
+
+/// sends a notification email to the user's address, unless they turned that kind off. the
+/// template gets an `unsubscribe_url` variable for its footer and the message the one-click
+/// unsubscribe headers
+pub async fn send_notification_email(
+    conn: &mut DbConn,
+    mg_api_key: &str,
+    user_id: Uuid,
+    kind: NotificationKind,
+    template: &str,
+    subject: &str,
+    variables: &[(&str, &str)],
+) -> Result<(), ()> {
+    // `None` when the kind is turned off or the account is gone, disabled or not confirmed
+    let recipient = notifications::email_enabled(conn, user_id, kind).and_then(|enabled| {
+        users::table
+            .find(user_id)
+            .filter(users::status.eq(AccountStatus::CONFIRMED))
+            .select(users::email)
+            .first::<String>(conn)
+            .optional()
+            .map(|email| email.filter(|_| enabled))
+    });
+    let email = match recipient {
+        Ok(Some(email)) => email,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            log::error!(
+                "Não foi possível obter as preferências de email de {}: {:?}",
+                user_id,
+                e
+            );
+            return Err(());
+        }
+    };
+
+    let unsubscribe_url = unsubscribe::page_url(user_id, kind);
+    let list_unsubscribe = format!("<{}>", unsubscribe::one_click_url(user_id, kind));
+    let variables = variables
+        .iter()
+        .copied()
+        .chain([("unsubscribe_url", unsubscribe_url.as_str())])
+        .collect::<Vec<_>>();
+    send(
+        mg_api_key,
+        &email,
+        template,
+        subject,
+        &variables,
+        &[
+            ("List-Unsubscribe", list_unsubscribe.as_str()),
+            ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"),
+        ],
+    )
+    .await
+}
@@ -1,13 +1,32 @@
+use std::time::Duration;
+
 use actix_identity::IdentityMiddleware;
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-use actix_web::{cookie::Key, middleware::Logger, web, App, HttpServer};
+use actix_session::SessionMiddleware;
+use actix_web::{
+    cookie::SameSite,
+    middleware::{from_fn, Logger},
+    web, App, HttpServer,
+};
 use aws_config::BehaviorVersion;
+use coisando_coisas::{
+    audit, captcha,
+    content_filter::ContentFilter,
+    csrf,
+    rate_limit::{self, RateLimitConfig, RateLimiter},
+    saved_searches,
+    session::{self, SessionBackend},
+    unsubscribe,
+};
 use diesel::{r2d2, PgConnection};
 use dotenvy::dotenv;
 use env_logger::Env;
 
+mod api;
 mod pages;
-use pages::{auth, index, info, submit};
+use pages::{
+    admin, auth, events, favorites, index, info, moderation, notifications, profile, searches,
+    sessions, submit, two_factor,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -20,7 +39,70 @@ async fn main() -> std::io::Result<()> {
         .build(manager)
         .expect("Failed to create pool");
 
-    let secret_key = Key::generate();
+    // audit entries past the retention period and expired logins are removed once a day
+    let audit_pool = pool.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            let Ok(mut conn) = audit_pool.get() else {
+                log::error!("Não foi possível conectar ao banco de dados para limpar a auditoria");
+                continue;
+            };
+            match audit::purge_expired(&mut conn) {
+                Ok(removed) => {
+                    log::info!("{} registro(s) de auditoria expirados removidos", removed)
+                }
+                Err(e) => log::error!("Não foi possível limpar a auditoria: {:?}", e),
+            }
+            match session::purge_expired_logins(&mut conn) {
+                Ok(removed) => log::info!("{} login(s) expirados removidos", removed),
+                Err(e) => log::error!("Não foi possível limpar os logins expirados: {:?}", e),
+            }
+        }
+    });
+
+    // matches of daily saved searches are sent together once a day, at a fixed hour. every
+    // instance checks often, whoever claims the day's digest first sends it
+    let digest_pool = pool.clone();
+    let mg_api_key =
+        std::env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    saved_searches::digest_hour();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(15 * 60));
+        // the first tick is immediate, wait a full interval after starting instead
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Ok(mut conn) = digest_pool.get() else {
+                log::error!(
+                    "Não foi possível conectar ao banco de dados para enviar os resumos de buscas"
+                );
+                continue;
+            };
+            match saved_searches::claim_digest(&mut conn) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    log::error!("Não foi possível verificar o resumo de buscas: {:?}", e);
+                    continue;
+                }
+            }
+            match saved_searches::take_digests(&mut conn) {
+                Ok(digests) => saved_searches::send_digests(&mut conn, &mg_api_key, &digests).await,
+                Err(e) => log::error!("Não foi possível obter os resumos de buscas: {:?}", e),
+            }
+        }
+    });
+
+    let secret_key = session::secret_key();
+    unsubscribe::secret_key();
+    let session_store = SessionBackend::from_env(pool.clone()).await;
+
+    // shared by all workers so the limits apply to the whole server
+    let rate_limiter = web::Data::new(RateLimiter::new(RateLimitConfig::from_env()));
+    let challenge = web::Data::from(captcha::from_env());
+    let content_filter = web::Data::new(ContentFilter::from_env());
 
     let endpoint_url =
         std::env::var("AWS_S3_ENDPOINT_URL").expect("AWS_S3_ENDPOINT_URL must be set");
@@ -34,16 +116,37 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(s3_client.clone()))
+            .app_data(rate_limiter.clone())
+            .app_data(challenge.clone())
+            .app_data(content_filter.clone())
+            .wrap(from_fn(csrf::csrf_protect))
+            .wrap(from_fn(rate_limit::rate_limit))
             .wrap(Logger::default())
             .wrap(IdentityMiddleware::default())
-            .wrap(SessionMiddleware::new(
-                CookieSessionStore::default(),
-                secret_key.clone(),
-            ))
+            .wrap(
+                SessionMiddleware::builder(session_store.clone(), secret_key.clone())
+                    .session_lifecycle(session::lifecycle())
+                    // the identity lives in this cookie. lax keeps it out of cross-site posts
+                    // while still allowing the links sent by email to land logged in
+                    .cookie_same_site(SameSite::Lax)
+                    .cookie_http_only(true)
+                    .cookie_secure(session::cookie_secure())
+                    .build(),
+            )
+            .configure(api::config)
             .configure(index::config)
             .configure(submit::config)
             .configure(auth::config)
             .configure(info::config)
+            .configure(sessions::config)
+            .configure(two_factor::config)
+            .configure(admin::config)
+            .configure(moderation::config)
+            .configure(profile::config)
+            .configure(favorites::config)
+            .configure(searches::config)
+            .configure(notifications::config)
+            .configure(events::config)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
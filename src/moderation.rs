@@ -0,0 +1,299 @@
+// reports sent by users and the record of what moderators did about them
+
+use std::{fmt, io::Write};
+
+use chrono::Utc;
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::Pg,
+    serialize::{IsNull, ToSql},
+    ExpressionMethods, OptionalExtension, QueryDsl, QueryResult, RunQueryDsl,
+};
+use uuid::Uuid;
+
+use crate::{
+    schema::{
+        listings, moderation_actions, reports,
+        sql_types::{ModerationAction as ModerationActionType, ReportReason as ReportReasonType},
+    },
+    DbConn,
+};
+
+/// longest text accepted with a report or a moderator's note
+pub const DETAILS_MAX_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression)]
+#[diesel(sql_type = ReportReasonType)]
+pub enum ReportReason {
+    Spam,
+    Sale,
+    Offensive,
+    Prohibited,
+    Scam,
+    Other,
+    /// filed by the content filter, never offered in the form
+    Filter,
+}
+
+impl ReportReason {
+    /// every reason, in the order they are offered in the form
+    pub const ALL: [ReportReason; 6] = [
+        ReportReason::Spam,
+        ReportReason::Sale,
+        ReportReason::Offensive,
+        ReportReason::Prohibited,
+        ReportReason::Scam,
+        ReportReason::Other,
+    ];
+
+    /// value used in forms
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReportReason::Spam => "spam",
+            ReportReason::Sale => "venda",
+            ReportReason::Offensive => "ofensivo",
+            ReportReason::Prohibited => "proibido",
+            ReportReason::Scam => "golpe",
+            ReportReason::Other => "outro",
+            ReportReason::Filter => "filtro",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|reason| reason.code() == code)
+    }
+}
+
+impl fmt::Display for ReportReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportReason::Spam => write!(f, "Spam ou conteúdo repetido"),
+            ReportReason::Sale => write!(f, "Venda ou comércio"),
+            ReportReason::Offensive => write!(f, "Conteúdo ofensivo"),
+            ReportReason::Prohibited => write!(f, "Item proibido"),
+            ReportReason::Scam => write!(f, "Golpe ou fraude"),
+            ReportReason::Other => write!(f, "Outro motivo"),
+            ReportReason::Filter => write!(f, "Filtro automático"),
+        }
+    }
+}
+
+impl ToSql<ReportReasonType, Pg> for ReportReason {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            ReportReason::Spam => out.write_all(b"SPAM")?,
+            ReportReason::Sale => out.write_all(b"SALE")?,
+            ReportReason::Offensive => out.write_all(b"OFFENSIVE")?,
+            ReportReason::Prohibited => out.write_all(b"PROHIBITED")?,
+            ReportReason::Scam => out.write_all(b"SCAM")?,
+            ReportReason::Other => out.write_all(b"OTHER")?,
+            ReportReason::Filter => out.write_all(b"FILTER")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<ReportReasonType, Pg> for ReportReason {
+    fn from_sql(
+        bytes: <Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"SPAM" => Ok(ReportReason::Spam),
+            b"SALE" => Ok(ReportReason::Sale),
+            b"OFFENSIVE" => Ok(ReportReason::Offensive),
+            b"PROHIBITED" => Ok(ReportReason::Prohibited),
+            b"SCAM" => Ok(ReportReason::Scam),
+            b"OTHER" => Ok(ReportReason::Other),
+            b"FILTER" => Ok(ReportReason::Filter),
+            _ => Err("Unknown report reason".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression)]
+#[diesel(sql_type = ModerationActionType)]
+pub enum ModerationAction {
+    HideListing,
+    ShowListing,
+    WarnUser,
+    DisableUser,
+    DismissReports,
+    FilterFlag,
+    FilterHide,
+    FilterReject,
+    RemoveReview,
+    EnableUser,
+}
+
+impl fmt::Display for ModerationAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModerationAction::HideListing => write!(f, "Ocultou anúncio"),
+            ModerationAction::ShowListing => write!(f, "Reexibiu anúncio"),
+            ModerationAction::WarnUser => write!(f, "Advertiu usuário"),
+            ModerationAction::DisableUser => write!(f, "Desativou conta"),
+            ModerationAction::DismissReports => write!(f, "Descartou denúncias"),
+            ModerationAction::FilterFlag => write!(f, "Filtro sinalizou anúncio"),
+            ModerationAction::FilterHide => write!(f, "Filtro ocultou anúncio"),
+            ModerationAction::FilterReject => write!(f, "Filtro recusou anúncio"),
+            ModerationAction::RemoveReview => write!(f, "Removeu avaliação"),
+            ModerationAction::EnableUser => write!(f, "Reativou conta"),
+        }
+    }
+}
+
+impl ToSql<ModerationActionType, Pg> for ModerationAction {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            ModerationAction::HideListing => out.write_all(b"HIDE_LISTING")?,
+            ModerationAction::ShowListing => out.write_all(b"SHOW_LISTING")?,
+            ModerationAction::WarnUser => out.write_all(b"WARN_USER")?,
+            ModerationAction::DisableUser => out.write_all(b"DISABLE_USER")?,
+            ModerationAction::DismissReports => out.write_all(b"DISMISS_REPORTS")?,
+            ModerationAction::FilterFlag => out.write_all(b"FILTER_FLAG")?,
+            ModerationAction::FilterHide => out.write_all(b"FILTER_HIDE")?,
+            ModerationAction::FilterReject => out.write_all(b"FILTER_REJECT")?,
+            ModerationAction::RemoveReview => out.write_all(b"REMOVE_REVIEW")?,
+            ModerationAction::EnableUser => out.write_all(b"ENABLE_USER")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<ModerationActionType, Pg> for ModerationAction {
+    fn from_sql(
+        bytes: <Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"HIDE_LISTING" => Ok(ModerationAction::HideListing),
+            b"SHOW_LISTING" => Ok(ModerationAction::ShowListing),
+            b"WARN_USER" => Ok(ModerationAction::WarnUser),
+            b"DISABLE_USER" => Ok(ModerationAction::DisableUser),
+            b"DISMISS_REPORTS" => Ok(ModerationAction::DismissReports),
+            b"FILTER_FLAG" => Ok(ModerationAction::FilterFlag),
+            b"FILTER_HIDE" => Ok(ModerationAction::FilterHide),
+            b"FILTER_REJECT" => Ok(ModerationAction::FilterReject),
+            b"REMOVE_REVIEW" => Ok(ModerationAction::RemoveReview),
+            b"ENABLE_USER" => Ok(ModerationAction::EnableUser),
+            _ => Err("Unknown moderation action".into()),
+        }
+    }
+}
+
+/// what a report is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReportTarget {
+    Listing(Uuid),
+    User(Uuid),
+}
+
+/// records a report, unless the reporter already has an open one about the same target.
+/// returns whether a new report was created
+pub fn create_report(
+    conn: &mut DbConn,
+    reporter_id: Uuid,
+    target: ReportTarget,
+    reason: ReportReason,
+    details: &str,
+) -> QueryResult<bool> {
+    let (listing_id, reported_user_id) = match target {
+        ReportTarget::Listing(id) => (Some(id), None),
+        ReportTarget::User(id) => (None, Some(id)),
+    };
+
+    let open_query = reports::table
+        .filter(reports::reporter_id.eq(reporter_id))
+        .filter(reports::resolved_at.is_null())
+        .select(reports::id)
+        .into_boxed();
+    let open_query = match target {
+        ReportTarget::Listing(id) => open_query.filter(reports::listing_id.eq(id)),
+        ReportTarget::User(id) => open_query.filter(reports::reported_user_id.eq(id)),
+    };
+    if open_query.first::<Uuid>(conn).optional()?.is_some() {
+        return Ok(false);
+    }
+
+    diesel::insert_into(reports::table)
+        .values((
+            reports::id.eq(Uuid::new_v4()),
+            reports::reporter_id.eq(Some(reporter_id)),
+            reports::listing_id.eq(listing_id),
+            reports::reported_user_id.eq(reported_user_id),
+            reports::reason.eq(reason),
+            reports::details.eq(details),
+        ))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// puts a listing caught by the content filter in the moderation queue, as a report without
+/// a reporter
+pub fn flag_listing(conn: &mut DbConn, listing_id: Uuid, details: &str) -> QueryResult<()> {
+    diesel::insert_into(reports::table)
+        .values((
+            reports::id.eq(Uuid::new_v4()),
+            reports::listing_id.eq(listing_id),
+            reports::reason.eq(ReportReason::Filter),
+            reports::details.eq(details),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// closes every open report about the target
+pub fn resolve_reports(conn: &mut DbConn, target: ReportTarget) -> QueryResult<usize> {
+    match target {
+        ReportTarget::Listing(id) => diesel::update(
+            reports::table
+                .filter(reports::listing_id.eq(id))
+                .filter(reports::resolved_at.is_null()),
+        )
+        .set(reports::resolved_at.eq(Utc::now()))
+        .execute(conn),
+        ReportTarget::User(id) => diesel::update(
+            reports::table
+                .filter(reports::reported_user_id.eq(id))
+                .filter(reports::resolved_at.is_null()),
+        )
+        .set(reports::resolved_at.eq(Utc::now()))
+        .execute(conn),
+    }
+}
+
+/// adds an entry to the moderation history. `moderator_id` is `None` for automatic decisions
+pub fn record_action(
+    conn: &mut DbConn,
+    moderator_id: Option<Uuid>,
+    action: ModerationAction,
+    listing_id: Option<Uuid>,
+    target_user_id: Option<Uuid>,
+    note: &str,
+) -> QueryResult<()> {
+    diesel::insert_into(moderation_actions::table)
+        .values((
+            moderation_actions::id.eq(Uuid::new_v4()),
+            moderation_actions::moderator_id.eq(moderator_id),
+            moderation_actions::action.eq(action),
+            moderation_actions::listing_id.eq(listing_id),
+            moderation_actions::target_user_id.eq(target_user_id),
+            moderation_actions::note.eq(note),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// hides or shows a listing, returning whether it exists
+pub fn set_listing_hidden(conn: &mut DbConn, listing_id: Uuid, hidden: bool) -> QueryResult<bool> {
+    let updated = diesel::update(listings::table.find(listing_id))
+        .set(listings::hidden.eq(hidden))
+        .execute(conn)?;
+    Ok(updated > 0)
+}
@@ -0,0 +1,343 @@
+// in-app notifications shown in the notification center, and the kinds each user wants to get
+// in the app and by email
+
+use std::{fmt, io::Write};
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::Pg,
+    serialize::{IsNull, ToSql},
+    upsert::excluded,
+    ExpressionMethods, OptionalExtension, QueryDsl, QueryResult, RunQueryDsl,
+};
+use uuid::Uuid;
+
+use crate::{
+    realtime::{self, Event},
+    schema::{
+        notification_preferences, notifications, sql_types::NotificationKind as KindType, users,
+    },
+    DbConn,
+};
+
+pub const MESSAGE_MAX_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, FromSqlRow, AsExpression)]
+#[diesel(sql_type = KindType)]
+pub enum NotificationKind {
+    Message,
+    Reservation,
+    Exchange,
+    SavedSearch,
+    Moderation,
+}
+
+impl NotificationKind {
+    // `Message` is left out until there are messages to notify about
+    pub const ALL: [NotificationKind; 4] = [
+        NotificationKind::Reservation,
+        NotificationKind::Exchange,
+        NotificationKind::SavedSearch,
+        NotificationKind::Moderation,
+    ];
+
+    /// value used in forms
+    pub fn code(&self) -> &'static str {
+        match self {
+            NotificationKind::Message => "mensagens",
+            NotificationKind::Reservation => "reservas",
+            NotificationKind::Exchange => "trocas",
+            NotificationKind::SavedSearch => "buscas",
+            NotificationKind::Moderation => "moderacao",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.code() == code)
+    }
+}
+
+impl fmt::Display for NotificationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotificationKind::Message => write!(f, "Mensagens"),
+            NotificationKind::Reservation => write!(f, "Itens salvos reservados ou removidos"),
+            NotificationKind::Exchange => write!(f, "Trocas para confirmar"),
+            NotificationKind::SavedSearch => write!(f, "Novos itens das buscas salvas"),
+            NotificationKind::Moderation => write!(f, "Decisões da moderação"),
+        }
+    }
+}
+
+impl ToSql<KindType, Pg> for NotificationKind {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            NotificationKind::Message => out.write_all(b"MESSAGE")?,
+            NotificationKind::Reservation => out.write_all(b"RESERVATION")?,
+            NotificationKind::Exchange => out.write_all(b"EXCHANGE")?,
+            NotificationKind::SavedSearch => out.write_all(b"SAVED_SEARCH")?,
+            NotificationKind::Moderation => out.write_all(b"MODERATION")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<KindType, Pg> for NotificationKind {
+    fn from_sql(
+        bytes: <Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"MESSAGE" => Ok(NotificationKind::Message),
+            b"RESERVATION" => Ok(NotificationKind::Reservation),
+            b"EXCHANGE" => Ok(NotificationKind::Exchange),
+            b"SAVED_SEARCH" => Ok(NotificationKind::SavedSearch),
+            b"MODERATION" => Ok(NotificationKind::Moderation),
+            _ => Err("Unknown notification kind".into()),
+        }
+    }
+}
+
+/// how the user wants to get one kind of notification
+pub struct Preference {
+    pub kind: NotificationKind,
+    pub in_app: bool,
+    pub email: bool,
+}
+
+/// the user's preferences for every kind, in the order of `ALL`
+pub fn preferences(conn: &mut DbConn, user_id: Uuid) -> QueryResult<Vec<Preference>> {
+    let saved = notification_preferences::table
+        .filter(notification_preferences::user_id.eq(user_id))
+        .select((
+            notification_preferences::kind,
+            notification_preferences::in_app,
+            notification_preferences::email,
+        ))
+        .load::<(NotificationKind, bool, bool)>(conn)?;
+    Ok(NotificationKind::ALL
+        .into_iter()
+        .map(|kind| {
+            let (in_app, email) = saved
+                .iter()
+                .find(|(saved_kind, _, _)| *saved_kind == kind)
+                .map_or((true, true), |(_, in_app, email)| (*in_app, *email));
+            Preference {
+                kind,
+                in_app,
+                email,
+            }
+        })
+        .collect())
+}
+
+/// turns on the given kinds, in the app and by email, and off all the others
+pub fn set_preferences(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    in_app: &[NotificationKind],
+    email: &[NotificationKind],
+) -> QueryResult<()> {
+    let rows: Vec<_> = NotificationKind::ALL
+        .into_iter()
+        .map(|kind| {
+            (
+                notification_preferences::user_id.eq(user_id),
+                notification_preferences::kind.eq(kind),
+                notification_preferences::in_app.eq(in_app.contains(&kind)),
+                notification_preferences::email.eq(email.contains(&kind)),
+            )
+        })
+        .collect();
+    diesel::insert_into(notification_preferences::table)
+        .values(&rows)
+        .on_conflict((
+            notification_preferences::user_id,
+            notification_preferences::kind,
+        ))
+        .do_update()
+        .set((
+            notification_preferences::in_app.eq(excluded(notification_preferences::in_app)),
+            notification_preferences::email.eq(excluded(notification_preferences::email)),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// whether emails of this kind can be sent to the user
+pub fn email_enabled(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    kind: NotificationKind,
+) -> QueryResult<bool> {
+    Ok(notification_preferences::table
+        .find((user_id, kind))
+        .select(notification_preferences::email)
+        .first::<bool>(conn)
+        .optional()?
+        .unwrap_or(true))
+}
+
+/// stops the emails of one kind, leaving the in-app notifications as they were. used by the
+/// unsubscribe links, returns `false` when the user no longer exists
+pub fn unsubscribe(conn: &mut DbConn, user_id: Uuid, kind: NotificationKind) -> QueryResult<bool> {
+    let exists = users::table
+        .find(user_id)
+        .select(users::id)
+        .first::<Uuid>(conn)
+        .optional()?;
+    if exists.is_none() {
+        return Ok(false);
+    }
+
+    diesel::insert_into(notification_preferences::table)
+        .values((
+            notification_preferences::user_id.eq(user_id),
+            notification_preferences::kind.eq(kind),
+            notification_preferences::email.eq(false),
+        ))
+        .on_conflict((
+            notification_preferences::user_id,
+            notification_preferences::kind,
+        ))
+        .do_update()
+        .set(notification_preferences::email.eq(false))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// adds a notification for the user, unless they turned its kind off. `link` is where it
+/// takes them once opened
+pub fn notify(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    kind: NotificationKind,
+    message: &str,
+    link: &str,
+) -> QueryResult<()> {
+    let enabled = notification_preferences::table
+        .find((user_id, kind))
+        .select(notification_preferences::in_app)
+        .first::<bool>(conn)
+        .optional()?
+        .unwrap_or(true);
+    if !enabled {
+        return Ok(());
+    }
+
+    let message: String = message.chars().take(MESSAGE_MAX_LENGTH).collect();
+    diesel::insert_into(notifications::table)
+        .values((
+            notifications::id.eq(Uuid::new_v4()),
+            notifications::user_id.eq(user_id),
+            notifications::kind.eq(kind),
+            notifications::message.eq(&message),
+            notifications::link.eq(link),
+        ))
+        .execute(conn)?;
+
+    // pages the user has open update the counter right away
+    let unread = unread_count(conn, user_id)?;
+    realtime::publish(
+        user_id,
+        Event::Notification {
+            message,
+            link: link.to_string(),
+            unread,
+        },
+    );
+    Ok(())
+}
+
+/// shown next to the bell in the navbar
+pub fn unread_count(conn: &mut DbConn, user_id: Uuid) -> QueryResult<i64> {
+    notifications::table
+        .filter(notifications::user_id.eq(user_id))
+        .filter(notifications::read_at.is_null())
+        .count()
+        .get_result(conn)
+}
+
+pub struct Notification {
+    pub id: Uuid,
+    pub kind: NotificationKind,
+    pub message: String,
+    pub read: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// the user's notifications, newest first
+pub fn list(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    limit: i64,
+    offset: i64,
+) -> QueryResult<Vec<Notification>> {
+    Ok(notifications::table
+        .filter(notifications::user_id.eq(user_id))
+        .order_by(notifications::created_at.desc())
+        .limit(limit)
+        .offset(offset)
+        .select((
+            notifications::id,
+            notifications::kind,
+            notifications::message,
+            notifications::read_at,
+            notifications::created_at,
+        ))
+        .load::<(
+            Uuid,
+            NotificationKind,
+            String,
+            Option<DateTime<Utc>>,
+            DateTime<Utc>,
+        )>(conn)?
+        .into_iter()
+        .map(|(id, kind, message, read_at, created_at)| Notification {
+            id,
+            kind,
+            message,
+            read: read_at.is_some(),
+            created_at,
+        })
+        .collect())
+}
+
+/// marks one of the user's notifications as read, returning its link. `None` when it isn't
+/// theirs
+pub fn mark_read(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    notification_id: Uuid,
+) -> QueryResult<Option<String>> {
+    let link = diesel::update(
+        notifications::table
+            .filter(notifications::id.eq(notification_id))
+            .filter(notifications::user_id.eq(user_id)),
+    )
+    .set(notifications::read_at.eq(Utc::now()))
+    .returning(notifications::link)
+    .get_result::<String>(conn)
+    .optional()?;
+
+    let unread = unread_count(conn, user_id)?;
+    realtime::publish(user_id, Event::Unread { unread });
+    Ok(link)
+}
+
+pub fn mark_all_read(conn: &mut DbConn, user_id: Uuid) -> QueryResult<usize> {
+    let updated = diesel::update(
+        notifications::table
+            .filter(notifications::user_id.eq(user_id))
+            .filter(notifications::read_at.is_null()),
+    )
+    .set(notifications::read_at.eq(Utc::now()))
+    .execute(conn)?;
+
+    realtime::publish(user_id, Event::Unread { unread: 0 });
+    Ok(updated)
+}
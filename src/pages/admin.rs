@@ -0,0 +1,847 @@
+use std::{collections::HashMap, env, io};
+
+use actix_web::{
+    error::ErrorInternalServerError, get, post, web, web::Bytes, HttpRequest, HttpResponse,
+};
+use aws_sdk_s3::Client;
+use chrono::{DateTime, Utc};
+use coisando_coisas::{
+    audit::{self, AuditEvent},
+    csrf::CsrfToken,
+    enable_account, like_pattern, listings, lower,
+    moderation::{self, ModerationAction},
+    notifications::{self, NotificationKind},
+    permissions::AdminUser,
+    schema::{attachments, audit_log, confirmation_codes, listings as listings_table, users},
+    set_account_status, AccountStatus, Campus, DbConn, DbPool, Role, Type,
+};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, JoinOnDsl, OptionalExtension,
+    PgTextExpressionMethods, QueryDsl, RunQueryDsl,
+};
+use futures_util::{stream, StreamExt};
+use maud::{html, Markup};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{auth::ErrorQuery, render_base};
+
+// how many rows each admin list shows per page
+const PAGE_SIZE: usize = 50;
+// how many audit entries are read at a time when exporting
+const EXPORT_BATCH_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    busca: Option<String>,
+    deslocamento: Option<usize>,
+}
+
+fn render_admin_nav(active: &str) -> Markup {
+    html! {
+        ul .nav.nav-tabs.mb-3 {
+            li .nav-item {
+                a .nav-link.active[active == "usuarios"] href="/admin" { "Usuários" }
+            }
+            li .nav-item {
+                a .nav-link.active[active == "anuncios"] href="/admin/anúncios" { "Anúncios" }
+            }
+            li .nav-item {
+                a .nav-link.active[active == "auditoria"] href="/admin/auditoria" { "Auditoria" }
+            }
+        }
+    }
+}
+
+fn render_pagination(path: &str, search: &str, offset: usize, shown: usize) -> Markup {
+    let link = |offset: usize| {
+        let query =
+            serde_urlencoded::to_string([("busca", search), ("deslocamento", &offset.to_string())])
+                .unwrap_or_default();
+        format!("{}?{}", path, query)
+    };
+    html! {
+        div .hstack.gap-2 {
+            @if offset > 0 {
+                a .btn.btn-outline-secondary href=(link(offset.saturating_sub(PAGE_SIZE))) { "Anterior" }
+            }
+            @if shown == PAGE_SIZE {
+                a .btn.btn-outline-secondary href=(link(offset + PAGE_SIZE)) { "Próxima" }
+            }
+        }
+    }
+}
+
+#[get("/admin")]
+async fn users_page(
+    admin: AdminUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    query: web::Query<SearchQuery>,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let search = query.busca.clone().unwrap_or_default();
+    let offset = query.deslocamento.unwrap_or(0);
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let mut users_query = users::table
+        .select((
+            users::id,
+            users::nickname,
+            users::email,
+            users::status,
+            users::role,
+            users::created_at,
+        ))
+        .order_by(users::created_at.desc())
+        .limit(PAGE_SIZE as i64)
+        .offset(offset as i64)
+        .into_boxed();
+    if !search.trim().is_empty() {
+        let pattern = like_pattern(&search);
+        users_query = users_query.filter(
+            users::nickname
+                .ilike(pattern.clone())
+                .or(users::email.ilike(pattern)),
+        );
+    }
+    let Ok(results) =
+        users_query.load::<(Uuid, String, String, AccountStatus, Role, DateTime<Utc>)>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter os usuários",
+        ));
+    };
+
+    let markup = render_base(
+        html! {
+            h1 { "Administração" }
+            (render_admin_nav("usuarios"))
+
+            @if let Some(ref error) = error.erro {
+                div .alert.alert-danger role="alert" { (match error.as_str() {
+                    "propria-conta" => "Você não pode desativar sua própria conta.",
+                    "permissao" => "Você não pode desativar a conta de outro administrador.",
+                    _ => "Erro desconhecido."
+                }) }
+            }
+
+            form .hstack.gap-2.mb-3 method="get" action="/admin" {
+                input .form-control type="search" name="busca" placeholder="Apelido ou email" value=(search);
+                button .btn.btn-primary type="submit" { "Buscar" }
+            }
+
+            div .table-responsive {
+                table .table.align-middle {
+                    thead {
+                        tr {
+                            th { "Apelido" }
+                            th { "Email" }
+                            th { "Situação" }
+                            th { "Papel" }
+                            th { "Criada em" }
+                            th {}
+                        }
+                    }
+                    tbody {
+                        @for (id, nickname, email, status, role, created_at) in &results {
+                            tr {
+                                td { (nickname) }
+                                td .text-break { (email) }
+                                td {
+                                    @match status {
+                                        AccountStatus::PENDING => span .badge.text-bg-warning { "Pendente" },
+                                        AccountStatus::CONFIRMED => span .badge.text-bg-success { "Confirmada" },
+                                        AccountStatus::DISABLED => span .badge.text-bg-danger { "Desativada" },
+                                    }
+                                }
+                                td { (role) }
+                                td { (created_at.format("%d/%m/%Y")) }
+                                td .text-nowrap {
+                                    a .btn.btn-sm.btn-outline-secondary href=(format!("/admin/auditoria?busca={}", id)) { "Auditoria" }
+                                    " "
+                                    @if *status == AccountStatus::PENDING {
+                                        form .d-inline method="post" action=(format!("/admin/users/{}/confirm", id)) {
+                                            (csrf)
+                                            button .btn.btn-sm.btn-outline-success type="submit" { "Confirmar" }
+                                        }
+                                        " "
+                                    }
+                                    @if *status == AccountStatus::DISABLED {
+                                        form .d-inline method="post" action=(format!("/admin/users/{}/enable", id)) {
+                                            (csrf)
+                                            button .btn.btn-sm.btn-outline-primary type="submit" { "Reativar" }
+                                        }
+                                    } @else if *id != admin.id {
+                                        form .d-inline method="post" action=(format!("/admin/users/{}/disable", id)) {
+                                            (csrf)
+                                            button .btn.btn-sm.btn-outline-danger type="submit" { "Desativar" }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            (render_pagination("/admin", &search, offset, results.len()))
+        },
+        admin.user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[post("/admin/users/{user_id}/disable")]
+async fn disable_user(
+    admin: AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+
+    // an admin locking themselves out would need someone with database access to undo it
+    if user_id == admin.id {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/admin?erro=propria-conta"))
+            .finish());
+    }
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // other admins can only be disabled by someone with database access
+    let Ok(Some(role)) = users::table
+        .find(user_id)
+        .select(users::role)
+        .first::<Role>(&mut conn)
+        .optional()
+    else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/admin"))
+            .finish());
+    };
+    if role >= admin.role {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/admin?erro=permissao"))
+            .finish());
+    }
+
+    let result = conn.transaction(|conn| {
+        set_account_status(conn, user_id, AccountStatus::DISABLED)?;
+        moderation::record_action(
+            conn,
+            Some(admin.id),
+            ModerationAction::DisableUser,
+            None,
+            Some(user_id),
+            "Pela administração",
+        )
+    });
+    if let Err(e) = result {
+        log::error!("Não foi possível desativar a conta {}: {:?}", user_id, e);
+        return Err(ErrorInternalServerError(
+            "Não foi possível desativar a conta",
+        ));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/admin"))
+        .finish())
+}
+
+#[post("/admin/users/{user_id}/enable")]
+async fn enable_user(
+    admin: AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let result = conn.transaction(|conn| {
+        enable_account(conn, user_id)?;
+        moderation::record_action(
+            conn,
+            Some(admin.id),
+            ModerationAction::EnableUser,
+            None,
+            Some(user_id),
+            "Pela administração",
+        )
+    });
+    if let Err(e) = result {
+        log::error!("Não foi possível reativar a conta {}: {:?}", user_id, e);
+        return Err(ErrorInternalServerError(
+            "Não foi possível reativar a conta",
+        ));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/admin"))
+        .finish())
+}
+
+#[post("/admin/users/{user_id}/confirm")]
+async fn confirm_user(
+    _admin: AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // same as following the link in the confirmation email, so the code is spent too
+    let Ok(_) = conn.transaction(|conn| {
+        diesel::update(
+            users::table
+                .filter(users::id.eq(user_id))
+                .filter(users::status.eq(AccountStatus::PENDING)),
+        )
+        .set(users::status.eq(AccountStatus::CONFIRMED))
+        .execute(conn)?;
+        diesel::delete(confirmation_codes::table.filter(confirmation_codes::user_id.eq(user_id)))
+            .execute(conn)
+    }) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível confirmar a conta",
+        ));
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/admin"))
+        .finish())
+}
+
+#[get("/admin/anúncios")]
+async fn listings_page(
+    admin: AdminUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    query: web::Query<SearchQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let search = query.busca.clone().unwrap_or_default();
+    let offset = query.deslocamento.unwrap_or(0);
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let mut listings_query = listings_table::table
+        .inner_join(users::table.on(listings_table::creator_id.eq(users::id)))
+        .select((
+            listings_table::id,
+            listings_table::title,
+            listings_table::type_,
+            listings_table::campus,
+            listings_table::created_at,
+            users::nickname,
+        ))
+        .order_by(listings_table::created_at.desc())
+        .limit(PAGE_SIZE as i64)
+        .offset(offset as i64)
+        .into_boxed();
+    if !search.trim().is_empty() {
+        let pattern = like_pattern(&search);
+        listings_query = listings_query.filter(
+            listings_table::title
+                .ilike(pattern.clone())
+                .or(users::nickname.ilike(pattern)),
+        );
+    }
+    let Ok(results) =
+        listings_query.load::<(Uuid, String, Type, Campus, DateTime<Utc>, String)>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError("Não foi possível obter os itens"));
+    };
+
+    // number of attachments of each listing shown
+    let listing_ids: Vec<Uuid> = results.iter().map(|row| row.0).collect();
+    let Ok(attachment_counts) = attachments::table
+        .filter(attachments::listing_id.eq_any(&listing_ids))
+        .group_by(attachments::listing_id)
+        .select((attachments::listing_id, diesel::dsl::count_star()))
+        .load::<(Uuid, i64)>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError("Não foi possível obter os itens"));
+    };
+    let attachment_count = |listing_id: &Uuid| {
+        attachment_counts
+            .iter()
+            .find(|(id, _)| id == listing_id)
+            .map(|(_, count)| *count)
+            .unwrap_or(0)
+    };
+
+    let markup = render_base(
+        html! {
+            h1 { "Administração" }
+            (render_admin_nav("anuncios"))
+
+            form .hstack.gap-2.mb-3 method="get" action="/admin/anúncios" {
+                input .form-control type="search" name="busca" placeholder="Título ou apelido" value=(search);
+                button .btn.btn-primary type="submit" { "Buscar" }
+            }
+
+            div .table-responsive {
+                table .table.align-middle {
+                    thead {
+                        tr {
+                            th { "Título" }
+                            th { "Autor" }
+                            th { "Tipo" }
+                            th { "Campus" }
+                            th { "Imagens" }
+                            th { "Criado em" }
+                            th {}
+                        }
+                    }
+                    tbody {
+                        @for (id, title, type_, campus, created_at, nickname) in &results {
+                            tr {
+                                td { (title) }
+                                td { (nickname) }
+                                td { (type_) }
+                                td { (campus) }
+                                td { (attachment_count(id)) }
+                                td { (created_at.format("%d/%m/%Y")) }
+                                td {
+                                    form method="post" action=(format!("/admin/listings/{}/delete", id)) onsubmit="return confirm('Remover este anúncio e suas imagens?')" {
+                                        (csrf)
+                                        button .btn.btn-sm.btn-outline-danger type="submit" { "Remover" }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            (render_pagination("/admin/anúncios", &search, offset, results.len()))
+        },
+        admin.user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[post("/admin/listings/{listing_id}/delete")]
+async fn delete_listing(
+    req: HttpRequest,
+    admin: AdminUser,
+    pool: web::Data<DbPool>,
+    s3_client: web::Data<Client>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let listing_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    let Ok(deleted) = listings::delete_listing(
+        &mut conn,
+        &req,
+        &s3_client,
+        &mg_api_key,
+        listing_id,
+        admin.id,
+    )
+    .await
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível remover o anúncio",
+        ));
+    };
+
+    if let Some(deleted) = deleted {
+        if let Err(e) = notifications::notify(
+            &mut conn,
+            deleted.creator_id,
+            NotificationKind::Moderation,
+            &format!(
+                "Seu anúncio \"{}\" foi removido pela administração",
+                deleted.title
+            ),
+            "/diretrizes-da-comunidade",
+        ) {
+            log::error!(
+                "Não foi possível notificar o usuário {}: {:?}",
+                deleted.creator_id,
+                e
+            );
+        }
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/admin/anúncios"))
+        .finish())
+}
+
+struct AuditEntry {
+    id: Uuid,
+    event: AuditEvent,
+    user_id: Option<Uuid>,
+    actor_id: Option<Uuid>,
+    ip: String,
+    details: String,
+    created_at: DateTime<Utc>,
+}
+
+// the log is queried per user, by id (which still works after the account is deleted) or by
+// the current nickname. `Ok(None)` when nothing matches
+fn audit_user(conn: &mut DbConn, search: &str) -> diesel::QueryResult<Option<Uuid>> {
+    if let Ok(id) = Uuid::parse_str(search.trim()) {
+        return Ok(Some(id));
+    }
+    users::table
+        .filter(lower(users::nickname).eq(search.trim().to_lowercase()))
+        .select(users::id)
+        .first::<Uuid>(conn)
+        .optional()
+}
+
+// entries for a user, or for everyone, newest first. exports go through the log in batches
+// starting `after` the last entry of the previous one, so new entries don't shift them
+fn load_audit_entries(
+    conn: &mut DbConn,
+    user_id: Option<Uuid>,
+    limit: usize,
+    offset: usize,
+    after: Option<(DateTime<Utc>, Uuid)>,
+) -> diesel::QueryResult<(Vec<AuditEntry>, HashMap<Uuid, String>)> {
+    let mut query = audit_log::table
+        .order_by((audit_log::created_at.desc(), audit_log::id.desc()))
+        .limit(limit as i64)
+        .offset(offset as i64)
+        .select((
+            audit_log::id,
+            audit_log::event,
+            audit_log::user_id,
+            audit_log::actor_id,
+            audit_log::ip,
+            audit_log::details,
+            audit_log::created_at,
+        ))
+        .into_boxed();
+    if let Some(user_id) = user_id {
+        query = query.filter(audit_log::user_id.eq(user_id));
+    }
+    if let Some((created_at, id)) = after {
+        query = query.filter(
+            audit_log::created_at
+                .lt(created_at)
+                .or(audit_log::created_at
+                    .eq(created_at)
+                    .and(audit_log::id.lt(id))),
+        );
+    }
+    let entries: Vec<AuditEntry> = query
+        .load::<(
+            Uuid,
+            AuditEvent,
+            Option<Uuid>,
+            Option<Uuid>,
+            String,
+            String,
+            DateTime<Utc>,
+        )>(conn)?
+        .into_iter()
+        .map(
+            |(id, event, user_id, actor_id, ip, details, created_at)| AuditEntry {
+                id,
+                event,
+                user_id,
+                actor_id,
+                ip,
+                details,
+                created_at,
+            },
+        )
+        .collect();
+
+    // current nicknames, accounts deleted since then only show their id
+    let ids: Vec<Uuid> = entries
+        .iter()
+        .flat_map(|entry| [entry.user_id, entry.actor_id])
+        .flatten()
+        .collect();
+    let nicknames = users::table
+        .filter(users::id.eq_any(&ids))
+        .select((users::id, users::nickname))
+        .load::<(Uuid, String)>(conn)?
+        .into_iter()
+        .collect();
+
+    Ok((entries, nicknames))
+}
+
+#[get("/admin/auditoria")]
+async fn audit_page(
+    admin: AdminUser,
+    pool: web::Data<DbPool>,
+    query: web::Query<SearchQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let search = query.busca.clone().unwrap_or_default();
+    let offset = query.deslocamento.unwrap_or(0);
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let user_id = if search.trim().is_empty() {
+        None
+    } else {
+        let Ok(user_id) = audit_user(&mut conn, &search) else {
+            return Err(ErrorInternalServerError("Não foi possível obter o usuário"));
+        };
+        user_id
+    };
+    let (entries, nicknames) = if search.trim().is_empty() || user_id.is_some() {
+        let Ok(loaded) = load_audit_entries(&mut conn, user_id, PAGE_SIZE, offset, None) else {
+            return Err(ErrorInternalServerError(
+                "Não foi possível obter a auditoria",
+            ));
+        };
+        loaded
+    } else {
+        (Vec::new(), HashMap::new())
+    };
+    let describe = |id: &Option<Uuid>| match id {
+        Some(id) => nicknames
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| id.simple().to_string()),
+        None => String::new(),
+    };
+
+    let export_link = format!(
+        "/admin/auditoria/exportar?{}",
+        serde_urlencoded::to_string([("busca", &search)]).unwrap_or_default()
+    );
+    let markup = render_base(
+        html! {
+            h1 { "Administração" }
+            (render_admin_nav("auditoria"))
+
+            p .text-muted {
+                "Registros são mantidos por " (audit::retention().num_days()) " dias."
+            }
+
+            form .hstack.gap-2.mb-3 method="get" action="/admin/auditoria" {
+                input .form-control type="search" name="busca" placeholder="Apelido ou id do usuário" value=(search);
+                button .btn.btn-primary type="submit" { "Buscar" }
+                a .btn.btn-outline-secondary.text-nowrap href=(export_link) { "Exportar CSV" }
+            }
+
+            @if !search.trim().is_empty() && user_id.is_none() {
+                p { "Nenhum usuário encontrado." }
+            }
+
+            div .table-responsive {
+                table .table.align-middle {
+                    thead {
+                        tr {
+                            th { "Quando" }
+                            th { "Evento" }
+                            th { "Usuário" }
+                            th { "Feito por" }
+                            th { "IP" }
+                            th { "Detalhes" }
+                        }
+                    }
+                    tbody {
+                        @for entry in &entries {
+                            tr {
+                                td .text-nowrap { (entry.created_at.format("%d/%m/%Y %H:%M")) }
+                                td { (entry.event) }
+                                td .text-break { (describe(&entry.user_id)) }
+                                td .text-break { (describe(&entry.actor_id)) }
+                                td .font-monospace.small { (entry.ip) }
+                                td .text-break { (entry.details) }
+                            }
+                        }
+                    }
+                }
+            }
+
+            (render_pagination("/admin/auditoria", &search, offset, entries.len()))
+        },
+        admin.user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+// quotes a value for the csv export when it has a separator, quote or line break. values a
+// spreadsheet would run as a formula get a leading apostrophe, they can come from users
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+// the lines of the csv export for a batch of entries
+fn csv_rows(entries: &[AuditEntry], nicknames: &HashMap<Uuid, String>) -> String {
+    let nickname = |id: &Option<Uuid>| {
+        id.and_then(|id| nicknames.get(&id).cloned())
+            .unwrap_or_default()
+    };
+    let id = |id: &Option<Uuid>| id.map(|id| id.to_string()).unwrap_or_default();
+    let mut csv = String::new();
+    for entry in entries {
+        let row = [
+            entry.created_at.to_rfc3339(),
+            entry.event.code().to_string(),
+            id(&entry.user_id),
+            nickname(&entry.user_id),
+            id(&entry.actor_id),
+            nickname(&entry.actor_id),
+            entry.ip.clone(),
+            entry.details.clone(),
+        ];
+        csv.push_str(
+            &row.iter()
+                .map(|value| csv_field(value))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        csv.push('\n');
+    }
+    csv
+}
+
+#[get("/admin/auditoria/exportar")]
+async fn export_audit(
+    _admin: AdminUser,
+    pool: web::Data<DbPool>,
+    query: web::Query<SearchQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let search = query.busca.clone().unwrap_or_default();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let user_id = if search.trim().is_empty() {
+        None
+    } else {
+        match audit_user(&mut conn, &search) {
+            Ok(Some(user_id)) => Some(user_id),
+            Ok(None) => return Ok(HttpResponse::NotFound().body("Usuário não encontrado")),
+            Err(_) => return Err(ErrorInternalServerError("Não foi possível obter o usuário")),
+        }
+    };
+    // the log can be long, so it's sent while it's read instead of loaded whole
+    let pool = pool.into_inner();
+    let batches = stream::unfold(Some(None), move |after| {
+        let pool = pool.clone();
+        async move {
+            let after = after?;
+            let batch = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+                load_audit_entries(&mut conn, user_id, EXPORT_BATCH_SIZE, 0, after)
+                    .map_err(|e| e.to_string())
+            });
+            match batch {
+                Ok((entries, nicknames)) => {
+                    let next = match entries.last() {
+                        Some(last) if entries.len() == EXPORT_BATCH_SIZE => {
+                            Some(Some((last.created_at, last.id)))
+                        }
+                        _ => None,
+                    };
+                    Some((Ok(Bytes::from(csv_rows(&entries, &nicknames))), next))
+                }
+                Err(e) => {
+                    log::error!("Não foi possível exportar a auditoria: {}", e);
+                    Some((Err(io::Error::other(e)), None))
+                }
+            }
+        }
+    });
+    let header = Bytes::from_static(b"data,evento,usuario_id,usuario,autor_id,autor,ip,detalhes\n");
+    let csv = stream::once(async { Ok(header) }).chain(batches);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .append_header((
+            "Content-Disposition",
+            "attachment; filename=\"auditoria.csv\"",
+        ))
+        .streaming(csv))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(users_page)
+        .service(disable_user)
+        .service(enable_user)
+        .service(confirm_user)
+        .service(listings_page)
+        .service(delete_listing)
+        .service(audit_page)
+        .service(export_audit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_field_quotes_separators() {
+        assert_eq!(csv_field("simples"), "simples");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("diz \"oi\""), "\"diz \"\"oi\"\"\"");
+        assert_eq!(csv_field("duas\nlinhas"), "\"duas\nlinhas\"");
+    }
+
+    #[test]
+    fn csv_field_neutralises_formulas() {
+        assert_eq!(csv_field("=1+1"), "'=1+1");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\t=1+1"), "'\t=1+1");
+        assert_eq!(csv_field("\r=1+1"), "\"'\r=1+1\"");
+    }
+}
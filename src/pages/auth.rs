@@ -1,6 +1,7 @@
-use std::{collections::HashMap, env};
+use std::{env, sync::OnceLock};
 
 use actix_identity::Identity;
+use actix_session::Session;
 use actix_web::{
     error::ErrorInternalServerError, get, post, web, HttpMessage, HttpRequest, HttpResponse,
 };
@@ -8,32 +9,128 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
     Argon2, PasswordHash,
 };
+use aws_sdk_s3::Client;
+use chrono::{DateTime, Duration, Utc};
 use coisando_coisas::{
-    schema::{confirmation_codes, users},
-    AccountStatus, DbPool, LocalUser,
+    audit::{self, AuditEvent},
+    captcha::{Challenge, ChallengeResponse},
+    client_ip,
+    csrf::CsrfToken,
+    favorites::{self as saved_listings, FavoriteEvent},
+    lower,
+    mail::send_template_email,
+    notifications as notification_center,
+    policy::{self, NicknameError, PasswordError},
+    rate_limit::RateLimiter,
+    schema::{
+        attachments, confirmation_codes, email_changes, exchanges, favorites, listings,
+        notification_preferences, notifications, recovery_codes, reports, reviews,
+        saved_search_matches, saved_searches, totp_credentials, user_sessions, users,
+    },
+    session, storage, two_factor, AccountStatus, DbConn, DbPool, LocalUser,
 };
 use diesel::{
     query_dsl::methods::{FilterDsl, SelectDsl},
-    Connection, ExpressionMethods, OptionalExtension, RunQueryDsl,
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, RunQueryDsl,
 };
 use maud::html;
 use serde::Deserialize;
 use uuid::Uuid;
 
-use crate::pages::render_base;
+use crate::pages::{
+    notifications::render_preferences as render_notification_preferences, render_base,
+};
 
 #[derive(Deserialize)]
 struct UserLoginForm {
-    pub nickname: String,
+    // nickname or email
+    pub login: String,
     pub password: String,
+    pub remember: Option<String>,
+    #[serde(flatten)]
+    pub challenge: ChallengeResponse,
 }
 
 #[post("/entrar")]
 async fn login_user(
     req: HttpRequest,
+    session: Session,
     pool: web::Data<DbPool>,
+    limiter: web::Data<RateLimiter>,
+    challenge: web::Data<dyn Challenge>,
     details: web::Form<UserLoginForm>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    let attempt = LoginAttempt {
+        login: &details.login,
+        password: &details.password,
+        remember: details.remember.is_some(),
+        challenge: &details.challenge,
+    };
+    let location =
+        match attempt_login(&req, &session, &pool, &limiter, &**challenge, attempt).await? {
+            // success, redirect to account page
+            LoginOutcome::LoggedIn => "/minha-conta",
+            LoginOutcome::TwoFactorRequired => "/entrar/verificação",
+            // redirect, showing an error message
+            LoginOutcome::Locked => "/entrar?erro=bloqueado",
+            LoginOutcome::ChallengeFailed => "/entrar?erro=captcha",
+            LoginOutcome::InvalidCredentials => "/entrar?erro=credenciais",
+            LoginOutcome::Disabled => "/entrar?erro=desativada",
+        };
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
+
+/// how a login attempt ended, each caller tells the user in its own way
+pub(crate) enum LoginOutcome {
+    LoggedIn,
+    /// the password was right, the code of the second factor is asked next
+    TwoFactorRequired,
+    /// too many failures from the account or the IP
+    Locked,
+    /// the challenge wasn't answered
+    ChallengeFailed,
+    InvalidCredentials,
+    Disabled,
+}
+
+/// what the user typed to log in
+pub(crate) struct LoginAttempt<'a> {
+    // nickname or email
+    pub login: &'a str,
+    pub password: &'a str,
+    pub remember: bool,
+    pub challenge: &'a ChallengeResponse,
+}
+
+/// checks the credentials and logs the user in, shared by the login form and the api
+pub(crate) async fn attempt_login(
+    req: &HttpRequest,
+    session: &Session,
+    pool: &DbPool,
+    limiter: &RateLimiter,
+    challenge: &dyn Challenge,
+    attempt: LoginAttempt<'_>,
+) -> actix_web::Result<LoginOutcome> {
+    // failed logins are counted both for the account and for the IP
+    let remote_ip = client_ip(req);
+    let ip_key = format!(
+        "login:ip:{}",
+        remote_ip.as_deref().unwrap_or("desconhecido")
+    );
+    if limiter.check_lockout(&ip_key).is_err() {
+        return Ok(LoginOutcome::Locked);
+    }
+
+    // check the challenge before touching the database
+    if !challenge
+        .verify(session, attempt.challenge, remote_ip.as_deref())
+        .await
+    {
+        return Ok(LoginOutcome::ChallengeFailed);
+    }
+
     // get a connection from the pool
     let Ok(mut conn) = pool.get() else {
         return Err(ErrorInternalServerError(
@@ -41,72 +138,204 @@ async fn login_user(
         ));
     };
 
-    // get user's hashed password
-    let Ok(creds) = users::table
-        .filter(users::nickname.eq(&details.nickname))
-        .select((users::id, users::hashed_password))
-        .first::<(Uuid, String)>(&mut conn)
-        .optional()
-    else {
+    // get user's hashed password, nicknames can't have an @ so anything with one is an email
+    let login = attempt.login.trim().to_lowercase();
+    let creds = if login.contains('@') {
+        users::table
+            .filter(lower(users::email).eq(&login))
+            .select((users::id, users::hashed_password, users::status))
+            .first::<(Uuid, String, AccountStatus)>(&mut conn)
+            .optional()
+    } else {
+        users::table
+            .filter(lower(users::nickname).eq(&login))
+            .select((users::id, users::hashed_password, users::status))
+            .first::<(Uuid, String, AccountStatus)>(&mut conn)
+            .optional()
+    };
+    let Ok(creds) = creds else {
         return Err(ErrorInternalServerError(
             "Não foi possível verificar suas credenciais",
         ));
     };
 
-    // check if user exists
-    let Some((user_id, hashed_pass)) = creds else {
-        // redirect, showing an error message
-        return Ok(HttpResponse::Found()
-            .append_header(("Location", "/entrar?erro=credenciais"))
-            .finish());
+    // the account is locked by id when it exists, so nickname and email share the counter
+    let account_key = match creds {
+        Some((user_id, _, _)) => format!("login:user:{}", user_id),
+        None => format!("login:user:{}", login),
     };
+    if limiter.check_lockout(&account_key).is_err() {
+        return Ok(LoginOutcome::Locked);
+    }
 
-    // verify password
+    // verify password. when the user doesn't exist the dummy hash is verified instead, so
+    // the response takes as long as it would for a wrong password
+    let hashed_pass = match creds {
+        Some((_, ref hashed_pass, _)) => hashed_pass.as_str(),
+        None => dummy_password_hash(),
+    };
     let argon2 = Argon2::default();
-    let Ok(parsed_password_hash) = PasswordHash::new(&hashed_pass) else {
+    let Ok(parsed_password_hash) = PasswordHash::new(hashed_pass) else {
         return Err(ErrorInternalServerError(
             "Não foi possível verificar suas credenciais",
         ));
     };
-    let Ok(_) = argon2.verify_password(details.password.as_bytes(), &parsed_password_hash) else {
-        return Ok(HttpResponse::Found()
-            .append_header(("Location", "/entrar?erro=credenciais"))
-            .finish());
+    let password_matches = argon2
+        .verify_password(attempt.password.as_bytes(), &parsed_password_hash)
+        .is_ok();
+
+    let existing_user_id = creds.as_ref().map(|(user_id, _, _)| *user_id);
+    let (Some((user_id, _, status)), true) = (creds, password_matches) else {
+        limiter.record_failure(&account_key);
+        limiter.record_failure(&ip_key);
+
+        // only attempts against existing accounts are worth keeping
+        if let Some(user_id) = existing_user_id {
+            if let Err(e) = audit::record(
+                &mut conn,
+                req,
+                AuditEvent::LoginFailed,
+                Some(user_id),
+                None,
+                "",
+            ) {
+                log::error!("Não foi possível registrar a auditoria: {:?}", e);
+            }
+        }
+
+        return Ok(LoginOutcome::InvalidCredentials);
     };
 
+    // only told after the password matched, so the status of an account can't be probed.
+    // pending accounts can log in, they are sent to the confirmation page
+    if let AccountStatus::DISABLED = status {
+        return Ok(LoginOutcome::Disabled);
+    }
+
+    // with 2FA enabled the password alone isn't enough, the code is asked next
+    let Ok(two_factor_enabled) = two_factor::is_enabled(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível verificar suas credenciais",
+        ));
+    };
+    password_accepted(limiter, &account_key, two_factor_enabled);
+    if two_factor_enabled {
+        two_factor::start_pending_login(session, user_id, attempt.remember);
+        return Ok(LoginOutcome::TwoFactorRequired);
+    }
+
     // log this user in
+    complete_login(req, &mut conn, session, user_id, attempt.remember)?;
+    Ok(LoginOutcome::LoggedIn)
+}
+
+// the failures of the account are only forgotten once the login completes. with 2FA that's
+// after the code, or entering the password again would reset the count of wrong codes
+fn password_accepted(limiter: &RateLimiter, account_key: &str, two_factor_enabled: bool) {
+    if !two_factor_enabled {
+        limiter.record_success(account_key);
+    }
+}
+
+// attaches the identity to the session and records the login, once every check has passed
+pub(crate) fn complete_login(
+    req: &HttpRequest,
+    conn: &mut DbConn,
+    session: &Session,
+    user_id: Uuid,
+    remember: bool,
+) -> actix_web::Result<()> {
     let Ok(_) = Identity::login(&req.extensions(), user_id.simple().to_string()) else {
         return Err(ErrorInternalServerError(
             "Não foi possível criar uma sessão para você",
         ));
     };
+    let Ok(_) = session::start_login(conn, req, session, user_id, remember) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível criar uma sessão para você",
+        ));
+    };
+    if let Err(e) = audit::record(conn, req, AuditEvent::Login, Some(user_id), None, "") {
+        log::error!("Não foi possível registrar a auditoria: {:?}", e);
+    }
+    Ok(())
+}
+
+// hash verified when the login doesn't match any account, made with the same parameters as
+// the real ones so both cases take the same time
+fn dummy_password_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        let salt = SaltString::generate(OsRng);
+        Argon2::default()
+            .hash_password(Uuid::new_v4().as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .expect("Failed to hash the dummy password")
+    })
+}
+
+// checks the password typed by the user against the stored hash, used to re-authenticate
+// before sensitive account changes
+pub(crate) fn verify_current_password(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    password: &str,
+) -> actix_web::Result<bool> {
+    let Ok(hashed_pass) = users::table
+        .filter(users::id.eq(user_id))
+        .select(users::hashed_password)
+        .first::<String>(conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível verificar suas credenciais",
+        ));
+    };
 
-    // success, redirect to account page
-    return Ok(HttpResponse::Found()
-        .append_header(("Location", "/minha-conta"))
-        .finish());
+    let Ok(parsed_password_hash) = PasswordHash::new(&hashed_pass) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível verificar suas credenciais",
+        ));
+    };
+
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_password_hash)
+        .is_ok())
 }
 
 #[derive(Deserialize)]
-struct ErrorQuery {
-    erro: Option<String>,
+pub(crate) struct ErrorQuery {
+    pub erro: Option<String>,
 }
 
 #[get("/entrar")]
-async fn login_page(local_user: LocalUser, error: web::Query<ErrorQuery>) -> HttpResponse {
+async fn login_page(
+    local_user: LocalUser,
+    session: Session,
+    csrf: CsrfToken,
+    challenge: web::Data<dyn Challenge>,
+    error: web::Query<ErrorQuery>,
+) -> HttpResponse {
     let markup = render_base(
         html! {
             form .vstack.gap-3 method="post" action="/entrar" {
+                (csrf)
                 h1 { "Entrar" }
                 @if let Some(ref error) = error.erro {
                     div .alert.alert-danger role="alert" { (match error.as_str() {
                         "credenciais" => "Credenciais inválidas.",
+                        "bloqueado" => "Muitas tentativas de login. Aguarde alguns minutos e tente novamente.",
+                        "captcha" => "Não foi possível verificar que você não é um robô. Tente novamente.",
+                        "desativada" => "Esta conta foi desativada. Se acha que foi um engano, entre em contato com suporte@coisandocoisas.cc.",
                         _ => "Erro desconhecido."
                     }) }
                 }
-                input .form-control type="text" name="nickname" placeholder="Apelido";
+                input .form-control type="text" name="login" placeholder="Apelido ou email" autocomplete="username";
                 input .form-control type="password" name="password" placeholder="Senha";
-                // TODO: add a captcha here
+                .form-check {
+                    input .form-check-input type="checkbox" id="remember" name="remember";
+                    label .form-check-label for="remember" { "Lembrar de mim" }
+                }
+                (challenge.render(&session))
                 button .btn.btn-primary type="submit" { "Enviar" }
             }
         },
@@ -120,16 +349,18 @@ struct UserRegisterForm {
     pub nickname: String,
     pub email: String,
     pub password: String,
+    #[serde(flatten)]
+    pub challenge: ChallengeResponse,
 }
 
 // error enum for user registration
 #[derive(Debug)]
 enum UserRegisterError {
     InternalServerError,
+    NicknameInvalid(NicknameError),
     NicknameInUse,
     EmailInUse,
-    PasswordTooShort,
-    PasswordWeak,
+    PasswordInvalid(PasswordError),
     UnableToHashPassword,
     UnableToCreateUser,
     UnableToCreateConfirmationCode,
@@ -156,38 +387,71 @@ async fn send_confirmation_email(
     email: &str,
     code: Uuid,
 ) -> Result<(), ()> {
-    let client = reqwest::Client::new();
-    let mut data = HashMap::new();
     let code = code.simple().to_string();
-    data.insert(
-        "from",
-        "Coisando Coisas <naoresponder@mg.coisandocoisas.cc>",
-    );
-    data.insert("to", &email);
-    data.insert("template", "verificação de conta");
-    data.insert("subject", "Confirme sua conta no Coisando Coisas");
-    data.insert("v:code", code.as_str());
-    data.insert("v:nickname", nickname);
-
-    // todo: add message template name and variables
-
-    let Ok(_response) = client
-        .post("https://api.mailgun.net/v3/mg.coisandocoisas.cc/messages")
-        .basic_auth("api", Some(mg_api_key))
-        .form(&data)
-        .send()
-        .await
-    else {
-        return Err(());
-    };
-    Ok(())
+    send_template_email(
+        mg_api_key,
+        email,
+        "verificação de conta",
+        "Confirme sua conta no Coisando Coisas",
+        &[("code", code.as_str()), ("nickname", nickname)],
+    )
+    .await
+}
+
+// function to send the verification link to the user's new email
+async fn send_email_change_confirmation(
+    mg_api_key: &str,
+    nickname: &str,
+    new_email: &str,
+    code: Uuid,
+) -> Result<(), ()> {
+    let code = code.simple().to_string();
+    send_template_email(
+        mg_api_key,
+        new_email,
+        "alteração de email",
+        "Confirme seu novo email no Coisando Coisas",
+        &[("code", code.as_str()), ("nickname", nickname)],
+    )
+    .await
+}
+
+// function to let the old address know that the account's email was changed
+async fn send_email_changed_notice(
+    mg_api_key: &str,
+    nickname: &str,
+    old_email: &str,
+    new_email: &str,
+) -> Result<(), ()> {
+    send_template_email(
+        mg_api_key,
+        old_email,
+        "aviso de alteração de email",
+        "O email da sua conta no Coisando Coisas foi alterado",
+        &[("nickname", nickname), ("new_email", new_email)],
+    )
+    .await
 }
 
 #[post("/registrar")]
 async fn register_new_user(
+    req: HttpRequest,
+    session: Session,
     pool: web::Data<DbPool>,
+    challenge: web::Data<dyn Challenge>,
     details: web::Form<UserRegisterForm>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    // check the challenge before touching the database
+    let remote_ip = client_ip(&req);
+    if !challenge
+        .verify(&session, &details.challenge, remote_ip.as_deref())
+        .await
+    {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/registrar?erro=captcha"))
+            .finish());
+    }
+
     // get a connection from the pool
     let Ok(mut conn) = pool.get() else {
         return Err(ErrorInternalServerError(
@@ -196,15 +460,14 @@ async fn register_new_user(
     };
 
     let transaction_result = conn.transaction::<Uuid, UserRegisterError, _>(|conn| {
-        // TODO: check if nickname is too short
-
-        // TODO: check if nickname has only alphanumeric characters and underscores
-
-        // TODO: do the same checks for changing nickname
+        // check nickname length, characters and reserved names
+        if let Err(err) = policy::validate_nickname(&details.nickname) {
+            return Err(UserRegisterError::NicknameInvalid(err));
+        }
 
         // check if nickname is already taken
         let Ok(nickname_in_use) = users::table
-            .filter(users::nickname.eq(&details.nickname))
+            .filter(lower(users::nickname).eq(details.nickname.to_lowercase()))
             .select(users::nickname)
             .first::<String>(conn)
             .optional()
@@ -220,7 +483,7 @@ async fn register_new_user(
 
         // check if email is already taken
         let Ok(email_in_use) = users::table
-            .filter(users::email.eq(&details.email))
+            .filter(lower(users::email).eq(details.email.to_lowercase()))
             .select(users::email)
             .first::<String>(conn)
             .optional()
@@ -233,22 +496,8 @@ async fn register_new_user(
         }
 
         // check password strength
-        // TODO: also do the same checks for changing password
-        let requirements = [
-            "abcdefghijklmnopqrstuvwxyz",
-            "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
-            "0123456789",
-            "!@#$%^&*()-_=+[]{}|;:,.<>/?",
-        ];
-        if details.password.len() < 8 {
-            // redirect, showing an error message
-            return Err(UserRegisterError::PasswordTooShort);
-        } else if requirements
-            .iter()
-            .any(|req| !req.chars().any(|c| details.password.contains(c)))
-        {
-            // redirect, showing an error message
-            return Err(UserRegisterError::PasswordWeak);
+        if let Err(err) = policy::validate_password(&details.password) {
+            return Err(UserRegisterError::PasswordInvalid(err));
         }
 
         // hash this bitch!
@@ -315,6 +564,11 @@ async fn register_new_user(
                 "Não foi possível criar sua conta devido a um erro interno.",
             ));
         }
+        Err(UserRegisterError::NicknameInvalid(err)) => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", format!("/registrar?erro={}", err.code())))
+                .finish());
+        }
         Err(UserRegisterError::NicknameInUse) => {
             return Ok(HttpResponse::Found()
                 .append_header(("Location", "/registrar?erro=apelido"))
@@ -325,14 +579,9 @@ async fn register_new_user(
                 .append_header(("Location", "/registrar?erro=email"))
                 .finish());
         }
-        Err(UserRegisterError::PasswordTooShort) => {
+        Err(UserRegisterError::PasswordInvalid(err)) => {
             return Ok(HttpResponse::Found()
-                .append_header(("Location", "/registrar?erro=senha-curta"))
-                .finish());
-        }
-        Err(UserRegisterError::PasswordWeak) => {
-            return Ok(HttpResponse::Found()
-                .append_header(("Location", "/registrar?erro=senha-fraca"))
+                .append_header(("Location", format!("/registrar?erro={}", err.code())))
                 .finish());
         }
         Err(UserRegisterError::UnableToHashPassword) => {
@@ -359,7 +608,13 @@ async fn register_new_user(
 }
 
 #[get("/registrar")]
-async fn register_page(local_user: LocalUser, error: web::Query<ErrorQuery>) -> HttpResponse {
+async fn register_page(
+    local_user: LocalUser,
+    session: Session,
+    csrf: CsrfToken,
+    challenge: web::Data<dyn Challenge>,
+    error: web::Query<ErrorQuery>,
+) -> HttpResponse {
     if let LocalUser::Authenticated { .. } = local_user {
         return HttpResponse::Found()
             .append_header(("Location", "/minha-conta"))
@@ -369,20 +624,29 @@ async fn register_page(local_user: LocalUser, error: web::Query<ErrorQuery>) ->
     let markup = render_base(
         html! {
             form .vstack.gap-3 method="post" action="/registrar" {
+                (csrf)
                 h1 { "Criar conta" }
                 @if let Some(ref error) = error.erro {
                     div .alert.alert-danger role="alert" { (match error.as_str() {
                         "apelido" => "O apelido já está em uso.",
+                        "apelido-curto" => "O apelido é muito curto.",
+                        "apelido-longo" => "O apelido é muito longo.",
+                        "apelido-invalido" => "O apelido só pode ter letras sem acento, números e sublinhados (_).",
+                        "apelido-reservado" => "Este apelido não está disponível.",
                         "email" => "O email já está em uso.",
                         "senha-curta" => "A senha é muito curta.",
+                        "senha-longa" => "A senha é muito longa.",
                         "senha-fraca" => "A senha é muito fraca.",
+                        "senha-comum" => "Esta senha é muito comum, escolha outra.",
+                        "captcha" => "Não foi possível verificar que você não é um robô. Tente novamente.",
                         _ => "Erro desconhecido."
                     }) }
                 }
                 input .form-control type="text" name="nickname" placeholder="Apelido";
+                small { (format!("Seu apelido precisa ter entre {} e {} caracteres, usando apenas letras sem acento, números e sublinhados (_).", policy::NICKNAME_MIN_LENGTH, policy::NICKNAME_MAX_LENGTH)) }
                 input .form-control type="email" name="email" placeholder="Email";
                 input .form-control type="password" name="password" placeholder="Senha";
-                small { "Sua senha precisa ter pelo menos 8 caracteres, uma letra maiúscula e uma minúscula, um dígito e um dos seguintes símbolos: !@#$%^&*()-_=+[]{}|;:,.<>/?" }
+                small { (format!("Sua senha precisa ter pelo menos {} caracteres, uma letra maiúscula e uma minúscula, um dígito e um dos seguintes símbolos: {}", policy::PASSWORD_MIN_LENGTH, policy::PASSWORD_SYMBOLS)) }
                 .form-check {
                     input .form-check-input type="checkbox" id="terms" name="terms" required;
                     label .form-check-label for="terms" {
@@ -392,7 +656,7 @@ async fn register_page(local_user: LocalUser, error: web::Query<ErrorQuery>) ->
                         a href="/diretrizes-da-comunidade" { "diretrizes da comunidade" } "."
                     }
                 }
-                // TODO: add a captcha here
+                (challenge.render(&session))
                 button .btn.btn-primary type="submit" { "Enviar" }
             }
         },
@@ -433,6 +697,7 @@ impl From<diesel::result::Error> for UserVerificationError {
 #[get("/confirmar-conta")]
 async fn confirm_account(
     req: HttpRequest,
+    session: Session,
     pool: web::Data<DbPool>,
     details: web::Query<VerificationInfo>,
 ) -> Result<HttpResponse, actix_web::Error> {
@@ -444,7 +709,7 @@ async fn confirm_account(
     };
 
     // wrap everything in a transaction
-    let transaction_result = conn.transaction::<(), UserVerificationError, _>(|conn| {
+    let transaction_result = conn.transaction::<Uuid, UserVerificationError, _>(|conn| {
         // find the confirmation code in the database
         let Ok(user_id) = confirmation_codes::table
             .filter(confirmation_codes::code.eq(&details.code))
@@ -467,24 +732,36 @@ async fn confirm_account(
             return Err(UserVerificationError::UnableToConfirmAccount);
         };
 
-        // set the account status to confirmed
-        let Ok(_) = diesel::update(users::table.filter(users::id.eq(&user_id)))
-            .set(users::status.eq(AccountStatus::CONFIRMED))
-            .execute(conn)
-        else {
+        // set the account status to confirmed, a disabled account stays disabled
+        let Ok(confirmed) = diesel::update(
+            users::table
+                .filter(users::id.eq(&user_id))
+                .filter(users::status.eq(AccountStatus::PENDING)),
+        )
+        .set(users::status.eq(AccountStatus::CONFIRMED))
+        .execute(conn) else {
             return Err(UserVerificationError::UnableToConfirmAccount);
         };
+        if confirmed == 0 {
+            return Err(UserVerificationError::CodeInvalid);
+        }
 
         // lastly, log the user in
         let Ok(_) = Identity::login(&req.extensions(), user_id.simple().to_string()) else {
             return Err(UserVerificationError::UnableToCreateSession);
         };
 
-        Ok(())
+        Ok(user_id)
     });
 
     match transaction_result {
-        Ok(_) => (),
+        Ok(user_id) => {
+            if session::start_login(&mut conn, &req, &session, user_id, false).is_err() {
+                return Err(ErrorInternalServerError(
+                    "Não foi possível criar uma sessão para você",
+                ));
+            }
+        }
         Err(UserVerificationError::InternalServerError) => {
             return Err(ErrorInternalServerError(
                 "Não foi possível confirmar a sua conta devido a um erro interno.",
@@ -534,52 +811,49 @@ async fn confirmation_page(local_user: LocalUser) -> HttpResponse {
 
 #[get("/minha-conta")]
 async fn account_page(local_user: LocalUser) -> Result<HttpResponse, actix_web::Error> {
-    // TODO: add part where user can manage their posts
-
-    let (_user_id, nickname, avatar_seed) = match &local_user {
-        LocalUser::Authenticated {
-            id,
-            nickname,
-            avatar_seed,
-        } => (id, nickname, avatar_seed),
-        LocalUser::Anonymous => {
-            return Ok(HttpResponse::Found()
-                .append_header(("Location", "/entrar"))
-                .finish());
-        }
-        LocalUser::Pending => {
-            return Ok(HttpResponse::Found()
-                .append_header(("Location", "/confirmação"))
-                .finish());
-        }
+    // the account page is the user's own public profile, where their listings are managed
+    let location = match &local_user {
+        LocalUser::Authenticated { nickname, .. } => format!("/u/{}", nickname),
+        LocalUser::Anonymous => "/entrar".to_string(),
+        LocalUser::Pending => "/confirmação".to_string(),
+        LocalUser::Disabled => "/conta-desativada".to_string(),
     };
 
-    let created_at = "TODO: get user's creation date and other info";
-    let markup = render_base(
-        html! {
-            h1 { (format!("Perfil de {}", nickname)) }
-            img .rounded-circle src=(format!("https://api.dicebear.com/9.x/dylan/svg?seed={}&radius=50&backgroundColor=29e051,619eff,ffa6e6,b6e3f4,c0aede,d1d4f9,ffd5dc,ffdfbf&hair=buns,flatTop,fluffy,longCurls,parting,plain,roundBob,shaggy,shortCurls,spiky,wavy,bangs&mood=happy,hopeful,superHappy", avatar_seed)) width="128" height="128" alt="avatar";
-            p #createdAt { (format!("Conta criada em {}", created_at)) }
-        },
-        local_user,
-    );
-
-    Ok(HttpResponse::Ok().body(markup.into_string()))
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
 }
 
 #[get("/sair")]
-async fn logout_user(id: Option<Identity>) -> HttpResponse {
-    if let Some(id) = id {
-        id.logout();
-    }
+async fn logout_user(
+    id: Option<Identity>,
+    session: Session,
+    pool: web::Data<DbPool>,
+) -> HttpResponse {
+    logout(id, &session, &pool);
 
     HttpResponse::Found()
         .append_header(("Location", "/"))
         .finish()
 }
 
+/// ends the current login, shared by the logout link and the api
+pub(crate) fn logout(id: Option<Identity>, session: &Session, pool: &DbPool) {
+    // forget this login so it no longer shows up in the sessions page
+    if let Ok(mut conn) = pool.get() {
+        if let Err(e) = session::end_login(&mut conn, session) {
+            log::error!("Não foi possível encerrar o login: {:?}", e);
+        }
+    }
+
+    if let Some(id) = id {
+        id.logout();
+    }
+}
+
 #[post("/settings/avatar")]
 async fn generate_avatar(
+    req: HttpRequest,
     local_user: LocalUser,
     pool: web::Data<DbPool>,
 ) -> actix_web::Result<HttpResponse> {
@@ -607,6 +881,16 @@ async fn generate_avatar(
                 "Não foi possível gerar um novo avatar",
             ));
         };
+        if let Err(e) = audit::record(
+            &mut conn,
+            &req,
+            AuditEvent::AvatarRegeneration,
+            Some(id),
+            None,
+            "",
+        ) {
+            log::error!("Não foi possível registrar a auditoria: {:?}", e);
+        }
     };
 
     Ok(HttpResponse::Found()
@@ -621,12 +905,18 @@ struct NewNicknameForm {
 
 #[post("/settings/nickname")]
 async fn change_nickname(
+    req: HttpRequest,
     local_user: LocalUser,
     pool: web::Data<DbPool>,
     new_nickname: web::Form<NewNicknameForm>,
 ) -> actix_web::Result<HttpResponse> {
     let new_nickname = new_nickname.into_inner();
-    if let LocalUser::Authenticated { id, .. } = local_user {
+    if let LocalUser::Authenticated {
+        id,
+        nickname: old_nickname,
+        ..
+    } = local_user
+    {
         // get a connection from the pool
         let Ok(mut conn) = pool.get() else {
             return Err(ErrorInternalServerError(
@@ -634,9 +924,17 @@ async fn change_nickname(
             ));
         };
 
+        // check nickname length, characters and reserved names
+        if let Err(err) = policy::validate_nickname(&new_nickname.nickname) {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", format!("/configurações?erro={}", err.code())))
+                .finish());
+        }
+
         // check if the new nickname is already in use
         let Ok(nickname_in_use) = users::table
-            .filter(users::nickname.eq(&new_nickname.nickname))
+            .filter(lower(users::nickname).eq(new_nickname.nickname.to_lowercase()))
+            .filter(users::id.ne(id))
             .select(users::nickname)
             .first::<String>(&mut conn)
             .optional()
@@ -660,6 +958,16 @@ async fn change_nickname(
                 "Não foi possível alterar o seu apelido",
             ));
         };
+        if let Err(e) = audit::record(
+            &mut conn,
+            &req,
+            AuditEvent::NicknameChange,
+            Some(id),
+            None,
+            &format!("{} → {}", old_nickname, new_nickname.nickname),
+        ) {
+            log::error!("Não foi possível registrar a auditoria: {:?}", e);
+        }
     } else {
         return Ok(HttpResponse::Found()
             .append_header(("Location", "/entrar"))
@@ -674,11 +982,13 @@ async fn change_nickname(
 
 #[derive(Deserialize)]
 struct NewPasswordForm {
+    current_password: String,
     password: String,
 }
 
 #[post("/settings/password")]
 async fn change_password(
+    req: HttpRequest,
     local_user: LocalUser,
     pool: web::Data<DbPool>,
     new_password: web::Form<NewPasswordForm>,
@@ -692,26 +1002,18 @@ async fn change_password(
             ));
         };
 
-        // check password strength
-        // TODO: move this to a function so the register page can use it too
-        let requirements = [
-            "abcdefghijklmnopqrstuvwxyz",
-            "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
-            "0123456789",
-            "!@#$%^&*()-_=+[]{}|;:,.<>/?",
-        ];
-        if new_password.password.len() < 8 {
-            // redirect, showing an error message
+        // make sure it's really the user asking for the change
+        if !verify_current_password(&mut conn, id, &new_password.current_password)? {
             return Ok(HttpResponse::Found()
-                .append_header(("Location", "/configurações?erro=senha-curta"))
+                .append_header(("Location", "/configurações?erro=senha-atual-incorreta"))
                 .finish());
-        } else if requirements
-            .iter()
-            .any(|req| !req.chars().any(|c| new_password.password.contains(c)))
-        {
+        }
+
+        // check password strength
+        if let Err(err) = policy::validate_password(&new_password.password) {
             // redirect, showing an error message
             return Ok(HttpResponse::Found()
-                .append_header(("Location", "/configurações?erro=senha-fraca"))
+                .append_header(("Location", format!("/configurações?erro={}", err.code())))
                 .finish());
         }
 
@@ -733,6 +1035,16 @@ async fn change_password(
                 "Não foi possível alterar a sua senha",
             ));
         };
+        if let Err(e) = audit::record(
+            &mut conn,
+            &req,
+            AuditEvent::PasswordChange,
+            Some(id),
+            None,
+            "",
+        ) {
+            log::error!("Não foi possível registrar a auditoria: {:?}", e);
+        }
     } else {
         return Ok(HttpResponse::Found()
             .append_header(("Location", "/entrar"))
@@ -745,19 +1057,298 @@ async fn change_password(
         .finish())
 }
 
-#[get("/configurações")]
-async fn settings_page(local_user: LocalUser, error: web::Query<ErrorQuery>) -> HttpResponse {
-    if let LocalUser::Anonymous = local_user {
-        return HttpResponse::Found()
+#[derive(Deserialize)]
+struct NewEmailForm {
+    email: String,
+    current_password: String,
+}
+
+#[post("/settings/email")]
+async fn change_email(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    details: web::Form<NewEmailForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated {
+        id: user_id,
+        nickname,
+        ..
+    } = local_user
+    else {
+        return Ok(HttpResponse::Found()
             .append_header(("Location", "/entrar"))
-            .finish();
-    } else if let LocalUser::Pending = local_user {
-        // TODO: do this also for the index and reservation pages
-        return HttpResponse::Found()
-            .append_header(("Location", "/confirmação"))
-            .finish();
+            .finish());
+    };
+    let new_email = details.email.trim();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // make sure it's really the user asking for the change
+    if !verify_current_password(&mut conn, user_id, &details.current_password)? {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/configurações?erro=senha-atual-incorreta"))
+            .finish());
     }
 
+    // TODO: check email domain
+
+    // check if the new email is already in use, including by the user
+    let Ok(email_owner) = users::table
+        .filter(lower(users::email).eq(new_email.to_lowercase()))
+        .select(users::id)
+        .first::<Uuid>(&mut conn)
+        .optional()
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível verificar o novo email",
+        ));
+    };
+    match email_owner {
+        Some(owner_id) if owner_id == user_id => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/configurações?erro=email-igual"))
+                .finish());
+        }
+        Some(_) => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/configurações?erro=email"))
+                .finish());
+        }
+        None => (),
+    }
+
+    // replace any previous request, only the latest link is valid
+    let code = Uuid::new_v4();
+    let Ok(_) = diesel::insert_into(email_changes::table)
+        .values((
+            email_changes::user_id.eq(user_id),
+            email_changes::new_email.eq(new_email),
+            email_changes::code.eq(code),
+        ))
+        .on_conflict(email_changes::user_id)
+        .do_update()
+        .set((
+            email_changes::new_email.eq(new_email),
+            email_changes::code.eq(code),
+            email_changes::created_at.eq(Utc::now()),
+        ))
+        .execute(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível salvar o novo email",
+        ));
+    };
+
+    // send the link to the new address, the old one stays active until then
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    if send_email_change_confirmation(&mg_api_key, &nickname, new_email, code)
+        .await
+        .is_err()
+    {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/configurações?erro=email-nao-enviado"))
+            .finish());
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/configurações"))
+        .finish())
+}
+
+// how long the link sent to the new email stays valid
+const EMAIL_CHANGE_EXPIRATION_HOURS: i64 = 24;
+
+enum EmailChangeError {
+    InternalServerError,
+    CodeInvalid,
+    CodeExpired,
+    EmailInUse,
+}
+
+// implement this From<> so we can rollback the transaction and return a meaningful error for the user
+impl From<EmailChangeError> for diesel::result::Error {
+    fn from(_: EmailChangeError) -> Self {
+        diesel::result::Error::RollbackTransaction
+    }
+}
+
+impl From<diesel::result::Error> for EmailChangeError {
+    fn from(_: diesel::result::Error) -> Self {
+        EmailChangeError::InternalServerError
+    }
+}
+
+/// GET /confirmar-email?code=...
+/// swaps the user's email for the one that received the code and warns the old address
+#[get("/confirmar-email")]
+async fn confirm_email_change(
+    pool: web::Data<DbPool>,
+    details: web::Query<VerificationInfo>,
+) -> actix_web::Result<HttpResponse> {
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let transaction_result =
+        conn.transaction::<(String, String, String), EmailChangeError, _>(|conn| {
+            // find the pending change
+            let Ok(change) = email_changes::table
+                .filter(email_changes::code.eq(&details.code))
+                .select((
+                    email_changes::user_id,
+                    email_changes::new_email,
+                    email_changes::created_at,
+                ))
+                .first::<(Uuid, String, DateTime<Utc>)>(conn)
+                .optional()
+            else {
+                return Err(EmailChangeError::InternalServerError);
+            };
+            let Some((user_id, new_email, created_at)) = change else {
+                return Err(EmailChangeError::CodeInvalid);
+            };
+
+            if created_at + Duration::hours(EMAIL_CHANGE_EXPIRATION_HOURS) < Utc::now() {
+                return Err(EmailChangeError::CodeExpired);
+            }
+
+            // we used the code, so delete it
+            diesel::delete(email_changes::table.filter(email_changes::user_id.eq(user_id)))
+                .execute(conn)?;
+
+            // someone may have taken the address since the request
+            let email_in_use = users::table
+                .filter(lower(users::email).eq(new_email.to_lowercase()))
+                .select(users::id)
+                .first::<Uuid>(conn)
+                .optional()?;
+            if email_in_use.is_some() {
+                return Err(EmailChangeError::EmailInUse);
+            }
+
+            let (nickname, old_email) = users::table
+                .filter(users::id.eq(user_id))
+                .select((users::nickname, users::email))
+                .first::<(String, String)>(conn)?;
+
+            diesel::update(users::table.filter(users::id.eq(user_id)))
+                .set(users::email.eq(&new_email))
+                .execute(conn)?;
+
+            Ok((nickname, old_email, new_email))
+        });
+
+    let (nickname, old_email, new_email) = match transaction_result {
+        Ok(result) => result,
+        Err(EmailChangeError::InternalServerError) => {
+            return Err(ErrorInternalServerError(
+                "Não foi possível alterar o seu email devido a um erro interno.",
+            ));
+        }
+        Err(EmailChangeError::CodeInvalid) => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/configurações?erro=codigo-invalido"))
+                .finish());
+        }
+        Err(EmailChangeError::CodeExpired) => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/configurações?erro=codigo-expirado"))
+                .finish());
+        }
+        Err(EmailChangeError::EmailInUse) => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/configurações?erro=email"))
+                .finish());
+        }
+    };
+
+    // warn the old address, in case the change wasn't made by the owner
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    if send_email_changed_notice(&mg_api_key, &nickname, &old_email, &new_email)
+        .await
+        .is_err()
+    {
+        log::error!(
+            "Não foi possível avisar {} sobre a alteração de email",
+            old_email
+        );
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/configurações"))
+        .finish())
+}
+
+#[get("/configurações")]
+async fn settings_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = match &local_user {
+        LocalUser::Authenticated { id, .. } => *id,
+        LocalUser::Anonymous => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/entrar"))
+                .finish());
+        }
+        LocalUser::Pending => {
+            // TODO: do this also for the index and reservation pages
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/confirmação"))
+                .finish());
+        }
+        LocalUser::Disabled => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/conta-desativada"))
+                .finish());
+        }
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // current email and the one waiting for confirmation, if any
+    let Ok(email) = users::table
+        .filter(users::id.eq(user_id))
+        .select(users::email)
+        .first::<String>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter suas informações",
+        ));
+    };
+    let Ok(pending_email) = email_changes::table
+        .filter(email_changes::user_id.eq(user_id))
+        .select(email_changes::new_email)
+        .first::<String>(&mut conn)
+        .optional()
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter suas informações",
+        ));
+    };
+    let Ok(notification_preferences) = notification_center::preferences(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter suas informações",
+        ));
+    };
+
     let markup = render_base(
         html! {
             h1 { "Configurações" }
@@ -766,14 +1357,27 @@ async fn settings_page(local_user: LocalUser, error: web::Query<ErrorQuery>) ->
             @if let Some(ref error) = error.erro {
                 div .alert.alert-danger role="alert" { (match error.as_str() {
                     "apelido-em-uso" => "O apelido já está em uso.",
+                    "apelido-curto" => "O apelido é muito curto.",
+                    "apelido-longo" => "O apelido é muito longo.",
+                    "apelido-invalido" => "O apelido só pode ter letras sem acento, números e sublinhados (_).",
+                    "apelido-reservado" => "Este apelido não está disponível.",
                     "senha-curta" => "A senha é muito curta.",
+                    "senha-longa" => "A senha é muito longa.",
                     "senha-fraca" => "A senha é muito fraca.",
+                    "senha-comum" => "Esta senha é muito comum, escolha outra.",
+                    "senha-atual-incorreta" => "A senha atual está incorreta.",
+                    "email" => "O email já está em uso.",
+                    "email-igual" => "Este já é o seu email.",
+                    "email-nao-enviado" => "Não foi possível enviar o email de confirmação.",
+                    "codigo-invalido" => "O link de confirmação é inválido.",
+                    "codigo-expirado" => "O link de confirmação expirou, peça a alteração novamente.",
                     _ => "Erro desconhecido."
                 }) }
             }
 
             // button to generate a new avatar
             form .vstack.gap-3 method="post" action="/settings/avatar" {
+                (csrf)
                 h2 { "Gerar novo avatar" }
                 // preview of the new avatar
                 @match &local_user {
@@ -787,6 +1391,7 @@ async fn settings_page(local_user: LocalUser, error: web::Query<ErrorQuery>) ->
 
             // change nickname
             form .vstack.gap-3 method="post" action="/settings/nickname" {
+                (csrf)
                 h2 { "Alterar apelido" }
 
                 @match &local_user {
@@ -800,18 +1405,99 @@ async fn settings_page(local_user: LocalUser, error: web::Query<ErrorQuery>) ->
                 button .btn.btn-primary type="submit" { "Enviar" }
             }
 
+            // change email
+            form .vstack.gap-3 method="post" action="/settings/email" {
+                (csrf)
+                h2 { "Alterar email" }
+                p { (format!("Seu email atual é {}", email)) }
+                @if let Some(ref pending_email) = pending_email {
+                    div .alert.alert-info role="alert" {
+                        (format!("Enviamos um link de confirmação para {}. Seu email atual continua valendo até lá.", pending_email))
+                    }
+                }
+                input .form-control type="email" name="email" placeholder="Novo email" required;
+                input .form-control type="password" name="current_password" placeholder="Senha atual" required;
+                button .btn.btn-primary type="submit" { "Enviar" }
+            }
+
             // change password
             form .vstack.gap-3 method="post" action="/settings/password" {
+                (csrf)
                 h2 { "Alterar senha" }
+                input .form-control type="password" name="current_password" placeholder="Senha atual" required;
                 input .form-control type="password" name="password" placeholder="Nova senha";
                 button .btn.btn-primary type="submit" { "Enviar" }
             }
 
-            // button to delete account
-            form .vstack.gap-3 method="post" action="/settings/delete" {
+            // kinds of notification to get
+            (render_notification_preferences(&csrf, &notification_preferences))
+
+            // second step at login
+            div .vstack.gap-3 {
+                h2 { "Verificação em duas etapas" }
+                p { "Peça um código do seu aplicativo autenticador, além da senha, sempre que entrar." }
+                a .btn.btn-secondary href="/configurações/2fa" { "Configurar verificação em duas etapas" }
+            }
+
+            // where the account is logged in
+            div .vstack.gap-3 {
+                h2 { "Sessões" }
+                p { "Veja onde sua conta está conectada e encerre as sessões que não reconhecer." }
+                a .btn.btn-secondary href="/configurações/sessões" { "Gerenciar sessões" }
+            }
+
+            // link to the account deletion confirmation page
+            div .vstack.gap-3 {
                 h2 { "Deletar conta" }
+                p { "Ao deletar sua conta, seus itens também serão removidos. Esta ação é irreversível." }
+                a .btn.btn-danger href="/configurações/deletar-conta" { "Deletar conta" }
+            }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[get("/configurações/deletar-conta")]
+async fn delete_account_page(
+    local_user: LocalUser,
+    csrf: CsrfToken,
+    error: web::Query<ErrorQuery>,
+) -> HttpResponse {
+    if let LocalUser::Anonymous = local_user {
+        return HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish();
+    } else if let LocalUser::Pending = local_user {
+        return HttpResponse::Found()
+            .append_header(("Location", "/confirmação"))
+            .finish();
+    }
+
+    let markup = render_base(
+        html! {
+            form .vstack.gap-3 method="post" action="/settings/delete" {
+                (csrf)
+                h1 { "Deletar conta" }
+                @if let Some(ref error) = error.erro {
+                    div .alert.alert-danger role="alert" { (match error.as_str() {
+                        "senha-atual-incorreta" => "A senha atual está incorreta.",
+                        "sem-confirmacao" => "Você precisa confirmar que deseja deletar sua conta.",
+                        _ => "Erro desconhecido."
+                    }) }
+                }
                 p { "Tem certeza que deseja deletar sua conta? Esta ação é irreversível." }
-                button .btn.btn-danger type="submit" { "Deletar conta" }
+                input .form-control type="password" name="current_password" placeholder="Senha atual" required;
+                .form-check {
+                    input .form-check-input type="checkbox" id="confirm" name="confirm" required;
+                    label .form-check-label for="confirm" {
+                        "Entendo que minha conta e meus itens serão apagados permanentemente."
+                    }
+                }
+                div .hstack.gap-2 {
+                    a .btn.btn-secondary href="/configurações" { "Cancelar" }
+                    button .btn.btn-danger type="submit" { "Deletar conta" }
+                }
             }
         },
         local_user,
@@ -819,38 +1505,213 @@ async fn settings_page(local_user: LocalUser, error: web::Query<ErrorQuery>) ->
     HttpResponse::Ok().body(markup.into_string())
 }
 
+// removes everything that references the user and then the user itself, returning the ids of
+// the attachments so their files can be removed from storage once the transaction commits
+fn delete_user_data(conn: &mut DbConn, user_id: Uuid) -> diesel::QueryResult<Vec<Uuid>> {
+    conn.transaction(|conn| {
+        let listing_ids = listings::table
+            .filter(listings::creator_id.eq(user_id))
+            .select(listings::id)
+            .load::<Uuid>(conn)?;
+
+        let attachment_ids =
+            diesel::delete(attachments::table.filter(attachments::listing_id.eq_any(&listing_ids)))
+                .returning(attachments::id)
+                .get_results::<Uuid>(conn)?;
+
+        diesel::delete(
+            reports::table.filter(
+                reports::listing_id
+                    .eq_any(&listing_ids)
+                    .or(reports::reporter_id.eq(user_id))
+                    .or(reports::reported_user_id.eq(user_id)),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(
+            favorites::table.filter(
+                favorites::listing_id
+                    .eq_any(&listing_ids)
+                    .or(favorites::user_id.eq(user_id)),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(
+            saved_search_matches::table.filter(
+                saved_search_matches::listing_id.eq_any(&listing_ids).or(
+                    saved_search_matches::search_id.eq_any(
+                        saved_searches::table
+                            .filter(saved_searches::user_id.eq(user_id))
+                            .select(saved_searches::id),
+                    ),
+                ),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(saved_searches::table.filter(saved_searches::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(notifications::table.filter(notifications::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(
+            notification_preferences::table.filter(notification_preferences::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(
+            reviews::table.filter(
+                reviews::reviewer_id
+                    .eq(user_id)
+                    .or(reviews::reviewed_id.eq(user_id)),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(
+            exchanges::table.filter(
+                exchanges::owner_id
+                    .eq(user_id)
+                    .or(exchanges::partner_id.eq(user_id)),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(listings::table.filter(listings::creator_id.eq(user_id))).execute(conn)?;
+        diesel::delete(confirmation_codes::table.filter(confirmation_codes::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(email_changes::table.filter(email_changes::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(user_sessions::table.filter(user_sessions::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(totp_credentials::table.filter(totp_credentials::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(users::table.filter(users::id.eq(user_id))).execute(conn)?;
+
+        Ok(attachment_ids)
+    })
+}
+
+#[derive(Deserialize)]
+struct DeleteAccountForm {
+    current_password: String,
+    confirm: Option<String>,
+}
+
 #[post("/settings/delete")]
 async fn delete_account(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
+    s3_client: web::Data<Client>,
     local_user: LocalUser,
     id: Option<Identity>,
+    details: web::Form<DeleteAccountForm>,
 ) -> actix_web::Result<HttpResponse> {
-    // log user out
-    if let Some(id) = id {
-        id.logout();
+    let LocalUser::Authenticated {
+        id: user_id,
+        nickname,
+        ..
+    } = local_user
+    else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // the checkbox is only sent when it's ticked
+    if details.confirm.is_none() {
+        return Ok(HttpResponse::Found()
+            .append_header((
+                "Location",
+                "/configurações/deletar-conta?erro=sem-confirmacao",
+            ))
+            .finish());
     }
 
-    if let LocalUser::Authenticated { id, .. } = local_user {
-        // get a connection from the pool
-        let Ok(mut conn) = pool.get() else {
-            return Err(ErrorInternalServerError(
-                "Não foi possível conectar ao banco de dados",
-            ));
-        };
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
 
-        // delete user's account
-        let Ok(_) = diesel::delete(users::table.filter(users::id.eq(id))).execute(&mut conn) else {
+    // make sure it's really the user asking for the deletion
+    if !verify_current_password(&mut conn, user_id, &details.current_password)? {
+        return Ok(HttpResponse::Found()
+            .append_header((
+                "Location",
+                "/configurações/deletar-conta?erro=senha-atual-incorreta",
+            ))
+            .finish());
+    }
+
+    // loaded before the listings are deleted, the emails are only sent once that's done
+    let notices = listings::table
+        .filter(listings::creator_id.eq(user_id))
+        .select(listings::id)
+        .load::<Uuid>(&mut conn)
+        .and_then(|listing_ids| saved_listings::notices(&mut conn, &listing_ids))
+        .unwrap_or_else(|e| {
+            log::error!("Não foi possível obter quem salvou os anúncios: {:?}", e);
+            Vec::new()
+        });
+
+    // delete user's account, listings and codes
+    let attachment_ids = match delete_user_data(&mut conn, user_id) {
+        Ok(attachment_ids) => attachment_ids,
+        Err(e) => {
+            log::error!("Não foi possível deletar a conta {}: {:?}", user_id, e);
             return Err(ErrorInternalServerError(
                 "Não foi possível deletar a sua conta",
             ));
-        };
+        }
     };
+    if let Err(e) = audit::record(
+        &mut conn,
+        &req,
+        AuditEvent::AccountDeletion,
+        Some(user_id),
+        None,
+        &nickname,
+    ) {
+        log::error!("Não foi possível registrar a auditoria: {:?}", e);
+    }
+
+    // log user out
+    if let Some(id) = id {
+        id.logout();
+    }
+
+    // the account is gone at this point, files that couldn't be removed are only logged
+    let failures = storage::delete_attachments(&s3_client, user_id, &attachment_ids).await;
+    if failures > 0 {
+        log::warn!(
+            "{} anexo(s) da conta {} não foram removidos do armazenamento",
+            failures,
+            user_id
+        );
+    }
+
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    saved_listings::send_notices(&mut conn, &mg_api_key, &notices, FavoriteEvent::Unavailable)
+        .await;
 
     Ok(HttpResponse::Found()
         .append_header(("Location", "/conta-deletada"))
         .finish())
 }
 
+#[get("/conta-desativada")]
+async fn disabled_account_page(local_user: LocalUser) -> HttpResponse {
+    let markup = render_base(
+        html! {
+            h1 { "Conta desativada" }
+            p { "Sua conta foi desativada e você foi desconectado." }
+            p { "Se acha que foi um engano, entre em contato com " strong { "suporte@coisandocoisas.cc" } }
+        },
+        local_user,
+    );
+    HttpResponse::Ok().body(markup.into_string())
+}
+
 #[get("/conta-deletada")]
 async fn deletion_confirmation_page(local_user: LocalUser) -> HttpResponse {
     if let LocalUser::Authenticated { .. } = local_user {
@@ -881,7 +1742,54 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         .service(generate_avatar)
         .service(change_nickname)
         .service(change_password)
+        .service(change_email)
+        .service(confirm_email_change)
         .service(settings_page)
+        .service(delete_account_page)
+        .service(disabled_account_page)
         .service(delete_account)
         .service(deletion_confirmation_page);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use coisando_coisas::rate_limit::RateLimitConfig;
+
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            register_per_hour: 5,
+            email_per_hour: 5,
+            submit_per_hour: 20,
+            login_max_failures: 2,
+            login_lockout: Duration::from_secs(30),
+            login_max_lockout: Duration::from_secs(3600),
+        })
+    }
+
+    #[test]
+    fn password_again_does_not_reset_wrong_codes() {
+        let limiter = limiter();
+        let account_key = "login:user:a";
+
+        // password, then a wrong code, as `verify_pending_login` records it
+        password_accepted(&limiter, account_key, true);
+        limiter.record_failure(account_key);
+        password_accepted(&limiter, account_key, true);
+        limiter.record_failure(account_key);
+        assert!(limiter.check_lockout(account_key).is_err());
+    }
+
+    #[test]
+    fn completed_login_resets_failures() {
+        let limiter = limiter();
+        let account_key = "login:user:a";
+        limiter.record_failure(account_key);
+        password_accepted(&limiter, account_key, false);
+        limiter.record_failure(account_key);
+        assert!(limiter.check_lockout(account_key).is_ok());
+    }
+}
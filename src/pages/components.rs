@@ -1,7 +1,26 @@
-use coisando_coisas::LocalUser;
-use maud::html;
+use coisando_coisas::{LocalUser, Role};
+use maud::{html, PreEscaped};
+use uuid::Uuid;
 
-pub fn render_navbar() -> maud::Markup {
+use super::events;
+
+/// the generated avatar for a seed, the same one shown everywhere the user appears
+pub fn avatar_url(avatar_seed: Uuid) -> String {
+    format!(
+        "https://api.dicebear.com/9.x/dylan/svg?seed={}&radius=50&backgroundColor=29e051,619eff,ffa6e6,b6e3f4,c0aede,d1d4f9,ffd5dc,ffdfbf&hair=buns,flatTop,fluffy,longCurls,parting,plain,roundBob,shaggy,shortCurls,spiky,wavy,bangs&mood=happy,hopeful,superHappy",
+        avatar_seed
+    )
+}
+
+pub fn render_navbar(local_user: &LocalUser) -> maud::Markup {
+    // the bell is only shown to logged in users
+    let unread = match local_user {
+        LocalUser::Authenticated {
+            unread_notifications,
+            ..
+        } => Some(*unread_notifications),
+        _ => None,
+    };
     html! {
         nav .navbar.bg-primary.navbar-dark.sticky-top {
             div .container {
@@ -9,6 +28,16 @@ pub fn render_navbar() -> maud::Markup {
                     i .fa-solid.fa-hand-holding-heart {}
                     strong { " Coisando Coisas" }
                 }
+                @if let Some(unread) = unread {
+                    a .btn.btn-primary.position-relative.me-2 href="/notificações" aria-label="Notificações" {
+                        i .fa-solid.fa-bell {}
+                        // kept while empty so the events script can fill it in
+                        span #notification-count .position-absolute.top-0.start-100.translate-middle.badge.rounded-pill.text-bg-danger .d-none[unread == 0] {
+                            @if unread > 99 { "99+" } @else { (unread) }
+                        }
+                    }
+                    script { (PreEscaped(events::CLIENT_SCRIPT)) }
+                }
                 button .btn.btn-primary.d-block.d-md-none type="button" data-bs-toggle="collapse" data-bs-target="#menu" aria-expanded="false" aria-controls="menu" {
                     i .fa-solid.fa-caret-down {} " Menu"
                 }
@@ -65,11 +94,35 @@ pub fn render_menu(local_user: &LocalUser) -> maud::Markup {
                                 i .fa-solid.fa-user {} " Minha conta"
                             }
                         }
+                        li .nav-item {
+                            a .nav-link href="/salvos" {
+                                i .fa-solid.fa-bookmark {} " Salvos"
+                            }
+                        }
+                        li .nav-item {
+                            a .nav-link href="/buscas" {
+                                i .fa-solid.fa-magnifying-glass {} " Buscas salvas"
+                            }
+                        }
                         li .nav-item {
                             a .nav-link href="/configurações" {
                                 i .fa-solid.fa-gear {} " Configurações"
                             }
                         }
+                        @if local_user.has_role(Role::Moderator) {
+                            li .nav-item {
+                                a .nav-link href="/moderação" {
+                                    i .fa-solid.fa-flag {} " Moderação"
+                                }
+                            }
+                        }
+                        @if local_user.has_role(Role::Admin) {
+                            li .nav-item {
+                                a .nav-link href="/admin" {
+                                    i .fa-solid.fa-screwdriver-wrench {} " Administração"
+                                }
+                            }
+                        }
                         li .nav-item {
                             a .nav-link href="/sair" {
                                 i .fa-solid.fa-lock-open {} " Sair"
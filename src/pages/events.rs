@@ -0,0 +1,82 @@
+use std::{convert::Infallible, time::Duration};
+
+use actix_session::Session;
+use actix_web::{error::ErrorUnauthorized, get, http::header, web, HttpResponse};
+use coisando_coisas::{realtime, session, DbPool, LocalUser};
+use futures_util::{stream, StreamExt};
+
+// proxies close connections that stay quiet for too long
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(25);
+// how often an open connection checks that its login is still valid
+const LOGIN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// the script that keeps the navbar counter up to date, included in pages of logged in users
+pub const CLIENT_SCRIPT: &str = r#"
+(() => {
+    const badge = document.getElementById("notification-count");
+    if (!badge || !window.EventSource) return;
+    const update = (unread) => {
+        badge.textContent = unread > 99 ? "99+" : unread;
+        badge.classList.toggle("d-none", unread === 0);
+    };
+    const events = new EventSource("/eventos");
+    events.addEventListener("notification", (e) => update(JSON.parse(e.data).unread));
+    events.addEventListener("unread", (e) => update(JSON.parse(e.data).unread));
+})();
+"#;
+
+#[get("/eventos")]
+async fn events(
+    local_user: LocalUser,
+    session: Session,
+    pool: web::Data<DbPool>,
+) -> actix_web::Result<HttpResponse> {
+    let (LocalUser::Authenticated { id: user_id, .. }, Some(login_id)) =
+        (local_user, session::login_id(&session))
+    else {
+        return Err(ErrorUnauthorized("Usuário não autenticado"));
+    };
+
+    // the connection is dropped with the stream when the client goes away, which closes the
+    // receiver and lets the broadcaster forget it
+    let receiver = realtime::broadcaster().subscribe(user_id);
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        Some((
+            Ok::<_, Infallible>(web::Bytes::from(event.to_sse())),
+            receiver,
+        ))
+    });
+    let keep_alive = stream::unfold(
+        actix_web::rt::time::interval(KEEP_ALIVE_INTERVAL),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((Ok(web::Bytes::from_static(b": keep-alive\n\n")), interval))
+        },
+    );
+    // the stream ends once the login is revoked or expires, or the account is disabled
+    let logged_out = async move {
+        let mut interval = actix_web::rt::time::interval(LOGIN_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Ok(mut conn) = pool.get() else {
+                continue;
+            };
+            match session::login_is_active(&mut conn, login_id, user_id) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => log::error!("Não foi possível verificar o login: {:?}", e),
+            }
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream::select(events, keep_alive).take_until(logged_out)))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(events);
+}
@@ -0,0 +1,219 @@
+use actix_web::{error::ErrorInternalServerError, get, post, web, HttpResponse};
+use coisando_coisas::{
+    csrf::CsrfToken,
+    exchanges, favorites,
+    schema::{favorites as favorites_table, listings, users},
+    Campus, DbPool, LocalUser, Type,
+};
+use diesel::{ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl};
+use maud::{html, Markup};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::render_base;
+
+/// the "salvar" button shown with listings. `back` is where the user returns after toggling
+pub fn render_save_toggle(
+    local_user: &LocalUser,
+    csrf: &CsrfToken,
+    listing_id: Uuid,
+    saved: bool,
+    back: &str,
+) -> Markup {
+    let authenticated = matches!(local_user, LocalUser::Authenticated { .. });
+    html! {
+        @if authenticated {
+            form .d-inline method="post" action=(format!("/favorites/{}/toggle", listing_id)) {
+                (csrf)
+                input type="hidden" name="back" value=(back);
+                button .btn.btn-link.p-0.text-decoration-none type="submit" {
+                    @if saved {
+                        i .fa-solid.fa-bookmark {} " Salvo"
+                    } @else {
+                        i .fa-regular.fa-bookmark {} " Salvar"
+                    }
+                }
+            }
+        } @else {
+            a .text-decoration-none href="/entrar" { i .fa-regular.fa-bookmark {} " Salvar" }
+        }
+    }
+}
+
+// where the toggle can send the user back to: the pages showing the button. anything else,
+// like another site, goes to the saved listings
+fn return_path(back: Option<&str>) -> &str {
+    match back {
+        Some(back @ ("/" | "/salvos")) => back,
+        Some(back)
+            if back.strip_prefix("/u/").is_some_and(|nickname| {
+                !nickname.is_empty()
+                    && nickname
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }) =>
+        {
+            back
+        }
+        _ => "/salvos",
+    }
+}
+
+#[derive(Deserialize)]
+struct ToggleForm {
+    back: Option<String>,
+}
+
+#[post("/favorites/{listing_id}/toggle")]
+async fn toggle_favorite(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<ToggleForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let listing_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    if let Err(e) = favorites::toggle(&mut conn, user_id, listing_id) {
+        log::error!("Não foi possível salvar o item {}: {:?}", listing_id, e);
+        return Err(ErrorInternalServerError("Não foi possível salvar o item"));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", return_path(details.back.as_deref())))
+        .finish())
+}
+
+#[get("/salvos")]
+async fn saved_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = match &local_user {
+        LocalUser::Authenticated { id, .. } => *id,
+        LocalUser::Anonymous => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/entrar"))
+                .finish());
+        }
+        LocalUser::Pending => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/confirmação"))
+                .finish());
+        }
+        LocalUser::Disabled => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/conta-desativada"))
+                .finish());
+        }
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // hidden listings stay saved but aren't listed, in case moderation shows them again
+    let Ok(saved) = favorites_table::table
+        .inner_join(listings::table)
+        .inner_join(users::table.on(listings::creator_id.eq(users::id)))
+        .filter(favorites_table::user_id.eq(user_id))
+        .filter(listings::hidden.eq(false))
+        .filter(exchanges::is_active())
+        .order_by(favorites_table::created_at.desc())
+        .select((
+            listings::id,
+            listings::title,
+            listings::type_,
+            listings::campus,
+            listings::reserved,
+            users::nickname,
+        ))
+        .load::<(Uuid, String, Type, Campus, bool, String)>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter os itens salvos",
+        ));
+    };
+
+    let markup = render_base(
+        html! {
+            h1 { "Itens salvos" }
+            p .text-muted { "Avisamos nas notificações e por email quando um item salvo for reservado ou deixar de estar disponível." }
+
+            @if saved.is_empty() {
+                p { "Você ainda não salvou nenhum item." }
+            }
+            div .vstack.gap-3 {
+                @for (id, title, listing_type, campus, reserved, nickname) in &saved {
+                    div .card.card-body.bg-body-tertiary.border-0.shadow-sm {
+                        div .hstack.gap-3 {
+                            div {
+                                a .h5.text-decoration-none href=(format!("/item/{}", id)) { (title) }
+                                @if *reserved {
+                                    " " span .badge.text-bg-warning { "Reservado" }
+                                }
+                                p .mb-0.text-muted {
+                                    (listing_type) " · " (campus) " · "
+                                    a href=(format!("/u/{}", nickname)) { (nickname) }
+                                }
+                            }
+                            div .ms-auto {
+                                (render_save_toggle(&local_user, &csrf, *id, true, "/salvos"))
+                            }
+                        }
+                    }
+                }
+            }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(toggle_favorite).service(saved_page);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_with_the_button_are_returned_to() {
+        assert_eq!(return_path(Some("/")), "/");
+        assert_eq!(return_path(Some("/salvos")), "/salvos");
+        assert_eq!(return_path(Some("/u/maria_2")), "/u/maria_2");
+    }
+
+    #[test]
+    fn other_addresses_go_to_the_saved_listings() {
+        for back in [
+            "//evil.example",
+            "/\\evil.example",
+            "/u/\\evil.example",
+            "/u/",
+            "/u/maria/../..",
+            "/u/maria\r\nSet-Cookie: a=b",
+            "https://evil.example",
+            "/entrar",
+        ] {
+            assert_eq!(return_path(Some(back)), "/salvos", "{:?}", back);
+        }
+        assert_eq!(return_path(None), "/salvos");
+    }
+}
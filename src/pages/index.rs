@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{collections::HashSet, time::Duration};
 
 use actix_web::{
     error::{ErrorBadRequest, ErrorInternalServerError},
@@ -6,31 +6,31 @@ use actix_web::{
 };
 use aws_sdk_s3::{presigning::PresigningConfig, Client};
 use coisando_coisas::{
-    schema::{attachments, listings, users},
-    AccountStatus, Campus, DbConn, DbPool, LocalUser, Type,
-};
-use diesel::{
-    BoolExpressionMethods, ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl, RunQueryDsl,
+    csrf::CsrfToken,
+    favorites,
+    listings::{self, ListingFilter},
+    reviews::{self, Reputation},
+    schema::users,
+    storage, AccountStatus, Campus, DbConn, DbPool, LocalUser, Type,
 };
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
 use maud::html;
 use uuid::Uuid;
 
-use super::{render_base, PaginationQuery};
+use super::{components::avatar_url, favorites::render_save_toggle, render_base, PaginationQuery};
 
 struct User {
     username: String,
     avatar_url: String,
+    reputation: Option<Reputation>,
 }
 
 impl User {
-    fn new(username: String, avatar_seed: Uuid) -> Self {
-        let avatar_url = format!(
-            "https://api.dicebear.com/9.x/dylan/svg?seed={}&radius=50&backgroundColor=29e051,619eff,ffa6e6,b6e3f4,c0aede,d1d4f9,ffd5dc,ffdfbf&hair=buns,flatTop,fluffy,longCurls,parting,plain,roundBob,shaggy,shortCurls,spiky,wavy,bangs&mood=happy,hopeful,superHappy",
-            avatar_seed
-        );
+    fn new(username: String, avatar_seed: Uuid, reputation: Option<Reputation>) -> Self {
         Self {
             username,
-            avatar_url,
+            avatar_url: avatar_url(avatar_seed),
+            reputation,
         }
     }
 }
@@ -41,21 +41,19 @@ struct Listing {
     description: String,
     type_: Type,
     campus: Campus,
+    reserved: bool,
+    saved: bool,
     images: Vec<String>,
     user: User,
 }
 
 fn get_listing_images(listing_id: Uuid, uploader_id: Uuid, conn: &mut DbConn) -> Vec<String> {
-    let Ok(results) = attachments::table
-        .filter(attachments::listing_id.eq(listing_id))
-        .select(attachments::id)
-        .load::<Uuid>(conn)
-    else {
+    let Ok(results) = listings::attachment_ids(conn, listing_id) else {
         return vec![];
     };
     let urls = results
         .iter()
-        .map(|id| format!("/attachments/{}/{}", uploader_id, id))
+        .map(|id| storage::attachment_url(uploader_id, *id))
         .collect();
 
     urls
@@ -65,6 +63,7 @@ fn get_listing_images(listing_id: Uuid, uploader_id: Uuid, conn: &mut DbConn) ->
 async fn render_index(
     pool: web::Data<DbPool>,
     local_user: LocalUser,
+    csrf: CsrfToken,
     pagination: web::Query<PaginationQuery>,
 ) -> actix_web::Result<HttpResponse> {
     let offset = pagination.deslocamento.unwrap_or(0);
@@ -78,42 +77,55 @@ async fn render_index(
     };
 
     // get just the content we need
-    let Ok(results) = listings::table
-        .inner_join(users::table.on(listings::creator_id.eq(users::id)))
-        .limit(limit as i64)
-        .offset(offset as i64)
-        .order_by(listings::created_at.desc())
-        .select((
-            listings::id,
-            listings::title,
-            listings::description,
-            listings::type_,
-            listings::campus,
-            users::id,
-            users::nickname,
-            users::avatar_seed,
-        ))
-        .load::<(Uuid, String, String, Type, Campus, Uuid, String, Uuid)>(&mut conn)
-    else {
+    let Ok(results) = listings::search(
+        &mut conn,
+        &ListingFilter::default(),
+        limit as i64,
+        offset as i64,
+    ) else {
         return Err(ErrorInternalServerError("Não foi possível obter os itens"));
     };
 
+    // score shown next to each poster's nickname
+    let creator_ids: Vec<Uuid> = results.iter().map(|row| row.creator_id).collect();
+    let Ok(reputations) = reviews::reputations(&mut conn, &creator_ids) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter as avaliações",
+        ));
+    };
+
+    // which of these the user saved
+    let saved = match local_user {
+        LocalUser::Authenticated { id, .. } => {
+            let listing_ids: Vec<Uuid> = results.iter().map(|row| row.id).collect();
+            let Ok(saved) = favorites::saved_among(&mut conn, id, &listing_ids) else {
+                return Err(ErrorInternalServerError(
+                    "Não foi possível obter os itens salvos",
+                ));
+            };
+            saved
+        }
+        _ => HashSet::new(),
+    };
+
     // convert to a more convenient format
     let listings: Vec<Listing> = results
-        .iter()
-        .map(
-            |(id, title, description, listing_type, campus, creator_id, nickname, avatar_seed)| {
-                Listing {
-                    id: *id,
-                    title: title.clone(),
-                    description: description.clone(),
-                    type_: *listing_type,
-                    campus: *campus,
-                    images: get_listing_images(*id, *creator_id, &mut conn),
-                    user: User::new(nickname.clone(), *avatar_seed),
-                }
-            },
-        )
+        .into_iter()
+        .map(|row| Listing {
+            id: row.id,
+            saved: saved.contains(&row.id),
+            images: get_listing_images(row.id, row.creator_id, &mut conn),
+            user: User::new(
+                row.creator_nickname,
+                row.creator_avatar_seed,
+                reputations.get(&row.creator_id).copied(),
+            ),
+            title: row.title,
+            description: row.description,
+            type_: row.listing_type,
+            campus: row.campus,
+            reserved: row.reserved,
+        })
         .collect();
 
     let markup = render_base(
@@ -134,7 +146,14 @@ async fn render_index(
                     div .col {
                         .card.card-body.bg-body-tertiary.border-0.shadow-sm.px-0 {
                             // simple avatar
-                            p .px-3 { img src=(item.user.avatar_url) width=(32) height=(32) {} " " (item.user.username) }
+                            p .px-3 {
+                                a .text-decoration-none.text-reset href=(format!("/u/{}", item.user.username)) {
+                                    img src=(item.user.avatar_url) width=(32) height=(32) {} " " (item.user.username)
+                                }
+                                @if let Some(reputation) = item.user.reputation {
+                                    small .text-muted { " " (reputation) }
+                                }
+                            }
 
                             // carousel
                             div .carousel.slide #(format!("carousel-{}", item.id)) {
@@ -159,7 +178,12 @@ async fn render_index(
 
                             div .vstack.gap-2.px-3 {
                                 // details
-                                h4 .mt-2.card-title { (item.title) }
+                                h4 .mt-2.card-title {
+                                    (item.title)
+                                    @if item.reserved {
+                                        " " span .badge.text-bg-warning.fs-6 { "Reservado" }
+                                    }
+                                }
                                 div .row.g-2 {
                                     div .col {
                                         strong.text-nowrap {
@@ -182,7 +206,11 @@ async fn render_index(
                                     }
                                 }
                                 p .d-block.text-truncate.text-wrap.card-text style="height: 3em" { (item.description) }
-                                a .text-decoration-none.text-center href=(format!("/item/{}", item.id)) { i .fa-solid.fa-circle-info {} " Detalhes" }
+                                div .hstack.justify-content-center.gap-3 {
+                                    a .text-decoration-none href=(format!("/item/{}", item.id)) { i .fa-solid.fa-circle-info {} " Detalhes" }
+                                    (render_save_toggle(&local_user, &csrf, item.id, item.saved, "/"))
+                                    a .text-decoration-none.text-danger href=(format!("/denunciar/anúncio/{}", item.id)) { i .fa-solid.fa-flag {} " Denunciar" }
+                                }
                             }
                         }
                     }
@@ -208,8 +236,8 @@ async fn generate_get_presigned_url(
     };
     let Ok(request) = s3_client
         .get_object()
-        .bucket("coisandocoisas")
-        .key(format!("{}/{}", user_id, attachment_id))
+        .bucket(storage::BUCKET)
+        .key(storage::attachment_key(user_id, attachment_id))
         .presigned(presigning_cfg)
         .await
     else {
@@ -22,7 +22,7 @@ fn render_base(content: maud::Markup, local_user: LocalUser) -> maud::Markup {
                 script src="https://kit.fontawesome.com/abfe2b7043.js" crossorigin="anonymous" {}
             }
             body {
-                (components::render_navbar())
+                (components::render_navbar(&local_user))
 
                 div .container.mt-4.mb-4 {
                     div .row {
@@ -49,7 +49,16 @@ fn render_base(content: maud::Markup, local_user: LocalUser) -> maud::Markup {
     }
 }
 
+pub mod admin;
 pub mod auth;
+pub mod events;
+pub mod favorites;
 pub mod index;
 pub mod info;
+pub mod moderation;
+pub mod notifications;
+pub mod profile;
+pub mod searches;
+pub mod sessions;
 pub mod submit;
+pub mod two_factor;
@@ -0,0 +1,1078 @@
+use std::{cmp::Reverse, collections::HashMap, env};
+
+use actix_web::{error::ErrorInternalServerError, get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use coisando_coisas::{
+    csrf::CsrfToken,
+    favorites::{self, FavoriteEvent},
+    mail::send_notification_email,
+    moderation::{self, ModerationAction, ReportReason, ReportTarget},
+    notifications::{self, NotificationKind},
+    permissions::ModeratorUser,
+    reviews, saved_searches,
+    schema::{listings, moderation_actions, reports, users},
+    set_account_status, AccountStatus, DbConn, DbPool, LocalUser, Role,
+};
+use diesel::{
+    Connection, ExpressionMethods, JoinOnDsl, NullableExpressionMethods, OptionalExtension,
+    QueryDsl, RunQueryDsl,
+};
+use maud::{html, Markup};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{auth::ErrorQuery, render_base};
+
+// how many entries the history shows per page
+const HISTORY_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct ReportForm {
+    reason: String,
+    #[serde(default)]
+    details: String,
+}
+
+fn render_report_form(
+    action: &str,
+    target: Markup,
+    csrf: &CsrfToken,
+    error: &ErrorQuery,
+) -> Markup {
+    html! {
+        form .vstack.gap-3 method="post" action=(action) {
+            (csrf)
+            h1 { "Denunciar" }
+            (target)
+            @if let Some(ref error) = error.erro {
+                div .alert.alert-danger role="alert" { (match error.as_str() {
+                    "motivo" => "Escolha um motivo para a denúncia.",
+                    "detalhes-longos" => "Os detalhes são muito longos.",
+                    "proprio" => "Você não pode denunciar a si mesmo nem seus próprios anúncios.",
+                    _ => "Erro desconhecido."
+                }) }
+            }
+            p {
+                "Denúncias são anônimas e analisadas pela moderação de acordo com as "
+                a href="/diretrizes-da-comunidade" { "diretrizes da comunidade" }
+                "."
+            }
+            select .form-select name="reason" required {
+                option value="" selected disabled { "Motivo" }
+                @for reason in ReportReason::ALL {
+                    option value=(reason.code()) { (reason) }
+                }
+            }
+            textarea .form-control name="details" rows="4" maxlength=(moderation::DETAILS_MAX_LENGTH) placeholder="Conte o que aconteceu (opcional)" {}
+            button .btn.btn-danger type="submit" { "Enviar denúncia" }
+        }
+    }
+}
+
+#[get("/denunciar/anúncio/{listing_id}")]
+async fn report_listing_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    path: web::Path<Uuid>,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let listing_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(title) = listings::table
+        .find(listing_id)
+        .filter(listings::hidden.eq(false))
+        .select(listings::title)
+        .first::<String>(&mut conn)
+        .optional()
+    else {
+        return Err(ErrorInternalServerError("Não foi possível obter o item"));
+    };
+    let Some(title) = title else {
+        return Ok(HttpResponse::NotFound().body("Anúncio não encontrado"));
+    };
+
+    let markup = render_base(
+        render_report_form(
+            &format!("/reports/listings/{}", listing_id),
+            html! { p { "Anúncio: " strong { (title) } } },
+            &csrf,
+            &error,
+        ),
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[get("/denunciar/usuário/{user_id}")]
+async fn report_user_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    path: web::Path<Uuid>,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let user_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(nickname) = users::table
+        .find(user_id)
+        .filter(users::status.eq(AccountStatus::CONFIRMED))
+        .select(users::nickname)
+        .first::<String>(&mut conn)
+        .optional()
+    else {
+        return Err(ErrorInternalServerError("Não foi possível obter o usuário"));
+    };
+    let Some(nickname) = nickname else {
+        return Ok(HttpResponse::NotFound().body("Usuário não encontrado"));
+    };
+
+    let markup = render_base(
+        render_report_form(
+            &format!("/reports/users/{}", user_id),
+            html! { p { "Usuário: " strong { (nickname) } } },
+            &csrf,
+            &error,
+        ),
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+// shared by both report routes, `form_url` is where errors are shown
+fn submit_report(
+    local_user: LocalUser,
+    pool: &DbPool,
+    target: ReportTarget,
+    form_url: &str,
+    details: &ReportForm,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated {
+        id: reporter_id, ..
+    } = local_user
+    else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    let Some(reason) = ReportReason::from_code(&details.reason) else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}?erro=motivo", form_url)))
+            .finish());
+    };
+    let text = details.details.trim();
+    if text.chars().count() > moderation::DETAILS_MAX_LENGTH {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}?erro=detalhes-longos", form_url)))
+            .finish());
+    }
+    if target == ReportTarget::User(reporter_id) {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}?erro=proprio", form_url)))
+            .finish());
+    }
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // a listing that's gone can't be reported, and neither can the reporter's own
+    if let ReportTarget::Listing(listing_id) = target {
+        let Ok(creator_id) = listings::table
+            .find(listing_id)
+            .select(listings::creator_id)
+            .first::<Uuid>(&mut conn)
+            .optional()
+        else {
+            return Err(ErrorInternalServerError(
+                "Não foi possível enviar a denúncia",
+            ));
+        };
+        match creator_id {
+            None => return Ok(HttpResponse::NotFound().body("Anúncio não encontrado")),
+            Some(creator_id) if creator_id == reporter_id => {
+                return Ok(HttpResponse::Found()
+                    .append_header(("Location", format!("{}?erro=proprio", form_url)))
+                    .finish())
+            }
+            Some(_) => {}
+        }
+    }
+
+    // a repeated report is accepted silently, the open one is already in the queue
+    let Ok(_) = moderation::create_report(&mut conn, reporter_id, target, reason, text) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível enviar a denúncia",
+        ));
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/denúncia-enviada"))
+        .finish())
+}
+
+#[post("/reports/listings/{listing_id}")]
+async fn report_listing(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<ReportForm>,
+) -> actix_web::Result<HttpResponse> {
+    let listing_id = path.into_inner();
+    submit_report(
+        local_user,
+        &pool,
+        ReportTarget::Listing(listing_id),
+        &format!("/denunciar/anúncio/{}", listing_id),
+        &details,
+    )
+}
+
+#[post("/reports/users/{user_id}")]
+async fn report_user(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<ReportForm>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+    submit_report(
+        local_user,
+        &pool,
+        ReportTarget::User(user_id),
+        &format!("/denunciar/usuário/{}", user_id),
+        &details,
+    )
+}
+
+#[get("/denúncia-enviada")]
+async fn report_sent_page(local_user: LocalUser) -> HttpResponse {
+    let markup = render_base(
+        html! {
+            h1 { "Denúncia enviada" }
+            p { "Obrigado por ajudar a manter a comunidade segura. A moderação vai analisar sua denúncia." }
+            a .btn.btn-primary href="/" { "Voltar ao início" }
+        },
+        local_user,
+    );
+    HttpResponse::Ok().body(markup.into_string())
+}
+
+struct Report {
+    reason: ReportReason,
+    details: String,
+    created_at: DateTime<Utc>,
+    // `None` for reports filed by the content filter
+    reporter: Option<String>,
+}
+
+// open reports about the same listing or user, shown together in the queue
+struct ReportGroup {
+    target: ReportTarget,
+    // listing title, or the nickname for reports about users
+    title: String,
+    // the reported user, or the creator of the reported listing
+    user_id: Uuid,
+    nickname: String,
+    listing_hidden: bool,
+    reports: Vec<Report>,
+}
+
+// loads the open reports grouped by target, oldest first
+fn load_report_groups(conn: &mut DbConn) -> diesel::QueryResult<Vec<ReportGroup>> {
+    let rows = reports::table
+        .left_join(users::table.on(reports::reporter_id.eq(users::id.nullable())))
+        .filter(reports::resolved_at.is_null())
+        .order_by(reports::created_at.asc())
+        .limit(500)
+        .select((
+            reports::listing_id,
+            reports::reported_user_id,
+            reports::reason,
+            reports::details,
+            reports::created_at,
+            users::nickname.nullable(),
+        ))
+        .load::<(
+            Option<Uuid>,
+            Option<Uuid>,
+            ReportReason,
+            String,
+            DateTime<Utc>,
+            Option<String>,
+        )>(conn)?;
+
+    let listing_ids: Vec<Uuid> = rows.iter().filter_map(|row| row.0).collect();
+    let listing_info: HashMap<Uuid, (String, bool, Uuid, String)> = listings::table
+        .inner_join(users::table.on(listings::creator_id.eq(users::id)))
+        .filter(listings::id.eq_any(&listing_ids))
+        .select((
+            listings::id,
+            listings::title,
+            listings::hidden,
+            users::id,
+            users::nickname,
+        ))
+        .load::<(Uuid, String, bool, Uuid, String)>(conn)?
+        .into_iter()
+        .map(|(id, title, hidden, user_id, nickname)| (id, (title, hidden, user_id, nickname)))
+        .collect();
+
+    let user_ids: Vec<Uuid> = rows.iter().filter_map(|row| row.1).collect();
+    let user_info: HashMap<Uuid, String> = users::table
+        .filter(users::id.eq_any(&user_ids))
+        .select((users::id, users::nickname))
+        .load::<(Uuid, String)>(conn)?
+        .into_iter()
+        .collect();
+
+    let mut groups: Vec<ReportGroup> = Vec::new();
+    let mut positions: HashMap<ReportTarget, usize> = HashMap::new();
+    for (listing_id, reported_user_id, reason, details, created_at, reporter) in rows {
+        let target = match (listing_id, reported_user_id) {
+            (Some(id), _) => ReportTarget::Listing(id),
+            (None, Some(id)) => ReportTarget::User(id),
+            (None, None) => continue,
+        };
+        let position = match positions.get(&target) {
+            Some(position) => *position,
+            None => {
+                let group = match target {
+                    ReportTarget::Listing(id) => {
+                        let Some((title, hidden, user_id, nickname)) = listing_info.get(&id) else {
+                            continue;
+                        };
+                        ReportGroup {
+                            target,
+                            title: title.clone(),
+                            user_id: *user_id,
+                            nickname: nickname.clone(),
+                            listing_hidden: *hidden,
+                            reports: Vec::new(),
+                        }
+                    }
+                    ReportTarget::User(id) => {
+                        let Some(nickname) = user_info.get(&id) else {
+                            continue;
+                        };
+                        ReportGroup {
+                            target,
+                            title: nickname.clone(),
+                            user_id: id,
+                            nickname: nickname.clone(),
+                            listing_hidden: false,
+                            reports: Vec::new(),
+                        }
+                    }
+                };
+                groups.push(group);
+                positions.insert(target, groups.len() - 1);
+                groups.len() - 1
+            }
+        };
+        groups[position].reports.push(Report {
+            reason,
+            details,
+            created_at,
+            reporter,
+        });
+    }
+
+    // the most reported targets first
+    groups.sort_by_key(|group| Reverse(group.reports.len()));
+    Ok(groups)
+}
+
+fn render_moderation_nav(active: &str) -> Markup {
+    html! {
+        ul .nav.nav-tabs.mb-3 {
+            li .nav-item {
+                a .nav-link.active[active == "fila"] href="/moderação" { "Denúncias" }
+            }
+            li .nav-item {
+                a .nav-link.active[active == "historico"] href="/moderação/histórico" { "Histórico" }
+            }
+        }
+    }
+}
+
+#[get("/moderação")]
+async fn moderation_queue(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(groups) = load_report_groups(&mut conn) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter as denúncias",
+        ));
+    };
+
+    let markup = render_base(
+        html! {
+            h1 { "Moderação" }
+            (render_moderation_nav("fila"))
+
+            @if let Some(ref error) = error.erro {
+                div .alert.alert-danger role="alert" { (match error.as_str() {
+                    "permissao" => "Você não pode desativar esta conta.",
+                    "mensagem" => "Escreva a mensagem da advertência.",
+                    "nota-longa" => "A mensagem é muito longa.",
+                    _ => "Erro desconhecido."
+                }) }
+            }
+
+            @if groups.is_empty() {
+                p { "Nenhuma denúncia aberta." }
+            }
+
+            @for group in &groups {
+                @let listing_id = match group.target {
+                    ReportTarget::Listing(id) => Some(id),
+                    ReportTarget::User(_) => None,
+                };
+                div .card.mb-3 {
+                    div .card-header.d-flex.justify-content-between.align-items-center {
+                        div {
+                            @match group.target {
+                                ReportTarget::Listing(_) => {
+                                    i .fa-solid.fa-box {} " Anúncio " strong { (group.title) }
+                                    " de " (group.nickname)
+                                    @if group.listing_hidden {
+                                        " " span .badge.text-bg-secondary { "Oculto" }
+                                    }
+                                }
+                                ReportTarget::User(_) => {
+                                    i .fa-solid.fa-user {} " Usuário " strong { (group.title) }
+                                }
+                            }
+                        }
+                        span .badge.text-bg-danger { (format!("{} denúncia(s)", group.reports.len())) }
+                    }
+                    ul .list-group.list-group-flush {
+                        @for report in &group.reports {
+                            li .list-group-item {
+                                strong { (report.reason) }
+                                small .text-muted {
+                                    (format!(" · por {} em {}", report.reporter.as_deref().unwrap_or("Filtro automático"), report.created_at.format("%d/%m/%Y %H:%M")))
+                                }
+                                @if !report.details.is_empty() {
+                                    p .mb-0.text-break { (report.details) }
+                                }
+                            }
+                        }
+                    }
+                    div .card-body.vstack.gap-2 {
+                        @if let Some(listing_id) = listing_id {
+                            @if group.listing_hidden {
+                                form method="post" action=(format!("/moderation/listings/{}/show", listing_id)) {
+                                    (csrf)
+                                    button .btn.btn-sm.btn-outline-secondary type="submit" { "Reexibir anúncio" }
+                                }
+                            } @else {
+                                form .hstack.gap-2 method="post" action=(format!("/moderation/listings/{}/hide", listing_id)) {
+                                    (csrf)
+                                    input .form-control.form-control-sm type="text" name="note" placeholder="Motivo (opcional)";
+                                    button .btn.btn-sm.btn-warning.text-nowrap type="submit" { "Ocultar anúncio" }
+                                }
+                            }
+                        }
+                        form .hstack.gap-2 method="post" action=(format!("/moderation/users/{}/warn", group.user_id)) {
+                            (csrf)
+                            @if let Some(listing_id) = listing_id {
+                                input type="hidden" name="listing_id" value=(listing_id);
+                            }
+                            input .form-control.form-control-sm type="text" name="note" placeholder=(format!("Mensagem para {}", group.nickname)) required;
+                            button .btn.btn-sm.btn-outline-primary.text-nowrap type="submit" { "Advertir" }
+                        }
+                        form .hstack.gap-2 method="post" action=(format!("/moderation/users/{}/disable", group.user_id)) {
+                            (csrf)
+                            @if let Some(listing_id) = listing_id {
+                                input type="hidden" name="listing_id" value=(listing_id);
+                            }
+                            input .form-control.form-control-sm type="text" name="note" placeholder="Motivo (opcional)";
+                            button .btn.btn-sm.btn-danger.text-nowrap type="submit" { (format!("Desativar {}", group.nickname)) }
+                        }
+                        form method="post" action="/moderation/reports/dismiss" {
+                            (csrf)
+                            @match group.target {
+                                ReportTarget::Listing(id) => { input type="hidden" name="listing_id" value=(id); }
+                                ReportTarget::User(id) => { input type="hidden" name="user_id" value=(id); }
+                            }
+                            button .btn.btn-sm.btn-link type="submit" { "Descartar denúncias" }
+                        }
+                    }
+                }
+            }
+        },
+        moderator.user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[derive(Deserialize)]
+struct ModerationForm {
+    #[serde(default)]
+    note: String,
+    listing_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+}
+
+impl ModerationForm {
+    fn note(&self) -> Option<&str> {
+        let note = self.note.trim();
+        (note.chars().count() <= moderation::DETAILS_MAX_LENGTH).then_some(note)
+    }
+}
+
+fn back_to_queue(error: Option<&str>) -> HttpResponse {
+    let location = match error {
+        Some(error) => format!("/moderação?erro={}", error),
+        None => "/moderação".to_string(),
+    };
+    HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish()
+}
+
+#[post("/moderation/listings/{listing_id}/hide")]
+async fn hide_listing(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<ModerationForm>,
+) -> actix_web::Result<HttpResponse> {
+    let listing_id = path.into_inner();
+    let Some(note) = details.note() else {
+        return Ok(back_to_queue(Some("nota-longa")));
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(Some((creator_id, title))) = listings::table
+        .find(listing_id)
+        .select((listings::creator_id, listings::title))
+        .first::<(Uuid, String)>(&mut conn)
+        .optional()
+    else {
+        return Ok(back_to_queue(None));
+    };
+
+    let result = conn.transaction(|conn| {
+        moderation::set_listing_hidden(conn, listing_id, true)?;
+        moderation::record_action(
+            conn,
+            Some(moderator.id),
+            ModerationAction::HideListing,
+            Some(listing_id),
+            Some(creator_id),
+            note,
+        )?;
+        moderation::resolve_reports(conn, ReportTarget::Listing(listing_id))
+    });
+    if let Err(e) = result {
+        log::error!("Não foi possível ocultar o anúncio {}: {:?}", listing_id, e);
+        return Err(ErrorInternalServerError(
+            "Não foi possível ocultar o anúncio",
+        ));
+    }
+
+    if let Err(e) = notifications::notify(
+        &mut conn,
+        creator_id,
+        NotificationKind::Moderation,
+        &format!(
+            "Seu anúncio \"{}\" foi ocultado pela moderação: {}",
+            title, note
+        ),
+        "/diretrizes-da-comunidade",
+    ) {
+        log::error!(
+            "Não foi possível notificar o usuário {}: {:?}",
+            creator_id,
+            e
+        );
+    }
+
+    // hidden listings stay saved, but for whoever saved them it's gone
+    let notices = favorites::notices(&mut conn, &[listing_id]).unwrap_or_else(|e| {
+        log::error!("Não foi possível obter quem salvou o anúncio: {:?}", e);
+        Vec::new()
+    });
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    favorites::send_notices(&mut conn, &mg_api_key, &notices, FavoriteEvent::Unavailable).await;
+
+    Ok(back_to_queue(None))
+}
+
+#[post("/moderation/listings/{listing_id}/show")]
+async fn show_listing(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let listing_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let result = conn.transaction::<bool, diesel::result::Error, _>(|conn| {
+        let was_hidden = listings::table
+            .find(listing_id)
+            .select(listings::hidden)
+            .first::<bool>(conn)
+            .optional()?;
+        moderation::set_listing_hidden(conn, listing_id, false)?;
+        moderation::record_action(
+            conn,
+            Some(moderator.id),
+            ModerationAction::ShowListing,
+            Some(listing_id),
+            None,
+            "",
+        )?;
+        Ok(was_hidden == Some(true))
+    });
+    let shown = match result {
+        Ok(shown) => shown,
+        Err(e) => {
+            log::error!(
+                "Não foi possível reexibir o anúncio {}: {:?}",
+                listing_id,
+                e
+            );
+            return Err(ErrorInternalServerError(
+                "Não foi possível reexibir o anúncio",
+            ));
+        }
+    };
+
+    // listings held back by the filter weren't announced to the saved searches yet
+    if shown {
+        let mg_api_key =
+            env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+        saved_searches::announce_listing(pool.get_ref().clone(), mg_api_key, listing_id);
+    }
+
+    Ok(back_to_queue(None))
+}
+
+// closes the reports about the user and, when the action came from a listing, about it too
+fn resolve_user_reports(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    listing_id: Option<Uuid>,
+) -> diesel::QueryResult<()> {
+    moderation::resolve_reports(conn, ReportTarget::User(user_id))?;
+    if let Some(listing_id) = listing_id {
+        moderation::resolve_reports(conn, ReportTarget::Listing(listing_id))?;
+    }
+    Ok(())
+}
+
+#[post("/moderation/users/{user_id}/warn")]
+async fn warn_user(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<ModerationForm>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+    let Some(note) = details.note() else {
+        return Ok(back_to_queue(Some("nota-longa")));
+    };
+    if note.is_empty() {
+        return Ok(back_to_queue(Some("mensagem")));
+    }
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(Some(nickname)) = users::table
+        .find(user_id)
+        .select(users::nickname)
+        .first::<String>(&mut conn)
+        .optional()
+    else {
+        return Ok(back_to_queue(None));
+    };
+
+    let result = conn.transaction(|conn| {
+        moderation::record_action(
+            conn,
+            Some(moderator.id),
+            ModerationAction::WarnUser,
+            details.listing_id,
+            Some(user_id),
+            note,
+        )?;
+        resolve_user_reports(conn, user_id, details.listing_id)
+    });
+    if let Err(e) = result {
+        log::error!("Não foi possível advertir o usuário {}: {:?}", user_id, e);
+        return Err(ErrorInternalServerError(
+            "Não foi possível advertir o usuário",
+        ));
+    }
+
+    // the warning is already recorded, notifications that fail to go out are only logged
+    if let Err(e) = notifications::notify(
+        &mut conn,
+        user_id,
+        NotificationKind::Moderation,
+        &format!("Você recebeu uma advertência da moderação: {}", note),
+        "/diretrizes-da-comunidade",
+    ) {
+        log::error!("Não foi possível notificar o usuário {}: {:?}", user_id, e);
+    }
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    if send_notification_email(
+        &mut conn,
+        &mg_api_key,
+        user_id,
+        NotificationKind::Moderation,
+        "advertência",
+        "Advertência da moderação do Coisando Coisas",
+        &[("nickname", nickname.as_str()), ("message", note)],
+    )
+    .await
+    .is_err()
+    {
+        log::error!("Não foi possível enviar a advertência para {}", user_id);
+    }
+
+    Ok(back_to_queue(None))
+}
+
+#[post("/moderation/users/{user_id}/disable")]
+async fn disable_user(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<ModerationForm>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = path.into_inner();
+    let Some(note) = details.note() else {
+        return Ok(back_to_queue(Some("nota-longa")));
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // staff can only be disabled by someone above them
+    let Ok(Some(role)) = users::table
+        .find(user_id)
+        .select(users::role)
+        .first::<Role>(&mut conn)
+        .optional()
+    else {
+        return Ok(back_to_queue(None));
+    };
+    if user_id == moderator.id || (role >= Role::Moderator && role >= moderator.role) {
+        return Ok(back_to_queue(Some("permissao")));
+    }
+
+    let result = conn.transaction(|conn| {
+        set_account_status(conn, user_id, AccountStatus::DISABLED)?;
+        moderation::record_action(
+            conn,
+            Some(moderator.id),
+            ModerationAction::DisableUser,
+            details.listing_id,
+            Some(user_id),
+            note,
+        )?;
+        resolve_user_reports(conn, user_id, details.listing_id)
+    });
+    if let Err(e) = result {
+        log::error!("Não foi possível desativar a conta {}: {:?}", user_id, e);
+        return Err(ErrorInternalServerError(
+            "Não foi possível desativar a conta",
+        ));
+    }
+
+    Ok(back_to_queue(None))
+}
+
+#[post("/moderation/reports/dismiss")]
+async fn dismiss_reports(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    details: web::Form<ModerationForm>,
+) -> actix_web::Result<HttpResponse> {
+    let target = match (details.listing_id, details.user_id) {
+        (Some(listing_id), _) => ReportTarget::Listing(listing_id),
+        (None, Some(user_id)) => ReportTarget::User(user_id),
+        (None, None) => return Ok(back_to_queue(None)),
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let result = conn.transaction(|conn| {
+        moderation::resolve_reports(conn, target)?;
+        moderation::record_action(
+            conn,
+            Some(moderator.id),
+            ModerationAction::DismissReports,
+            details.listing_id,
+            details.user_id,
+            "",
+        )
+    });
+    if let Err(e) = result {
+        log::error!("Não foi possível descartar as denúncias: {:?}", e);
+        return Err(ErrorInternalServerError(
+            "Não foi possível descartar as denúncias",
+        ));
+    }
+
+    Ok(back_to_queue(None))
+}
+
+#[post("/moderation/reviews/{review_id}/remove")]
+async fn remove_review(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let review_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // the comment goes to the history, since the review itself is gone
+    let result = conn.transaction(|conn| {
+        let Some((reviewed_id, comment)) = reviews::remove(conn, review_id)? else {
+            return Ok(None);
+        };
+        moderation::record_action(
+            conn,
+            Some(moderator.id),
+            ModerationAction::RemoveReview,
+            None,
+            Some(reviewed_id),
+            &comment,
+        )?;
+        users::table
+            .find(reviewed_id)
+            .select(users::nickname)
+            .first::<String>(conn)
+            .optional()
+    });
+    let nickname = match result {
+        Ok(nickname) => nickname,
+        Err(e) => {
+            log::error!(
+                "Não foi possível remover a avaliação {}: {:?}",
+                review_id,
+                e
+            );
+            return Err(ErrorInternalServerError(
+                "Não foi possível remover a avaliação",
+            ));
+        }
+    };
+
+    // back to the profile the review was on
+    let location = match nickname {
+        Some(nickname) => format!("/u/{}", nickname),
+        None => "/moderação/histórico".to_string(),
+    };
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    deslocamento: Option<usize>,
+}
+
+#[get("/moderação/histórico")]
+async fn moderation_history(
+    moderator: ModeratorUser,
+    pool: web::Data<DbPool>,
+    query: web::Query<HistoryQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let offset = query.deslocamento.unwrap_or(0);
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(entries) = moderation_actions::table
+        .left_join(users::table.on(moderation_actions::moderator_id.eq(users::id.nullable())))
+        .order_by(moderation_actions::created_at.desc())
+        .limit(HISTORY_PAGE_SIZE as i64)
+        .offset(offset as i64)
+        .select((
+            moderation_actions::action,
+            moderation_actions::listing_id,
+            moderation_actions::target_user_id,
+            moderation_actions::note,
+            moderation_actions::created_at,
+            users::nickname.nullable(),
+        ))
+        .load::<(
+            ModerationAction,
+            Option<Uuid>,
+            Option<Uuid>,
+            String,
+            DateTime<Utc>,
+            Option<String>,
+        )>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter o histórico",
+        ));
+    };
+
+    // nicknames of the users acted on, accounts deleted since then show up as removed
+    let target_ids: Vec<Uuid> = entries.iter().filter_map(|entry| entry.2).collect();
+    let Ok(targets) = users::table
+        .filter(users::id.eq_any(&target_ids))
+        .select((users::id, users::nickname))
+        .load::<(Uuid, String)>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter o histórico",
+        ));
+    };
+    let targets: HashMap<Uuid, String> = targets.into_iter().collect();
+
+    let markup = render_base(
+        html! {
+            h1 { "Moderação" }
+            (render_moderation_nav("historico"))
+
+            div .table-responsive {
+                table .table.align-middle {
+                    thead {
+                        tr {
+                            th { "Quando" }
+                            th { "Quem" }
+                            th { "Ação" }
+                            th { "Usuário" }
+                            th { "Anúncio" }
+                            th { "Nota" }
+                        }
+                    }
+                    tbody {
+                        @for (action, listing_id, target_user_id, note, created_at, moderator_nickname) in &entries {
+                            tr {
+                                td .text-nowrap { (created_at.format("%d/%m/%Y %H:%M")) }
+                                td { (moderator_nickname.as_deref().unwrap_or("Automático")) }
+                                td { (action) }
+                                td {
+                                    @if let Some(target_user_id) = target_user_id {
+                                        (targets.get(target_user_id).map(String::as_str).unwrap_or("(conta removida)"))
+                                    }
+                                }
+                                td .font-monospace.small {
+                                    @if let Some(listing_id) = listing_id {
+                                        (listing_id.simple().to_string()[..8])
+                                    }
+                                }
+                                td .text-break { (note) }
+                            }
+                        }
+                    }
+                }
+            }
+
+            div .hstack.gap-2 {
+                @if offset > 0 {
+                    a .btn.btn-outline-secondary href=(format!("/moderação/histórico?deslocamento={}", offset.saturating_sub(HISTORY_PAGE_SIZE))) { "Anterior" }
+                }
+                @if entries.len() == HISTORY_PAGE_SIZE {
+                    a .btn.btn-outline-secondary href=(format!("/moderação/histórico?deslocamento={}", offset + HISTORY_PAGE_SIZE)) { "Próxima" }
+                }
+            }
+        },
+        moderator.user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(report_listing_page)
+        .service(report_user_page)
+        .service(report_listing)
+        .service(report_user)
+        .service(report_sent_page)
+        .service(moderation_queue)
+        .service(hide_listing)
+        .service(show_listing)
+        .service(warn_user)
+        .service(disable_user)
+        .service(dismiss_reports)
+        .service(remove_review)
+        .service(moderation_history);
+}
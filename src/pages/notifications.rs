@@ -0,0 +1,336 @@
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError},
+    get, post, web, HttpResponse,
+};
+use coisando_coisas::{
+    csrf::CsrfToken,
+    notifications::{self, NotificationKind, Preference},
+    unsubscribe, DbPool, LocalUser,
+};
+use maud::html;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{render_base, PaginationQuery};
+
+const PAGE_SIZE: usize = 20;
+
+#[get("/notificações")]
+async fn notifications_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    pagination: web::Query<PaginationQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let (user_id, unread) = match &local_user {
+        LocalUser::Authenticated {
+            id,
+            unread_notifications,
+            ..
+        } => (*id, *unread_notifications),
+        LocalUser::Anonymous => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/entrar"))
+                .finish());
+        }
+        LocalUser::Pending => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/confirmação"))
+                .finish());
+        }
+        LocalUser::Disabled => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/conta-desativada"))
+                .finish());
+        }
+    };
+    let offset = pagination.deslocamento.unwrap_or(0);
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(results) = notifications::list(&mut conn, user_id, PAGE_SIZE as i64, offset as i64)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter as notificações",
+        ));
+    };
+
+    let markup = render_base(
+        html! {
+            div .hstack.gap-3.mb-3 {
+                h1 .mb-0 { "Notificações" }
+                @if unread > 0 {
+                    form .ms-auto method="post" action="/notifications/read-all" {
+                        (csrf)
+                        button .btn.btn-sm.btn-outline-secondary type="submit" { "Marcar todas como lidas" }
+                    }
+                }
+            }
+            p .text-muted {
+                "Escolha quais notificações receber nas "
+                a href="/configurações" { "configurações" }
+                "."
+            }
+
+            @if results.is_empty() && offset == 0 {
+                p { "Você não tem notificações." }
+            }
+            div .list-group.mb-3 {
+                @for notification in &results {
+                    // opening a notification marks it as read on the way to its link
+                    form method="post" action=(format!("/notifications/{}/read", notification.id)) {
+                        (csrf)
+                        button .list-group-item.list-group-item-action.text-start.w-100 .fw-semibold[!notification.read] type="submit" {
+                            div { (notification.message) }
+                            small .text-muted {
+                                (notification.kind) " · " (notification.created_at.format("%d/%m/%Y %H:%M"))
+                            }
+                        }
+                    }
+                }
+            }
+
+            div .hstack.gap-2 {
+                @if offset > 0 {
+                    a .btn.btn-outline-secondary href=(format!("/notificações?deslocamento={}", offset.saturating_sub(PAGE_SIZE))) { "Mais recentes" }
+                }
+                @if results.len() == PAGE_SIZE {
+                    a .btn.btn-outline-secondary href=(format!("/notificações?deslocamento={}", offset + PAGE_SIZE)) { "Mais antigas" }
+                }
+            }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[post("/notifications/{notification_id}/read")]
+async fn read_notification(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(link) = notifications::mark_read(&mut conn, user_id, path.into_inner()) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível abrir a notificação",
+        ));
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header((
+            "Location",
+            link.unwrap_or_else(|| "/notificações".to_string()),
+        ))
+        .finish())
+}
+
+#[post("/notifications/read-all")]
+async fn read_all_notifications(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    if let Err(e) = notifications::mark_all_read(&mut conn, user_id) {
+        log::error!(
+            "Não foi possível marcar as notificações como lidas: {:?}",
+            e
+        );
+        return Err(ErrorInternalServerError(
+            "Não foi possível marcar as notificações como lidas",
+        ));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/notificações"))
+        .finish())
+}
+
+/// the preferences section of the settings page
+pub fn render_preferences(csrf: &CsrfToken, preferences: &[Preference]) -> maud::Markup {
+    html! {
+        form .vstack.gap-3 method="post" action="/settings/notifications" {
+            (csrf)
+            h2 { "Notificações" }
+            p { "Escolha o que aparece nas suas notificações e o que também chega por email. Emails sobre a sua conta, como confirmações e alterações, são sempre enviados." }
+            table .table.align-middle {
+                thead {
+                    tr {
+                        th { "Tipo" }
+                        th .text-center { "No site" }
+                        th .text-center { "Por email" }
+                    }
+                }
+                tbody {
+                    @for preference in preferences {
+                        tr {
+                            td { (preference.kind) }
+                            td .text-center {
+                                input .form-check-input type="checkbox" name="in_app" value=(preference.kind.code()) aria-label=(format!("{} no site", preference.kind)) checked[preference.in_app];
+                            }
+                            td .text-center {
+                                input .form-check-input type="checkbox" name="email" value=(preference.kind.code()) aria-label=(format!("{} por email", preference.kind)) checked[preference.email];
+                            }
+                        }
+                    }
+                }
+            }
+            button .btn.btn-primary type="submit" { "Salvar" }
+        }
+    }
+}
+
+#[post("/settings/notifications")]
+async fn update_preferences(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    details: web::Form<Vec<(String, String)>>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // unchecked boxes aren't sent, so every kind left out is turned off
+    let checked = |field: &str| -> Vec<NotificationKind> {
+        details
+            .iter()
+            .filter(|(name, _)| name == field)
+            .filter_map(|(_, code)| NotificationKind::from_code(code))
+            .collect()
+    };
+    let in_app = checked("in_app");
+    let email = checked("email");
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    if let Err(e) = notifications::set_preferences(&mut conn, user_id, &in_app, &email) {
+        log::error!(
+            "Não foi possível salvar as preferências de notificação: {:?}",
+            e
+        );
+        return Err(ErrorInternalServerError(
+            "Não foi possível salvar as preferências de notificação",
+        ));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/configurações"))
+        .finish())
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeQuery {
+    token: String,
+}
+
+#[get("/cancelar-inscrição")]
+async fn unsubscribe_page(
+    local_user: LocalUser,
+    query: web::Query<UnsubscribeQuery>,
+) -> HttpResponse {
+    let kind = unsubscribe::verify(&query.token).map(|(_, kind)| kind);
+    let markup = render_base(
+        html! {
+            h1 { "Cancelar emails" }
+            @match kind {
+                Some(kind) => {
+                    p { "Você vai deixar de receber emails de " strong { (kind) } ". As notificações no site continuam como estão." }
+                    form method="post" action=(format!("/unsubscribe?token={}", query.token)) {
+                        button .btn.btn-primary type="submit" { "Cancelar emails" }
+                    }
+                }
+                None => {
+                    p { "O link é inválido. Você pode escolher quais emails receber nas " a href="/configurações" { "configurações" } "." }
+                }
+            }
+        },
+        local_user,
+    );
+    HttpResponse::Ok().body(markup.into_string())
+}
+
+// also the target of the one-click `List-Unsubscribe-Post` requests from mail clients, which
+// have no session and no csrf token
+#[post("/unsubscribe")]
+async fn unsubscribe_one_click(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    query: web::Query<UnsubscribeQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let Some((user_id, kind)) = unsubscribe::verify(&query.token) else {
+        return Err(ErrorBadRequest("Link inválido"));
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    if let Err(e) = notifications::unsubscribe(&mut conn, user_id, kind) {
+        log::error!(
+            "Não foi possível cancelar os emails de {}: {:?}",
+            user_id,
+            e
+        );
+        return Err(ErrorInternalServerError(
+            "Não foi possível cancelar os emails",
+        ));
+    }
+
+    let markup = render_base(
+        html! {
+            h1 { "Emails cancelados" }
+            p { "Você não vai mais receber emails de " strong { (kind) } "." }
+            p { "Mudou de ideia? Escolha quais emails receber nas " a href="/configurações" { "configurações" } "." }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(notifications_page)
+        .service(read_notification)
+        .service(read_all_notifications)
+        .service(update_preferences)
+        .service(unsubscribe_page)
+        .service(unsubscribe_one_click);
+}
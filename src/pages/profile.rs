@@ -0,0 +1,593 @@
+use std::{collections::HashSet, env};
+
+use actix_web::{
+    error::{ErrorInternalServerError, ErrorNotFound},
+    get, post, web, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use coisando_coisas::{
+    csrf::CsrfToken,
+    exchanges,
+    favorites::{self, FavoriteEvent},
+    lower,
+    notifications::{self, NotificationKind},
+    reviews::{self, COMMENT_MAX_LENGTH, RATING_MAX, RATING_MIN},
+    schema::{listings, users},
+    AccountStatus, Campus, DbPool, LocalUser, Role, Type,
+};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use maud::html;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{auth::ErrorQuery, components::avatar_url, favorites::render_save_toggle, render_base};
+
+// how many of the latest reviews the profile shows
+const REVIEWS_SHOWN: i64 = 20;
+
+#[get("/u/{nickname}")]
+async fn profile_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    path: web::Path<String>,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let nickname = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // disabled and unconfirmed accounts have no public profile
+    let Ok(user) = users::table
+        .filter(lower(users::nickname).eq(nickname.to_lowercase()))
+        .filter(users::status.eq(AccountStatus::CONFIRMED))
+        .select((
+            users::id,
+            users::nickname,
+            users::avatar_seed,
+            users::created_at,
+        ))
+        .first::<(Uuid, String, Uuid, DateTime<Utc>)>(&mut conn)
+        .optional()
+    else {
+        return Err(ErrorInternalServerError("Não foi possível obter o perfil"));
+    };
+    let Some((user_id, nickname, avatar_seed, created_at)) = user else {
+        return Err(ErrorNotFound("Usuário não encontrado"));
+    };
+
+    let Ok(active_listings) = listings::table
+        .filter(listings::creator_id.eq(user_id))
+        .filter(listings::hidden.eq(false))
+        .filter(exchanges::is_active())
+        .order_by(listings::created_at.desc())
+        .select((
+            listings::id,
+            listings::title,
+            listings::type_,
+            listings::campus,
+            listings::reserved,
+        ))
+        .load::<(Uuid, String, Type, Campus, bool)>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter os anúncios",
+        ));
+    };
+    let Ok(completed) = exchanges::completed_count(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError("Não foi possível obter as trocas"));
+    };
+    let (Ok(reputation), Ok(received)) = (
+        reviews::reputation(&mut conn, user_id),
+        reviews::received(&mut conn, user_id, REVIEWS_SHOWN),
+    ) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter as avaliações",
+        ));
+    };
+
+    // exchanges waiting for the user's answer or review are only shown on their own profile
+    let is_own = matches!(local_user, LocalUser::Authenticated { id, .. } if id == user_id);
+    let (awaiting, pending) = if is_own {
+        let (Ok(awaiting), Ok(pending)) = (
+            exchanges::awaiting(&mut conn, user_id),
+            reviews::pending(&mut conn, user_id),
+        ) else {
+            return Err(ErrorInternalServerError("Não foi possível obter as trocas"));
+        };
+        (awaiting, pending)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    let is_moderator = local_user.has_role(Role::Moderator);
+
+    // which of the listings the visitor saved
+    let saved = match local_user {
+        LocalUser::Authenticated { id, .. } if !is_own => {
+            let listing_ids: Vec<Uuid> = active_listings.iter().map(|row| row.0).collect();
+            let Ok(saved) = favorites::saved_among(&mut conn, id, &listing_ids) else {
+                return Err(ErrorInternalServerError(
+                    "Não foi possível obter os itens salvos",
+                ));
+            };
+            saved
+        }
+        _ => HashSet::new(),
+    };
+    let profile_path = format!("/u/{}", nickname);
+    let markup = render_base(
+        html! {
+            div .hstack.gap-3.mb-3 {
+                img .rounded-circle src=(avatar_url(avatar_seed)) width="96" height="96" alt="avatar";
+                div {
+                    h1 { (nickname) }
+                    p .text-muted.mb-0 { "Membro desde " (created_at.format("%d/%m/%Y")) }
+                }
+            }
+
+            div .hstack.gap-4.mb-4 {
+                div {
+                    strong { (active_listings.len()) }
+                    " anúncio(s) ativo(s)"
+                }
+                div {
+                    strong { (completed) }
+                    " troca(s) concluída(s)"
+                }
+                div {
+                    @match reputation {
+                        Some(reputation) => strong { (reputation) },
+                        None => span .text-muted { "Sem avaliações" },
+                    }
+                }
+                @if !is_own {
+                    a .ms-auto.text-decoration-none.text-danger href=(format!("/denunciar/usuário/{}", user_id)) {
+                        i .fa-solid.fa-flag {} " Denunciar"
+                    }
+                }
+            }
+
+            @if let Some(ref error) = error.erro {
+                div .alert.alert-danger role="alert" { (match error.as_str() {
+                    "parceiro" => "Não encontramos ninguém com esse apelido.",
+                    "proprio" => "Você não pode concluir um anúncio com você mesmo.",
+                    "concluido" => "Esse anúncio já foi concluído.",
+                    "nota" => "Escolha uma nota de 1 a 5.",
+                    "comentario-longo" => "O comentário é muito longo.",
+                    "avaliacao" => "Você já avaliou essa troca.",
+                    "troca" => "Essa troca não está mais aguardando sua confirmação.",
+                    _ => "Erro desconhecido."
+                }) }
+            }
+
+            @if !awaiting.is_empty() {
+                h2 .h4 { "Confirme suas trocas" }
+                div .vstack.gap-3.mb-4 {
+                    @for exchange in &awaiting {
+                        div .card.card-body.bg-body-tertiary.border-0.shadow-sm.vstack.gap-2 {
+                            p .mb-0 {
+                                a href=(format!("/u/{}", exchange.owner)) { (exchange.owner) }
+                                " diz que " strong { (exchange.listing_title) } " ficou com você"
+                                span .text-muted { " em " (exchange.completed_at.format("%d/%m/%Y")) }
+                            }
+                            div .hstack.gap-2 {
+                                form method="post" action=(format!("/exchanges/{}/accept", exchange.id)) {
+                                    (csrf)
+                                    button .btn.btn-sm.btn-primary type="submit" { "Confirmar" }
+                                }
+                                form method="post" action=(format!("/exchanges/{}/decline", exchange.id)) {
+                                    (csrf)
+                                    button .btn.btn-sm.btn-outline-secondary type="submit" { "Não participei" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            @if !pending.is_empty() {
+                h2 .h4 { "Avalie suas trocas" }
+                div .vstack.gap-3.mb-4 {
+                    @for exchange in &pending {
+                        form .card.card-body.bg-body-tertiary.border-0.shadow-sm.vstack.gap-2 method="post" action=(format!("/exchanges/{}/review", exchange.exchange_id)) {
+                            (csrf)
+                            p .mb-0 { strong { (exchange.listing_title) } " com " a href=(format!("/u/{}", exchange.other)) { (exchange.other) } }
+                            select .form-select name="rating" required {
+                                option value="" { "Nota" }
+                                @for rating in (RATING_MIN..=RATING_MAX).rev() {
+                                    option value=(rating) { (rating) " ★" }
+                                }
+                            }
+                            textarea .form-control name="comment" maxlength=(COMMENT_MAX_LENGTH) placeholder="Como foi a troca? (opcional)" {}
+                            button .btn.btn-primary.align-self-start type="submit" { "Avaliar" }
+                        }
+                    }
+                }
+            }
+
+            h2 .h4 { "Anúncios ativos" }
+            @if active_listings.is_empty() {
+                p .text-muted { "Nenhum anúncio ativo no momento." }
+            }
+            div .vstack.gap-3 {
+                @for (id, title, listing_type, campus, reserved) in &active_listings {
+                    div .card.card-body.bg-body-tertiary.border-0.shadow-sm {
+                        div .hstack.gap-3 {
+                            div {
+                                a .h5.text-decoration-none href=(format!("/item/{}", id)) { (title) }
+                                @if *reserved {
+                                    " " span .badge.text-bg-warning { "Reservado" }
+                                }
+                                p .mb-0.text-muted { (listing_type) " · " (campus) }
+                            }
+                            @if !is_own {
+                                div .ms-auto {
+                                    (render_save_toggle(&local_user, &csrf, *id, saved.contains(id), &profile_path))
+                                }
+                            }
+                        }
+                        @if is_own {
+                            form .mt-3 method="post" action=(format!("/listings/{}/reserve", id)) {
+                                (csrf)
+                                @if *reserved {
+                                    button .btn.btn-sm.btn-outline-secondary type="submit" { "Disponível novamente" }
+                                } @else {
+                                    button .btn.btn-sm.btn-outline-warning type="submit" { "Marcar como reservado" }
+                                }
+                            }
+                            // recorded by the creator once the item was handed over, counts once
+                            // whoever received it confirms
+                            form .hstack.gap-2.mt-3 method="post" action=(format!("/listings/{}/complete", id)) {
+                                (csrf)
+                                input .form-control.form-control-sm type="text" name="partner" placeholder="Apelido de quem recebeu" required;
+                                button .btn.btn-sm.btn-outline-success.text-nowrap type="submit" { "Marcar como concluído" }
+                            }
+                        }
+                    }
+                }
+            }
+
+            h2 .h4.mt-4 { "Avaliações" }
+            @if received.is_empty() {
+                p .text-muted { "Nenhuma avaliação ainda." }
+            }
+            div .vstack.gap-3 {
+                @for review in &received {
+                    div .card.card-body.bg-body-tertiary.border-0.shadow-sm {
+                        p .mb-1 {
+                            strong { (review.rating) " ★" }
+                            " · "
+                            a href=(format!("/u/{}", review.reviewer)) { (review.reviewer) }
+                            span .text-muted { " em " (review.created_at.format("%d/%m/%Y")) }
+                        }
+                        @if !review.comment.is_empty() {
+                            p .mb-0 { (review.comment) }
+                        }
+                        @if is_moderator {
+                            form .mt-2 method="post" action=(format!("/moderation/reviews/{}/remove", review.id)) {
+                                (csrf)
+                                button .btn.btn-sm.btn-outline-danger type="submit" { "Remover avaliação" }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[derive(Deserialize)]
+struct CompleteForm {
+    partner: String,
+}
+
+#[post("/listings/{listing_id}/complete")]
+async fn complete_listing(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<CompleteForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated {
+        id: user_id,
+        nickname,
+        ..
+    } = local_user
+    else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let listing_id = path.into_inner();
+    let back = |error: &str| {
+        HttpResponse::Found()
+            .append_header(("Location", format!("/u/{}?erro={}", nickname, error)))
+            .finish()
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(partner) = users::table
+        .filter(lower(users::nickname).eq(details.partner.trim().to_lowercase()))
+        .filter(users::status.eq(AccountStatus::CONFIRMED))
+        .select((users::id, users::nickname))
+        .first::<(Uuid, String)>(&mut conn)
+        .optional()
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível verificar o apelido",
+        ));
+    };
+    let Some((partner_id, partner_nickname)) = partner else {
+        return Ok(back("parceiro"));
+    };
+    if partner_id == user_id {
+        return Ok(back("proprio"));
+    }
+
+    // loaded before the listing leaves the active ones. whoever received it already knows
+    let Ok(mut notices) = favorites::notices(&mut conn, &[listing_id]) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível concluir o anúncio",
+        ));
+    };
+    notices.retain(|notice| !notice.nickname.eq_ignore_ascii_case(details.partner.trim()));
+
+    let Ok(title) = listings::table
+        .find(listing_id)
+        .select(listings::title)
+        .first::<String>(&mut conn)
+        .optional()
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível concluir o anúncio",
+        ));
+    };
+    let Some(title) = title else {
+        return Ok(back("concluido"));
+    };
+    let Ok(completed) = exchanges::complete_listing(&mut conn, listing_id, user_id, partner_id)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível concluir o anúncio",
+        ));
+    };
+    if !completed {
+        return Ok(back("concluido"));
+    }
+
+    if let Err(e) = notifications::notify(
+        &mut conn,
+        partner_id,
+        NotificationKind::Exchange,
+        &format!(
+            "{} diz que você ficou com \"{}\". Confirme a troca no seu perfil.",
+            nickname, title
+        ),
+        &format!("/u/{}", partner_nickname),
+    ) {
+        log::error!(
+            "Não foi possível notificar o usuário {}: {:?}",
+            partner_id,
+            e
+        );
+    }
+
+    let mg_api_key =
+        env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+    favorites::send_notices(&mut conn, &mg_api_key, &notices, FavoriteEvent::Unavailable).await;
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("/u/{}", nickname)))
+        .finish())
+}
+
+#[post("/listings/{listing_id}/reserve")]
+async fn toggle_reserved(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated {
+        id: user_id,
+        nickname,
+        ..
+    } = local_user
+    else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let listing_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(reserved) = diesel::update(
+        listings::table
+            .filter(listings::id.eq(listing_id))
+            .filter(listings::creator_id.eq(user_id)),
+    )
+    .set(listings::reserved.eq(diesel::dsl::not(listings::reserved)))
+    .returning(listings::reserved)
+    .get_result::<bool>(&mut conn)
+    .optional() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível reservar o anúncio",
+        ));
+    };
+
+    // only becoming reserved is worth an email
+    if reserved == Some(true) {
+        let Ok(notices) = favorites::notices(&mut conn, &[listing_id]) else {
+            return Err(ErrorInternalServerError(
+                "Não foi possível avisar quem salvou o anúncio",
+            ));
+        };
+        let mg_api_key =
+            env::var("MAILGUN_SENDING_API_KEY").expect("MAILGUN_SENDING_API_KEY must be set");
+        favorites::send_notices(&mut conn, &mg_api_key, &notices, FavoriteEvent::Reserved).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("/u/{}", nickname)))
+        .finish())
+}
+
+// the partner named in an exchange accepting or declining it
+async fn answer_exchange(
+    local_user: LocalUser,
+    pool: &DbPool,
+    exchange_id: Uuid,
+    accept: bool,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated {
+        id: user_id,
+        nickname,
+        ..
+    } = local_user
+    else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let result = if accept {
+        exchanges::accept(&mut conn, exchange_id, user_id)
+    } else {
+        exchanges::decline(&mut conn, exchange_id, user_id)
+    };
+    let location = match result {
+        Ok(true) => format!("/u/{}", nickname),
+        Ok(false) => format!("/u/{}?erro=troca", nickname),
+        Err(e) => {
+            log::error!(
+                "Não foi possível responder à troca {}: {:?}",
+                exchange_id,
+                e
+            );
+            return Err(ErrorInternalServerError(
+                "Não foi possível responder à troca",
+            ));
+        }
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
+
+#[post("/exchanges/{exchange_id}/accept")]
+async fn accept_exchange(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    answer_exchange(local_user, &pool, path.into_inner(), true).await
+}
+
+#[post("/exchanges/{exchange_id}/decline")]
+async fn decline_exchange(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    answer_exchange(local_user, &pool, path.into_inner(), false).await
+}
+
+#[derive(Deserialize)]
+struct ReviewForm {
+    rating: String,
+    #[serde(default)]
+    comment: String,
+}
+
+#[post("/exchanges/{exchange_id}/review")]
+async fn submit_review(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+    details: web::Form<ReviewForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated {
+        id: user_id,
+        nickname,
+        ..
+    } = local_user
+    else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let exchange_id = path.into_inner();
+    let back = |error: &str| {
+        HttpResponse::Found()
+            .append_header(("Location", format!("/u/{}?erro={}", nickname, error)))
+            .finish()
+    };
+
+    let Some(rating) = details
+        .rating
+        .parse::<i16>()
+        .ok()
+        .filter(|rating| (RATING_MIN..=RATING_MAX).contains(rating))
+    else {
+        return Ok(back("nota"));
+    };
+    let comment = details.comment.trim();
+    if comment.chars().count() > COMMENT_MAX_LENGTH {
+        return Ok(back("comentario-longo"));
+    }
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(submitted) = reviews::submit(&mut conn, exchange_id, user_id, rating, comment) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível salvar a avaliação",
+        ));
+    };
+    if !submitted {
+        return Ok(back("avaliacao"));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("/u/{}", nickname)))
+        .finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(profile_page)
+        .service(complete_listing)
+        .service(toggle_reserved)
+        .service(accept_exchange)
+        .service(decline_exchange)
+        .service(submit_review);
+}
@@ -0,0 +1,246 @@
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError},
+    get, post, web, HttpResponse,
+};
+use coisando_coisas::{
+    csrf::CsrfToken,
+    saved_searches::{self, Frequency},
+    Campus, DbPool, LocalUser, Type,
+};
+use maud::html;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{auth::ErrorQuery, render_base};
+
+#[get("/buscas")]
+async fn searches_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = match &local_user {
+        LocalUser::Authenticated { id, .. } => *id,
+        LocalUser::Anonymous => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/entrar"))
+                .finish());
+        }
+        LocalUser::Pending => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/confirmação"))
+                .finish());
+        }
+        LocalUser::Disabled => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/conta-desativada"))
+                .finish());
+        }
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(searches) = saved_searches::list(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter as buscas salvas",
+        ));
+    };
+
+    let markup = render_base(
+        html! {
+            h1 { "Buscas salvas" }
+            p .text-muted { "Avisamos nas notificações e por email quando um novo item tiver todas as palavras de uma busca." }
+
+            @if let Some(ref error) = error.erro {
+                div .alert.alert-danger role="alert" { (match error.as_str() {
+                    "palavras" => "Digite pelo menos uma palavra.",
+                    "palavras-longas" => "As palavras da busca são muito longas.",
+                    "limite" => "Você já tem o máximo de buscas salvas. Remova uma para criar outra.",
+                    _ => "Erro desconhecido."
+                }) }
+            }
+
+            div .vstack.gap-3.mb-4 {
+                @if searches.is_empty() {
+                    p { "Você ainda não salvou nenhuma busca." }
+                }
+                @for search in &searches {
+                    div .card.card-body.bg-body-tertiary.border-0.shadow-sm {
+                        div .hstack.gap-3 {
+                            div {
+                                p .h5.mb-1 { (search.keywords) }
+                                p .mb-0.text-muted {
+                                    @match search.campus {
+                                        Some(campus) => { (campus) }
+                                        None => { "Todos os campi" }
+                                    }
+                                    " · "
+                                    @match search.listing_type {
+                                        Some(listing_type) => { (listing_type) }
+                                        None => { "Todos os tipos" }
+                                    }
+                                    " · " (search.frequency)
+                                }
+                            }
+                            form .ms-auto method="post" action=(format!("/searches/{}/delete", search.id)) {
+                                (csrf)
+                                button .btn.btn-sm.btn-outline-danger type="submit" { "Remover" }
+                            }
+                        }
+                    }
+                }
+            }
+
+            form .vstack.gap-3 method="post" action="/searches" {
+                (csrf)
+                h2 { "Nova busca" }
+                input .form-control type="text" name="keywords" maxlength=(saved_searches::KEYWORDS_MAX_LENGTH) placeholder="Palavras, por exemplo: calculadora científica" required;
+                select .form-select name="campus" {
+                    option value="" { "Todos os campi" }
+                    @for campus in Campus::ALL {
+                        option { (campus) }
+                    }
+                }
+                select .form-select name="listing_type" {
+                    option value="" { "Todos os tipos" }
+                    @for listing_type in Type::ALL {
+                        option { (listing_type) }
+                    }
+                }
+                select .form-select name="frequency" {
+                    @for frequency in Frequency::ALL {
+                        option value=(frequency.code()) { (frequency) }
+                    }
+                }
+                button .btn.btn-primary type="submit" { "Salvar busca" }
+            }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[derive(Deserialize)]
+struct SearchForm {
+    keywords: String,
+    campus: String,
+    listing_type: String,
+    frequency: String,
+}
+
+fn back(error: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", format!("/buscas?erro={}", error)))
+        .finish()
+}
+
+#[post("/searches")]
+async fn create_search(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    details: web::Form<SearchForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // the same words in any spacing are the same search
+    let keywords = details
+        .keywords
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if keywords.is_empty() {
+        return Ok(back("palavras"));
+    }
+    if keywords.chars().count() > saved_searches::KEYWORDS_MAX_LENGTH {
+        return Ok(back("palavras-longas"));
+    }
+
+    // empty filters match everything
+    let campus = match details.campus.as_str() {
+        "" => None,
+        value => match Campus::ALL.into_iter().find(|c| c.to_string() == value) {
+            Some(campus) => Some(campus),
+            None => return Err(ErrorBadRequest("Campus inválido")),
+        },
+    };
+    let listing_type = match details.listing_type.as_str() {
+        "" => None,
+        value => match Type::ALL.into_iter().find(|t| t.to_string() == value) {
+            Some(listing_type) => Some(listing_type),
+            None => return Err(ErrorBadRequest("Tipo de anúncio inválido")),
+        },
+    };
+    let Some(frequency) = Frequency::from_code(&details.frequency) else {
+        return Err(ErrorBadRequest("Frequência inválida"));
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(created) = saved_searches::create(
+        &mut conn,
+        user_id,
+        &keywords,
+        campus,
+        listing_type,
+        frequency,
+    ) else {
+        return Err(ErrorInternalServerError("Não foi possível salvar a busca"));
+    };
+    if !created {
+        return Ok(back("limite"));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/buscas"))
+        .finish())
+}
+
+#[post("/searches/{search_id}/delete")]
+async fn delete_search(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    if let Err(e) = saved_searches::delete(&mut conn, user_id, path.into_inner()) {
+        log::error!("Não foi possível remover a busca: {:?}", e);
+        return Err(ErrorInternalServerError("Não foi possível remover a busca"));
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/buscas"))
+        .finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(searches_page)
+        .service(create_search)
+        .service(delete_search);
+}
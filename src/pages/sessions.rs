@@ -0,0 +1,210 @@
+use actix_session::Session;
+use actix_web::{error::ErrorInternalServerError, get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use coisando_coisas::{csrf::CsrfToken, schema::user_sessions, session, DbPool, LocalUser};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use maud::html;
+use uuid::Uuid;
+
+use super::render_base;
+
+struct Login {
+    id: Uuid,
+    user_agent: String,
+    ip: String,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+}
+
+#[get("/configurações/sessões")]
+async fn sessions_page(
+    local_user: LocalUser,
+    session: Session,
+    csrf: CsrfToken,
+    pool: web::Data<DbPool>,
+) -> actix_web::Result<HttpResponse> {
+    let user_id = match &local_user {
+        LocalUser::Authenticated { id, .. } => *id,
+        LocalUser::Anonymous => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/entrar"))
+                .finish());
+        }
+        LocalUser::Pending => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/confirmação"))
+                .finish());
+        }
+        LocalUser::Disabled => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/conta-desativada"))
+                .finish());
+        }
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(results) = user_sessions::table
+        .filter(user_sessions::user_id.eq(user_id))
+        .filter(user_sessions::expires_at.gt(Utc::now()))
+        .order_by(user_sessions::last_seen_at.desc())
+        .select((
+            user_sessions::id,
+            user_sessions::user_agent,
+            user_sessions::ip,
+            user_sessions::created_at,
+            user_sessions::last_seen_at,
+        ))
+        .load::<(Uuid, String, String, DateTime<Utc>, DateTime<Utc>)>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter suas sessões",
+        ));
+    };
+    let logins: Vec<Login> = results
+        .into_iter()
+        .map(|(id, user_agent, ip, created_at, last_seen_at)| Login {
+            id,
+            user_agent,
+            ip,
+            created_at,
+            last_seen_at,
+        })
+        .collect();
+    let current_login = session::login_id(&session);
+
+    let markup = render_base(
+        html! {
+            h1 { "Sessões ativas" }
+            p { "Estes são os lugares onde sua conta está conectada. Se não reconhecer algum deles, encerre a sessão e altere sua senha." }
+
+            ul .list-group.mb-3 {
+                @for login in &logins {
+                    li .list-group-item.d-flex.justify-content-between.align-items-start {
+                        div .me-2 {
+                            strong {
+                                (login.ip)
+                                @if Some(login.id) == current_login {
+                                    " " span .badge.text-bg-primary { "Esta sessão" }
+                                }
+                            }
+                            br;
+                            small .text-muted.text-break { (login.user_agent) }
+                            br;
+                            small {
+                                (format!(
+                                    "Entrou em {} · visto por último em {}",
+                                    login.created_at.format("%d/%m/%Y %H:%M"),
+                                    login.last_seen_at.format("%d/%m/%Y %H:%M")
+                                ))
+                            }
+                        }
+                        @if Some(login.id) != current_login {
+                            form method="post" action=(format!("/settings/sessions/{}/revoke", login.id)) {
+                                (csrf)
+                                button .btn.btn-sm.btn-outline-danger type="submit" { "Encerrar" }
+                            }
+                        }
+                    }
+                }
+            }
+
+            @if logins.len() > 1 {
+                form method="post" action="/settings/sessions/revoke-others" {
+                    (csrf)
+                    button .btn.btn-danger type="submit" { "Encerrar todas as outras sessões" }
+                }
+            }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[post("/settings/sessions/{login_id}/revoke")]
+async fn revoke_session(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let login_id = path.into_inner();
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // the user filter makes sure only the user's own logins can be revoked
+    let Ok(_) = diesel::delete(
+        user_sessions::table
+            .filter(user_sessions::id.eq(login_id))
+            .filter(user_sessions::user_id.eq(user_id)),
+    )
+    .execute(&mut conn) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível encerrar a sessão",
+        ));
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/configurações/sessões"))
+        .finish())
+}
+
+#[post("/settings/sessions/revoke-others")]
+async fn revoke_other_sessions(
+    local_user: LocalUser,
+    session: Session,
+    pool: web::Data<DbPool>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+    let Some(current_login) = session::login_id(&session) else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(_) = diesel::delete(
+        user_sessions::table
+            .filter(user_sessions::user_id.eq(user_id))
+            .filter(user_sessions::id.ne(current_login)),
+    )
+    .execute(&mut conn) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível encerrar as sessões",
+        ));
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/configurações/sessões"))
+        .finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(sessions_page)
+        .service(revoke_session)
+        .service(revoke_other_sessions);
+}
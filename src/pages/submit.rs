@@ -1,25 +1,42 @@
+use std::env;
+
 use actix_multipart::form::{tempfile::TempFile, text::Text, MultipartForm};
 use actix_web::{
     error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
     get, post, web, HttpResponse,
 };
-use aws_sdk_s3::{primitives::ByteStream, Client};
+use aws_sdk_s3::Client;
 use coisando_coisas::{
-    schema::{attachments, listings},
-    Campus, DbPool, LocalUser, Type,
+    content_filter::ContentFilter,
+    csrf::CsrfToken,
+    listings::{self, ListingDetails, Submission},
+    saved_searches, storage, Campus, DbPool, LocalUser, Type,
 };
-use diesel::{ExpressionMethods, RunQueryDsl};
 use maud::html;
-use uuid::Uuid;
 
-use crate::pages::render_base;
+use crate::pages::{auth::ErrorQuery, render_base};
 
 #[get("/novo")]
-async fn render_submit(local_user: LocalUser) -> actix_web::Result<HttpResponse> {
+async fn render_submit(
+    local_user: LocalUser,
+    csrf: CsrfToken,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let too_many_images = format!("Adicione no máximo {} imagens.", listings::MAX_IMAGES);
     let markup = render_base(
         html! {
             form .vstack action="/novo" method="post" enctype="multipart/form-data" {
+                // the csrf middleware only reads the first field of multipart bodies
+                (csrf)
                 h2 { "Novo item" }
+                @if let Some(ref error) = error.erro {
+                    div .alert.alert-danger role="alert" { (match error.as_str() {
+                        "sem-imagem" => "Adicione pelo menos uma imagem.",
+                        "muitas-imagens" => too_many_images.as_str(),
+                        "conteudo" => "O anúncio não segue as diretrizes da comunidade. Itens são doados, emprestados ou trocados, nunca vendidos, e o contato acontece pela plataforma.",
+                        _ => "Erro desconhecido."
+                    }) }
+                }
 
                 div .form-floating.mb-3 {
                     input type="text" class="form-control" id="title" name="title" placeholder="";
@@ -72,22 +89,29 @@ struct ItemForm {
 async fn submit_item(
     pool: web::Data<DbPool>,
     s3_client: web::Data<Client>,
+    content_filter: web::Data<ContentFilter>,
     local_user: LocalUser,
     MultipartForm(form): MultipartForm<ItemForm>,
 ) -> actix_web::Result<HttpResponse> {
     match local_user {
-        LocalUser::Anonymous => return Err(ErrorUnauthorized("Usuário não autenticado")),
-        LocalUser::Pending => return Err(ErrorForbidden("Usuário não confirmado")),
+        LocalUser::Anonymous => Err(ErrorUnauthorized("Usuário não autenticado")),
+        LocalUser::Pending => Err(ErrorForbidden("Usuário não confirmado")),
+        LocalUser::Disabled => Err(ErrorForbidden("Conta desativada")),
         LocalUser::Authenticated { id: creator_id, .. } => {
             let title = form.title.into_inner();
             let description = form.description.into_inner();
             let images = form.images;
 
-            if images.len() == 0 {
+            if images.is_empty() {
                 return Ok(HttpResponse::Found()
                     .append_header(("Location", "/novo?erro=sem-imagem"))
                     .finish());
             }
+            if images.len() > listings::MAX_IMAGES {
+                return Ok(HttpResponse::Found()
+                    .append_header(("Location", "/novo?erro=muitas-imagens"))
+                    .finish());
+            }
 
             // validate type and campus
             let listing_type = match form.listing_type.as_str() {
@@ -112,90 +136,35 @@ async fn submit_item(
                 ));
             };
 
-            // insert into database
-            let listing_id = match diesel::insert_into(listings::table)
-                .values((
-                    listings::id.eq(Uuid::new_v4()),
-                    listings::title.eq(title),
-                    listings::description.eq(description),
-                    listings::type_.eq(listing_type),
-                    listings::campus.eq(campus),
-                    listings::creator_id.eq(creator_id),
-                ))
-                .returning(listings::id)
-                .get_result::<Uuid>(&mut conn)
-            {
-                Ok(id) => id,
-                Err(e) => return Err(ErrorInternalServerError(e)),
+            // checked against the community guidelines before it's saved
+            let details = ListingDetails {
+                title,
+                description,
+                listing_type,
+                campus,
             };
-
-            // upload images to cloudflare r2
-            for image in images {
-                let img_id = Uuid::new_v4();
-                let path = format!("/tmp/{}", img_id);
-
-                // persist image file
-                if let Err(e) = image.file.persist(&path) {
-                    // log error
-                    log::error!(
-                        "Não foi possível salvar o anexo {} no item {}: {:?}",
-                        img_id,
-                        listing_id,
-                        e
-                    );
-                    continue;
-                }
-
-                // create ByteStream from path
-                let stream = match ByteStream::from_path(&path).await {
-                    Ok(stream) => stream,
-                    Err(e) => {
-                        // log error
-                        log::error!(
-                            "Não foi possível criar ByteStream para o anexo {} no item {}: {:?}",
-                            img_id,
-                            listing_id,
-                            e
-                        );
-                        continue;
+            let (listing_id, hidden) =
+                match listings::create_listing(&mut conn, &content_filter, creator_id, &details) {
+                    Ok(Submission::Accepted { listing_id, hidden }) => (listing_id, hidden),
+                    Ok(Submission::Rejected) => {
+                        return Ok(HttpResponse::Found()
+                            .append_header(("Location", "/novo?erro=conteudo"))
+                            .finish());
                     }
+                    Err(e) => return Err(ErrorInternalServerError(e)),
                 };
 
-                // upload image using s3 sdk
-                if let Err(e) = s3_client
-                    .put_object()
-                    .bucket("coisandocoisas")
-                    .key(&format!("{}/{}", creator_id, img_id))
-                    .body(stream)
-                    .send()
-                    .await
-                {
-                    // log error
-                    log::error!(
-                        "Não foi possível enviar o anexo {} do item {}: {:?}",
-                        img_id,
-                        listing_id,
-                        e
-                    );
-                    continue;
-                }
+            // upload images to cloudflare r2
+            for image in images {
+                storage::upload_attachment(&s3_client, &mut conn, creator_id, listing_id, image)
+                    .await;
+            }
 
-                // insert attachment into database
-                if let Err(e) = diesel::insert_into(attachments::table)
-                    .values((
-                        attachments::id.eq(img_id),
-                        attachments::listing_id.eq(listing_id),
-                    ))
-                    .execute(&mut conn)
-                {
-                    // log error
-                    log::error!(
-                        "Não foi possível inserir informações do anexo {}, item {} no banco de dados: {:?}",
-                        img_id, listing_id,
-                        e
-                    );
-                    continue;
-                }
+            // let the users whose saved searches match know, hidden listings wait for moderation
+            if !hidden {
+                let mg_api_key = env::var("MAILGUN_SENDING_API_KEY")
+                    .expect("MAILGUN_SENDING_API_KEY must be set");
+                saved_searches::announce_listing(pool.get_ref().clone(), mg_api_key, listing_id);
             }
 
             Ok(HttpResponse::SeeOther()
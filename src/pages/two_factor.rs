@@ -0,0 +1,444 @@
+use actix_session::Session;
+use actix_web::{error::ErrorInternalServerError, get, post, web, HttpRequest, HttpResponse};
+use coisando_coisas::{
+    csrf::CsrfToken,
+    rate_limit::RateLimiter,
+    schema::{totp_credentials, users},
+    two_factor, AccountStatus, DbPool, LocalUser,
+};
+use diesel::{upsert::excluded, ExpressionMethods, QueryDsl, RunQueryDsl};
+use maud::{html, Markup, PreEscaped};
+use serde::Deserialize;
+
+use super::{
+    auth::{complete_login, verify_current_password, ErrorQuery},
+    render_base,
+};
+
+#[derive(Deserialize)]
+struct CodeForm {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordForm {
+    current_password: String,
+}
+
+fn render_recovery_codes(codes: &[String]) -> Markup {
+    html! {
+        div .alert.alert-warning role="alert" {
+            "Guarde estes códigos de recuperação em um lugar seguro. Cada um pode ser usado uma única vez para entrar se você perder o acesso ao aplicativo, e eles não serão mostrados novamente."
+        }
+        ul .list-group.mb-3.font-monospace {
+            @for code in codes {
+                li .list-group-item { (code) }
+            }
+        }
+    }
+}
+
+#[get("/configurações/2fa")]
+async fn two_factor_page(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    csrf: CsrfToken,
+    error: web::Query<ErrorQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let (user_id, nickname) = match &local_user {
+        LocalUser::Authenticated { id, nickname, .. } => (*id, nickname.clone()),
+        LocalUser::Anonymous => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/entrar"))
+                .finish());
+        }
+        LocalUser::Pending => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/confirmação"))
+                .finish());
+        }
+        LocalUser::Disabled => {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/conta-desativada"))
+                .finish());
+        }
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(credentials) = two_factor::credentials(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter suas informações",
+        ));
+    };
+
+    // the secret is kept while it's not confirmed, so reloading the page doesn't invalidate
+    // a QR code that was already scanned
+    let (secret, enabled) = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            let secret = two_factor::generate_secret();
+            let Ok(_) = diesel::insert_into(totp_credentials::table)
+                .values((
+                    totp_credentials::user_id.eq(user_id),
+                    totp_credentials::secret.eq(&secret),
+                ))
+                .on_conflict(totp_credentials::user_id)
+                .do_update()
+                .set(totp_credentials::secret.eq(excluded(totp_credentials::secret)))
+                .execute(&mut conn)
+            else {
+                return Err(ErrorInternalServerError(
+                    "Não foi possível iniciar a configuração",
+                ));
+            };
+            (secret, false)
+        }
+    };
+
+    let remaining_codes = if enabled {
+        let Ok(remaining) = two_factor::remaining_recovery_codes(&mut conn, user_id) else {
+            return Err(ErrorInternalServerError(
+                "Não foi possível obter suas informações",
+            ));
+        };
+        remaining
+    } else {
+        0
+    };
+
+    let markup = render_base(
+        html! {
+            h1 { "Verificação em duas etapas" }
+
+            @if let Some(ref error) = error.erro {
+                div .alert.alert-danger role="alert" { (match error.as_str() {
+                    "codigo" => "O código está incorreto. Confira o horário do seu celular e tente novamente.",
+                    "senha-atual-incorreta" => "A senha atual está incorreta.",
+                    _ => "Erro desconhecido."
+                }) }
+            }
+
+            @if enabled {
+                div .alert.alert-success role="alert" {
+                    "A verificação em duas etapas está ativada. Ao entrar, pediremos um código do seu aplicativo autenticador."
+                }
+                p { (format!("Você tem {} código(s) de recuperação não usado(s).", remaining_codes)) }
+
+                form .vstack.gap-3.mb-3 method="post" action="/settings/2fa/recovery-codes" {
+                    (csrf)
+                    h2 { "Gerar novos códigos de recuperação" }
+                    p { "Os códigos antigos deixarão de funcionar." }
+                    input .form-control type="password" name="current_password" placeholder="Senha atual" required;
+                    button .btn.btn-secondary type="submit" { "Gerar novos códigos" }
+                }
+
+                form .vstack.gap-3 method="post" action="/settings/2fa/disable" {
+                    (csrf)
+                    h2 { "Desativar" }
+                    input .form-control type="password" name="current_password" placeholder="Senha atual" required;
+                    button .btn.btn-danger type="submit" { "Desativar verificação em duas etapas" }
+                }
+            } @else {
+                p { "Escaneie o QR code abaixo com um aplicativo autenticador (como Google Authenticator, Aegis ou 1Password) e digite o código gerado para ativar." }
+                @if let Some(svg) = two_factor::qr_code_svg(&secret, &nickname) {
+                    div .mb-3 { (PreEscaped(svg)) }
+                }
+                p {
+                    "Se não puder escanear, digite esta chave no aplicativo: "
+                    code .text-break { (secret) }
+                }
+
+                form .vstack.gap-3 method="post" action="/settings/2fa/enable" {
+                    (csrf)
+                    input .form-control type="text" name="code" placeholder="Código de 6 dígitos" inputmode="numeric" autocomplete="one-time-code" required;
+                    button .btn.btn-primary type="submit" { "Ativar" }
+                }
+            }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[post("/settings/2fa/enable")]
+async fn enable_two_factor(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    details: web::Form<CodeForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(credentials) = two_factor::credentials(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível obter suas informações",
+        ));
+    };
+    // only a secret that was shown and not confirmed yet can be enabled
+    let Some((secret, false)) = credentials else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/configurações/2fa"))
+            .finish());
+    };
+
+    // the code proves the app was set up correctly
+    let Some(step) = two_factor::verify_totp(&secret, &details.code) else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/configurações/2fa?erro=codigo"))
+            .finish());
+    };
+
+    let Ok(_) = two_factor::enable(&mut conn, user_id, step) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível ativar a verificação em duas etapas",
+        ));
+    };
+    let Ok(codes) = two_factor::replace_recovery_codes(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível gerar os códigos de recuperação",
+        ));
+    };
+
+    // the codes are shown right away instead of redirecting, they aren't stored in clear text
+    let markup = render_base(
+        html! {
+            h1 { "Verificação em duas etapas ativada" }
+            (render_recovery_codes(&codes))
+            a .btn.btn-primary href="/configurações" { "Voltar às configurações" }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[post("/settings/2fa/recovery-codes")]
+async fn regenerate_recovery_codes(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    details: web::Form<PasswordForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    if !verify_current_password(&mut conn, user_id, &details.current_password)? {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/configurações/2fa?erro=senha-atual-incorreta"))
+            .finish());
+    }
+
+    let Ok(true) = two_factor::is_enabled(&mut conn, user_id) else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/configurações/2fa"))
+            .finish());
+    };
+    let Ok(codes) = two_factor::replace_recovery_codes(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível gerar os códigos de recuperação",
+        ));
+    };
+
+    let markup = render_base(
+        html! {
+            h1 { "Novos códigos de recuperação" }
+            (render_recovery_codes(&codes))
+            a .btn.btn-primary href="/configurações/2fa" { "Voltar" }
+        },
+        local_user,
+    );
+    Ok(HttpResponse::Ok().body(markup.into_string()))
+}
+
+#[post("/settings/2fa/disable")]
+async fn disable_two_factor(
+    local_user: LocalUser,
+    pool: web::Data<DbPool>,
+    details: web::Form<PasswordForm>,
+) -> actix_web::Result<HttpResponse> {
+    let LocalUser::Authenticated { id: user_id, .. } = local_user else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish());
+    };
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    // someone with access to an open session shouldn't be able to turn it off
+    if !verify_current_password(&mut conn, user_id, &details.current_password)? {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/configurações/2fa?erro=senha-atual-incorreta"))
+            .finish());
+    }
+
+    let Ok(_) = two_factor::disable(&mut conn, user_id) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível desativar a verificação em duas etapas",
+        ));
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", "/configurações"))
+        .finish())
+}
+
+#[get("/entrar/verificação")]
+async fn verification_page(
+    local_user: LocalUser,
+    session: Session,
+    csrf: CsrfToken,
+    error: web::Query<ErrorQuery>,
+) -> HttpResponse {
+    if two_factor::pending_login(&session).is_none() {
+        return HttpResponse::Found()
+            .append_header(("Location", "/entrar"))
+            .finish();
+    }
+
+    let markup = render_base(
+        html! {
+            form .vstack.gap-3 method="post" action="/entrar/verificação" {
+                (csrf)
+                h1 { "Verificação em duas etapas" }
+                @if let Some(ref error) = error.erro {
+                    div .alert.alert-danger role="alert" { (match error.as_str() {
+                        "codigo" => "Código incorreto.",
+                        "bloqueado" => "Muitas tentativas de login. Aguarde alguns minutos e tente novamente.",
+                        _ => "Erro desconhecido."
+                    }) }
+                }
+                p { "Digite o código do seu aplicativo autenticador ou um dos seus códigos de recuperação." }
+                input .form-control type="text" name="code" placeholder="Código" autocomplete="one-time-code" autofocus required;
+                button .btn.btn-primary type="submit" { "Entrar" }
+            }
+        },
+        local_user,
+    );
+    HttpResponse::Ok().body(markup.into_string())
+}
+
+#[post("/entrar/verificação")]
+async fn verify_login(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    limiter: web::Data<RateLimiter>,
+    details: web::Form<CodeForm>,
+) -> actix_web::Result<HttpResponse> {
+    let location = match verify_pending_login(&req, &session, &pool, &limiter, &details.code)? {
+        VerificationOutcome::LoggedIn => "/minha-conta",
+        VerificationOutcome::NoPendingLogin => "/entrar",
+        VerificationOutcome::Locked => "/entrar/verificação?erro=bloqueado",
+        VerificationOutcome::InvalidCode => "/entrar/verificação?erro=codigo",
+        VerificationOutcome::Disabled => "/entrar?erro=desativada",
+    };
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
+
+/// how checking the second factor of a login ended
+pub(crate) enum VerificationOutcome {
+    LoggedIn,
+    /// the password wasn't checked first, or the login took too long
+    NoPendingLogin,
+    /// too many failures for the account
+    Locked,
+    InvalidCode,
+    /// the account was disabled after the password was accepted
+    Disabled,
+}
+
+/// finishes a login waiting for the second factor, shared by the form and the api
+pub(crate) fn verify_pending_login(
+    req: &HttpRequest,
+    session: &Session,
+    pool: &DbPool,
+    limiter: &RateLimiter,
+    code: &str,
+) -> actix_web::Result<VerificationOutcome> {
+    let Some((user_id, remember)) = two_factor::pending_login(session) else {
+        return Ok(VerificationOutcome::NoPendingLogin);
+    };
+
+    // wrong codes count towards the same lockout as wrong passwords
+    let account_key = format!("login:user:{}", user_id);
+    if limiter.check_lockout(&account_key).is_err() {
+        return Ok(VerificationOutcome::Locked);
+    }
+
+    // get a connection from the pool
+    let Ok(mut conn) = pool.get() else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível conectar ao banco de dados",
+        ));
+    };
+
+    let Ok(valid) = two_factor::verify_code(&mut conn, user_id, code) else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível verificar o código",
+        ));
+    };
+    if !valid {
+        limiter.record_failure(&account_key);
+        return Ok(VerificationOutcome::InvalidCode);
+    }
+
+    limiter.record_success(&account_key);
+    two_factor::end_pending_login(session);
+
+    // the account may have been disabled while the code was being typed
+    let Ok(status) = users::table
+        .find(user_id)
+        .select(users::status)
+        .first::<AccountStatus>(&mut conn)
+    else {
+        return Err(ErrorInternalServerError(
+            "Não foi possível verificar o código",
+        ));
+    };
+    if let AccountStatus::DISABLED = status {
+        return Ok(VerificationOutcome::Disabled);
+    }
+
+    complete_login(req, &mut conn, session, user_id, remember)?;
+    Ok(VerificationOutcome::LoggedIn)
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(two_factor_page)
+        .service(enable_two_factor)
+        .service(regenerate_recovery_codes)
+        .service(disable_two_factor)
+        .service(verification_page)
+        .service(verify_login);
+}
@@ -0,0 +1,84 @@
+// extractors guarding the pages only some roles can see. a handler taking `AdminUser` or
+// `ModeratorUser` only runs for users with that role or above, everyone else is turned away
+
+use std::{
+    future::{ready, Ready},
+    marker::PhantomData,
+};
+
+use actix_web::{dev::Payload, error::InternalError, FromRequest, HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+use crate::{LocalUser, Role};
+
+/// the least role needed by a `Staff` extractor
+pub trait MinimumRole {
+    const ROLE: Role;
+}
+
+pub struct Moderators;
+pub struct Admins;
+
+impl MinimumRole for Moderators {
+    const ROLE: Role = Role::Moderator;
+}
+
+impl MinimumRole for Admins {
+    const ROLE: Role = Role::Admin;
+}
+
+/// a logged in user with at least the role `R`. the `LocalUser` is kept so pages can still be
+/// rendered with it
+pub struct Staff<R: MinimumRole> {
+    pub id: Uuid,
+    pub role: Role,
+    pub user: LocalUser,
+    _role: PhantomData<R>,
+}
+
+pub type ModeratorUser = Staff<Moderators>;
+pub type AdminUser = Staff<Admins>;
+
+fn redirect(location: &str) -> actix_web::Error {
+    InternalError::from_response(
+        "",
+        HttpResponse::Found()
+            .append_header(("Location", location))
+            .finish(),
+    )
+    .into()
+}
+
+fn forbidden() -> actix_web::Error {
+    InternalError::from_response(
+        "",
+        HttpResponse::Forbidden().body("Você não tem permissão para acessar esta página."),
+    )
+    .into()
+}
+
+impl<R: MinimumRole> FromRequest for Staff<R> {
+    type Error = actix_web::Error;
+    type Future = Ready<actix_web::Result<Staff<R>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = match LocalUser::from_request(req, payload).into_inner() {
+            Ok(user) => user,
+            Err(e) => return ready(Err(e)),
+        };
+
+        let result = match user {
+            LocalUser::Authenticated { id, role, .. } if role >= R::ROLE => Ok(Staff {
+                id,
+                role,
+                user,
+                _role: PhantomData,
+            }),
+            LocalUser::Authenticated { .. } => Err(forbidden()),
+            LocalUser::Anonymous => Err(redirect("/entrar")),
+            LocalUser::Pending => Err(redirect("/confirmação")),
+            LocalUser::Disabled => Err(redirect("/conta-desativada")),
+        };
+        ready(result)
+    }
+}
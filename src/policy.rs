@@ -0,0 +1,207 @@
+// rules for nicknames and passwords, shared by registration and the settings page
+
+pub const NICKNAME_MIN_LENGTH: usize = 3;
+pub const NICKNAME_MAX_LENGTH: usize = 24;
+pub const PASSWORD_MIN_LENGTH: usize = 8;
+pub const PASSWORD_MAX_LENGTH: usize = 128;
+pub const PASSWORD_SYMBOLS: &str = "!@#$%^&*()-_=+[]{}|;:,.<>/?";
+
+// names that could be used to impersonate the staff or clash with our routes
+const RESERVED_NICKNAMES: [&str; 16] = [
+    "admin",
+    "administrador",
+    "administrator",
+    "coisando",
+    "coisandocoisas",
+    "coisando_coisas",
+    "equipe",
+    "mod",
+    "moderador",
+    "moderator",
+    "naoresponder",
+    "root",
+    "sistema",
+    "staff",
+    "suporte",
+    "support",
+];
+
+// passwords that pass the character requirements but are still among the first
+// ones tried by anyone guessing. compared in lowercase
+const COMMON_PASSWORDS: [&str; 20] = [
+    "password1!",
+    "password123!",
+    "passw0rd!",
+    "p@ssw0rd",
+    "p@ssw0rd1",
+    "p@ssword1",
+    "senha123!",
+    "senha@123",
+    "senha@1234",
+    "s3nh@123",
+    "qwerty123!",
+    "qwerty@123",
+    "abc@1234",
+    "abcd@1234",
+    "admin@123",
+    "welcome1!",
+    "mudar@123",
+    "brasil@123",
+    "unb@2024",
+    "unb@2025",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NicknameError {
+    TooShort,
+    TooLong,
+    InvalidCharacters,
+    Reserved,
+}
+
+impl NicknameError {
+    /// value used in the `erro` query parameter when redirecting back to the form
+    pub fn code(&self) -> &'static str {
+        match self {
+            NicknameError::TooShort => "apelido-curto",
+            NicknameError::TooLong => "apelido-longo",
+            NicknameError::InvalidCharacters => "apelido-invalido",
+            NicknameError::Reserved => "apelido-reservado",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordError {
+    TooShort,
+    TooLong,
+    Weak,
+    Common,
+}
+
+impl PasswordError {
+    /// value used in the `erro` query parameter when redirecting back to the form
+    pub fn code(&self) -> &'static str {
+        match self {
+            PasswordError::TooShort => "senha-curta",
+            PasswordError::TooLong => "senha-longa",
+            PasswordError::Weak => "senha-fraca",
+            PasswordError::Common => "senha-comum",
+        }
+    }
+}
+
+/// checks length, allowed characters and reserved names of a nickname
+pub fn validate_nickname(nickname: &str) -> Result<(), NicknameError> {
+    let length = nickname.chars().count();
+    if length < NICKNAME_MIN_LENGTH {
+        return Err(NicknameError::TooShort);
+    }
+    if length > NICKNAME_MAX_LENGTH {
+        return Err(NicknameError::TooLong);
+    }
+    if !nickname
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(NicknameError::InvalidCharacters);
+    }
+
+    let lowercase = nickname.to_ascii_lowercase();
+    if RESERVED_NICKNAMES.contains(&lowercase.as_str()) {
+        return Err(NicknameError::Reserved);
+    }
+
+    Ok(())
+}
+
+/// checks length, character classes and the common password list
+pub fn validate_password(password: &str) -> Result<(), PasswordError> {
+    let length = password.chars().count();
+    if length < PASSWORD_MIN_LENGTH {
+        return Err(PasswordError::TooShort);
+    }
+    if length > PASSWORD_MAX_LENGTH {
+        return Err(PasswordError::TooLong);
+    }
+
+    let has_lowercase = password.chars().any(|c| c.is_ascii_lowercase());
+    let has_uppercase = password.chars().any(|c| c.is_ascii_uppercase());
+    let has_digit = password.chars().any(|c| c.is_ascii_digit());
+    let has_symbol = password.chars().any(|c| PASSWORD_SYMBOLS.contains(c));
+    if !(has_lowercase && has_uppercase && has_digit && has_symbol) {
+        return Err(PasswordError::Weak);
+    }
+
+    let lowercase = password.to_lowercase();
+    if COMMON_PASSWORDS.contains(&lowercase.as_str()) {
+        return Err(PasswordError::Common);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nickname_length() {
+        assert_eq!(validate_nickname("ab"), Err(NicknameError::TooShort));
+        assert_eq!(validate_nickname("abc"), Ok(()));
+        assert_eq!(validate_nickname(&"a".repeat(NICKNAME_MAX_LENGTH)), Ok(()));
+        assert_eq!(
+            validate_nickname(&"a".repeat(NICKNAME_MAX_LENGTH + 1)),
+            Err(NicknameError::TooLong)
+        );
+    }
+
+    #[test]
+    fn nickname_characters() {
+        assert_eq!(validate_nickname("maria_2024"), Ok(()));
+        assert_eq!(
+            validate_nickname("maria silva"),
+            Err(NicknameError::InvalidCharacters)
+        );
+        assert_eq!(
+            validate_nickname("joão"),
+            Err(NicknameError::InvalidCharacters)
+        );
+        assert_eq!(
+            validate_nickname("a@b.com"),
+            Err(NicknameError::InvalidCharacters)
+        );
+    }
+
+    #[test]
+    fn reserved_nicknames_ignore_case() {
+        assert_eq!(validate_nickname("admin"), Err(NicknameError::Reserved));
+        assert_eq!(validate_nickname("AdMiN"), Err(NicknameError::Reserved));
+        assert_eq!(validate_nickname("Moderador"), Err(NicknameError::Reserved));
+        assert_eq!(validate_nickname("admin2"), Ok(()));
+    }
+
+    #[test]
+    fn password_length() {
+        assert_eq!(validate_password("Ab1!xyz"), Err(PasswordError::TooShort));
+        assert_eq!(validate_password("Ab1!wxyz"), Ok(()));
+        let long = format!("Ab1!{}", "x".repeat(PASSWORD_MAX_LENGTH - 3));
+        assert_eq!(validate_password(&long), Err(PasswordError::TooLong));
+    }
+
+    #[test]
+    fn weak_passwords() {
+        assert_eq!(validate_password("abcdefg1!"), Err(PasswordError::Weak));
+        assert_eq!(validate_password("ABCDEFG1!"), Err(PasswordError::Weak));
+        assert_eq!(validate_password("Abcdefgh!"), Err(PasswordError::Weak));
+        assert_eq!(validate_password("Abcdefgh1"), Err(PasswordError::Weak));
+    }
+
+    #[test]
+    fn common_passwords_ignore_case() {
+        assert_eq!(validate_password("Password1!"), Err(PasswordError::Common));
+        assert_eq!(validate_password("P@ssw0rd1"), Err(PasswordError::Common));
+        assert_eq!(validate_password("Senha@123"), Err(PasswordError::Common));
+        assert_eq!(validate_password("Senha@123x"), Ok(()));
+    }
+}
@@ -0,0 +1,328 @@
+// in-process rate limiting. counters live in memory, so each instance keeps its own
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_identity::IdentityExt;
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web, HttpResponse,
+};
+
+use crate::{client_ip, env_or};
+
+// entries are pruned once the map grows past this size
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// limits for the routes that are expensive or send emails, read from the environment
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// registrations per hour, per IP (`RATE_LIMIT_REGISTER_PER_HOUR`)
+    pub register_per_hour: u32,
+    /// emails sent on request (email change) per hour, per IP and account (`RATE_LIMIT_EMAIL_PER_HOUR`)
+    pub email_per_hour: u32,
+    /// listings submitted per hour, per IP and account (`RATE_LIMIT_SUBMIT_PER_HOUR`)
+    pub submit_per_hour: u32,
+    /// failed logins allowed before locking the account or IP (`LOGIN_MAX_FAILURES`)
+    pub login_max_failures: u32,
+    /// first lockout, doubled on every failure after that (`LOGIN_LOCKOUT_SECONDS`)
+    pub login_lockout: Duration,
+    /// longest lockout possible (`LOGIN_MAX_LOCKOUT_SECONDS`)
+    pub login_max_lockout: Duration,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        Self {
+            register_per_hour: env_or("RATE_LIMIT_REGISTER_PER_HOUR", 5),
+            email_per_hour: env_or("RATE_LIMIT_EMAIL_PER_HOUR", 5),
+            submit_per_hour: env_or("RATE_LIMIT_SUBMIT_PER_HOUR", 20),
+            login_max_failures: env_or("LOGIN_MAX_FAILURES", 5),
+            login_lockout: Duration::from_secs(env_or("LOGIN_LOCKOUT_SECONDS", 30)),
+            login_max_lockout: Duration::from_secs(env_or("LOGIN_MAX_LOCKOUT_SECONDS", 3600)),
+        }
+    }
+}
+
+struct Window {
+    started_at: Instant,
+    count: u32,
+}
+
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// shared between workers through `web::Data`
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    windows: Mutex<HashMap<String, Window>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            windows: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// counts a hit for the key, failing with the time left in the window when over the limit
+    pub fn hit(&self, key: &str, limit: u32, period: Duration) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, window| now.duration_since(window.started_at) < period);
+        }
+
+        let window = windows.entry(key.to_string()).or_insert(Window {
+            started_at: now,
+            count: 0,
+        });
+        if now.duration_since(window.started_at) >= period {
+            window.started_at = now;
+            window.count = 0;
+        }
+        if window.count >= limit {
+            return Err(period - now.duration_since(window.started_at));
+        }
+        window.count += 1;
+        Ok(())
+    }
+
+    /// fails with the time left when the key is locked out because of failed logins
+    pub fn check_lockout(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        match failures.get(key).and_then(|entry| entry.locked_until) {
+            Some(locked_until) if locked_until > now => Err(locked_until - now),
+            _ => Ok(()),
+        }
+    }
+
+    /// counts a failed login, locking the key for twice as long on every failure past the limit
+    pub fn record_failure(&self, key: &str) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        if failures.len() > PRUNE_THRESHOLD {
+            let max_lockout = self.config.login_max_lockout;
+            failures.retain(|_, entry| now.duration_since(entry.last_failure) < max_lockout);
+        }
+
+        let entry = failures.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            locked_until: None,
+            last_failure: now,
+        });
+        // failures are forgotten after a quiet period as long as the longest lockout
+        if now.duration_since(entry.last_failure) >= self.config.login_max_lockout {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last_failure = now;
+
+        if entry.count >= self.config.login_max_failures {
+            let exponent = (entry.count - self.config.login_max_failures).min(16);
+            let lockout = self
+                .config
+                .login_lockout
+                .saturating_mul(1 << exponent)
+                .min(self.config.login_max_lockout);
+            entry.locked_until = Some(now + lockout);
+        }
+    }
+
+    /// forgets the failures of the key after a successful login
+    pub fn record_success(&self, key: &str) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.remove(key);
+    }
+}
+
+/// how the limited routes are told apart, along with the limit for each
+fn limit_for(config: &RateLimitConfig, method: &Method, path: &str) -> Option<(&'static str, u32)> {
+    if method != Method::POST {
+        return None;
+    }
+    match path {
+        "/registrar" => Some(("registrar", config.register_per_hour)),
+        "/settings/email" => Some(("email", config.email_per_hour)),
+        // listings created through the api share the limit of the form
+        "/novo" | "/api/v1/listings" => Some(("novo", config.submit_per_hour)),
+        // images added to a listing afterwards, which fill the bucket just the same
+        path if path.starts_with("/api/v1/listings/") && path.ends_with("/attachments") => {
+            Some(("imagens", config.submit_per_hour))
+        }
+        _ => None,
+    }
+}
+
+pub fn too_many_requests(path: &str, retry_after: Duration) -> HttpResponse {
+    let minutes = retry_after.as_secs().div_ceil(60).max(1);
+    let message = format!(
+        "Muitas tentativas. Tente novamente em {} minuto(s).",
+        minutes
+    );
+    let mut response = HttpResponse::TooManyRequests();
+    response.append_header(("Retry-After", retry_after.as_secs().max(1).to_string()));
+    if path.starts_with("/api/") {
+        return response.json(serde_json::json!({
+            "error": { "code": "rate_limited", "message": message }
+        }));
+    }
+    response.body(message)
+}
+
+/// middleware limiting the routes in `limit_for` per IP and, when logged in, per account.
+/// must be wrapped inside the identity middleware so the account is known
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() else {
+        return next.call(req).await.map(|res| res.map_into_boxed_body());
+    };
+    let Some((route, limit)) = limit_for(&limiter.config, req.method(), req.path()) else {
+        return next.call(req).await.map(|res| res.map_into_boxed_body());
+    };
+    let period = Duration::from_secs(3600);
+
+    let ip = client_ip(req.request()).unwrap_or_else(|| "desconhecido".to_string());
+    if let Err(retry_after) = limiter.hit(&format!("{}:ip:{}", route, ip), limit, period) {
+        log::warn!("Limite de {} atingido pelo IP {}", route, ip);
+        let response = too_many_requests(req.path(), retry_after);
+        return Ok(req.into_response(response));
+    }
+
+    if let Some(user_id) = req.get_identity().ok().and_then(|id| id.id().ok()) {
+        if let Err(retry_after) = limiter.hit(&format!("{}:user:{}", route, user_id), limit, period)
+        {
+            log::warn!("Limite de {} atingido pelo usuário {}", route, user_id);
+            let response = too_many_requests(req.path(), retry_after);
+            return Ok(req.into_response(response));
+        }
+    }
+
+    next.call(req).await.map(|res| res.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            register_per_hour: 5,
+            email_per_hour: 5,
+            submit_per_hour: 20,
+            login_max_failures: 3,
+            login_lockout: Duration::from_secs(30),
+            login_max_lockout: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn hits_over_the_limit_fail() {
+        let limiter = RateLimiter::new(config());
+        let period = Duration::from_secs(3600);
+        assert!(limiter.hit("a", 2, period).is_ok());
+        assert!(limiter.hit("a", 2, period).is_ok());
+        let retry_after = limiter.hit("a", 2, period).unwrap_err();
+        assert!(retry_after <= period && retry_after > Duration::from_secs(3500));
+    }
+
+    #[test]
+    fn keys_are_counted_apart() {
+        let limiter = RateLimiter::new(config());
+        let period = Duration::from_secs(3600);
+        assert!(limiter.hit("a", 1, period).is_ok());
+        assert!(limiter.hit("a", 1, period).is_err());
+        assert!(limiter.hit("b", 1, period).is_ok());
+    }
+
+    #[test]
+    fn window_starts_over_after_the_period() {
+        let limiter = RateLimiter::new(config());
+        let period = Duration::from_millis(20);
+        assert!(limiter.hit("a", 1, period).is_ok());
+        assert!(limiter.hit("a", 1, period).is_err());
+        sleep(period);
+        assert!(limiter.hit("a", 1, period).is_ok());
+    }
+
+    #[test]
+    fn failures_lock_out_past_the_limit() {
+        let limiter = RateLimiter::new(config());
+        limiter.record_failure("a");
+        limiter.record_failure("a");
+        assert!(limiter.check_lockout("a").is_ok());
+        limiter.record_failure("a");
+        let first = limiter.check_lockout("a").unwrap_err();
+        assert!(first <= Duration::from_secs(30) && first > Duration::from_secs(25));
+        assert!(limiter.check_lockout("b").is_ok());
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_maximum() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            login_max_lockout: Duration::from_secs(100),
+            ..config()
+        });
+        for _ in 0..4 {
+            limiter.record_failure("a");
+        }
+        let second = limiter.check_lockout("a").unwrap_err();
+        assert!(second <= Duration::from_secs(60) && second > Duration::from_secs(55));
+        limiter.record_failure("a");
+        let capped = limiter.check_lockout("a").unwrap_err();
+        assert!(capped <= Duration::from_secs(100) && capped > Duration::from_secs(95));
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let limiter = RateLimiter::new(config());
+        for _ in 0..3 {
+            limiter.record_failure("a");
+        }
+        assert!(limiter.check_lockout("a").is_err());
+        limiter.record_success("a");
+        assert!(limiter.check_lockout("a").is_ok());
+        limiter.record_failure("a");
+        assert!(limiter.check_lockout("a").is_ok());
+    }
+
+    #[test]
+    fn limited_routes() {
+        let config = config();
+        assert_eq!(
+            limit_for(&config, &Method::POST, "/registrar"),
+            Some(("registrar", 5))
+        );
+        assert_eq!(
+            limit_for(&config, &Method::POST, "/api/v1/listings"),
+            Some(("novo", 20))
+        );
+        assert_eq!(
+            limit_for(
+                &config,
+                &Method::POST,
+                "/api/v1/listings/1c1f8c2e-5f7a-4a35-9d47-3b0e0c1b7a10/attachments"
+            ),
+            Some(("imagens", 20))
+        );
+        assert_eq!(limit_for(&config, &Method::GET, "/registrar"), None);
+        assert_eq!(limit_for(&config, &Method::POST, "/login"), None);
+    }
+}
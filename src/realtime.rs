@@ -0,0 +1,145 @@
+// events pushed to the open pages of a user over server-sent events, so the notification
+// counter and similar bits update without a reload
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+// events a slow connection can fall behind before new ones are dropped for it
+const CONNECTION_BUFFER: usize = 32;
+
+/// something that happened to the user, sent as the `data` of an event named after the variant
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    /// a new notification was added
+    Notification {
+        message: String,
+        link: String,
+        unread: i64,
+    },
+    /// notifications were read, possibly on another page
+    Unread { unread: i64 },
+}
+
+impl Event {
+    /// the event name clients listen for
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Notification { .. } => "notification",
+            Event::Unread { .. } => "unread",
+        }
+    }
+
+    /// the event in the server-sent events wire format
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string());
+        format!("event: {}\ndata: {}\n\n", self.name(), data)
+    }
+}
+
+/// delivers events to the connections of each user. the in-process one only reaches the
+/// connections held by this server, one based on postgres' LISTEN/NOTIFY would reach every
+/// instance without changing the callers
+pub trait Broadcaster: Send + Sync {
+    /// sends the event to every open connection of the user, if there's any
+    fn publish(&self, user_id: Uuid, event: Event);
+
+    /// a new connection of the user, receiving every event published from now on
+    fn subscribe(&self, user_id: Uuid) -> mpsc::Receiver<Event>;
+}
+
+/// keeps the connections in memory, enough while there's a single instance
+#[derive(Default)]
+pub struct InProcess {
+    connections: Mutex<HashMap<Uuid, Vec<mpsc::Sender<Event>>>>,
+}
+
+impl Broadcaster for InProcess {
+    fn publish(&self, user_id: Uuid, event: Event) {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(senders) = connections.get_mut(&user_id) else {
+            return;
+        };
+
+        // closed connections are only noticed here, when something is sent to them
+        senders.retain(|sender| match sender.try_send(event.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Closed(_)) => false,
+        });
+        if senders.is_empty() {
+            connections.remove(&user_id);
+        }
+    }
+
+    fn subscribe(&self, user_id: Uuid) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel(CONNECTION_BUFFER);
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        // connections of users nothing is published to are only cleaned up here
+        connections.retain(|_, senders| {
+            senders.retain(|sender| !sender.is_closed());
+            !senders.is_empty()
+        });
+        connections.entry(user_id).or_default().push(sender);
+        receiver
+    }
+}
+
+/// picks the broadcaster with `REALTIME_BROADCASTER`. only `memory` (default) for now
+pub fn from_env() -> Arc<dyn Broadcaster> {
+    let broadcaster = env::var("REALTIME_BROADCASTER").unwrap_or_else(|_| "memory".to_string());
+    match broadcaster.as_str() {
+        "memory" => Arc::new(InProcess::default()),
+        _ => panic!("Unknown REALTIME_BROADCASTER: {}", broadcaster),
+    }
+}
+
+/// the broadcaster of this process. it's global since events are published from the domain
+/// functions, which only get a database connection
+pub fn broadcaster() -> &'static Arc<dyn Broadcaster> {
+    static BROADCASTER: OnceLock<Arc<dyn Broadcaster>> = OnceLock::new();
+    BROADCASTER.get_or_init(from_env)
+}
+
+/// shorthand for `broadcaster().publish(..)`
+pub fn publish(user_id: Uuid, event: Event) {
+    broadcaster().publish(user_id, event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_connections_are_forgotten_on_subscribe() {
+        let broadcaster = InProcess::default();
+        let user_id = Uuid::new_v4();
+        drop(broadcaster.subscribe(user_id));
+        drop(broadcaster.subscribe(user_id));
+
+        let _receiver = broadcaster.subscribe(Uuid::new_v4());
+        let connections = broadcaster.connections.lock().unwrap();
+        assert!(!connections.contains_key(&user_id));
+        assert_eq!(connections.len(), 1);
+    }
+
+    #[test]
+    fn events_reach_every_connection_of_the_user() {
+        let broadcaster = InProcess::default();
+        let user_id = Uuid::new_v4();
+        let mut first = broadcaster.subscribe(user_id);
+        let mut second = broadcaster.subscribe(user_id);
+        let mut other = broadcaster.subscribe(Uuid::new_v4());
+
+        broadcaster.publish(user_id, Event::Unread { unread: 3 });
+        assert!(matches!(first.try_recv(), Ok(Event::Unread { unread: 3 })));
+        assert!(matches!(second.try_recv(), Ok(Event::Unread { unread: 3 })));
+        assert!(other.try_recv().is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// ratings left by both sides of an accepted exchange, summed up into each user's reputation
+
+use std::{collections::HashMap, fmt};
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    dsl::{count, exists, not},
+    BoolExpressionMethods, ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+use uuid::Uuid;
+
+use crate::{
+    schema::{exchanges, reviews, users},
+    DbConn,
+};
+
+pub const RATING_MIN: i16 = 1;
+pub const RATING_MAX: i16 = 5;
+pub const COMMENT_MAX_LENGTH: usize = 500;
+
+/// ratings received by a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reputation {
+    pub total: i64,
+    pub count: i64,
+}
+
+impl Reputation {
+    pub fn average(&self) -> f64 {
+        self.total as f64 / self.count as f64
+    }
+}
+
+impl fmt::Display for Reputation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // decimal comma, as it's written in portuguese
+        let average = format!("{:.1}", self.average()).replace('.', ",");
+        write!(f, "★ {} ({})", average, self.count)
+    }
+}
+
+/// reputation of each user that received at least one review
+pub fn reputations(conn: &mut DbConn, user_ids: &[Uuid]) -> QueryResult<HashMap<Uuid, Reputation>> {
+    Ok(reviews::table
+        .filter(reviews::reviewed_id.eq_any(user_ids))
+        .group_by(reviews::reviewed_id)
+        .select((
+            reviews::reviewed_id,
+            diesel::dsl::sum(reviews::rating),
+            count(reviews::id),
+        ))
+        .load::<(Uuid, Option<i64>, i64)>(conn)?
+        .into_iter()
+        .map(|(user_id, total, count)| {
+            (
+                user_id,
+                Reputation {
+                    total: total.unwrap_or(0),
+                    count,
+                },
+            )
+        })
+        .collect())
+}
+
+/// `None` while the user has no reviews
+pub fn reputation(conn: &mut DbConn, user_id: Uuid) -> QueryResult<Option<Reputation>> {
+    Ok(reputations(conn, &[user_id])?.remove(&user_id))
+}
+
+/// a review as shown on profiles
+pub struct Review {
+    pub id: Uuid,
+    pub reviewer: String,
+    pub rating: i16,
+    pub comment: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// latest reviews received by the user
+pub fn received(conn: &mut DbConn, user_id: Uuid, limit: i64) -> QueryResult<Vec<Review>> {
+    Ok(reviews::table
+        .inner_join(users::table.on(reviews::reviewer_id.eq(users::id)))
+        .filter(reviews::reviewed_id.eq(user_id))
+        .order_by(reviews::created_at.desc())
+        .limit(limit)
+        .select((
+            reviews::id,
+            users::nickname,
+            reviews::rating,
+            reviews::comment,
+            reviews::created_at,
+        ))
+        .load::<(Uuid, String, i16, String, DateTime<Utc>)>(conn)?
+        .into_iter()
+        .map(|(id, reviewer, rating, comment, created_at)| Review {
+            id,
+            reviewer,
+            rating,
+            comment,
+            created_at,
+        })
+        .collect())
+}
+
+/// an exchange the user took part in and didn't review yet
+pub struct PendingReview {
+    pub exchange_id: Uuid,
+    pub listing_title: String,
+    pub other: String,
+}
+
+/// accepted exchanges the user still can review, newest first
+pub fn pending(conn: &mut DbConn, user_id: Uuid) -> QueryResult<Vec<PendingReview>> {
+    let rows = exchanges::table
+        .filter(
+            exchanges::owner_id
+                .eq(user_id)
+                .or(exchanges::partner_id.eq(user_id)),
+        )
+        .filter(exchanges::accepted_at.is_not_null())
+        .filter(not(exists(
+            reviews::table.filter(
+                reviews::exchange_id
+                    .eq(exchanges::id)
+                    .and(reviews::reviewer_id.eq(user_id)),
+            ),
+        )))
+        .order_by(exchanges::completed_at.desc())
+        .select((
+            exchanges::id,
+            exchanges::listing_title,
+            exchanges::owner_id,
+            exchanges::partner_id,
+        ))
+        .load::<(Uuid, String, Uuid, Uuid)>(conn)?;
+
+    let other_ids: Vec<Uuid> = rows
+        .iter()
+        .map(|(_, _, owner_id, partner_id)| {
+            if *owner_id == user_id {
+                *partner_id
+            } else {
+                *owner_id
+            }
+        })
+        .collect();
+    let nicknames: HashMap<Uuid, String> = users::table
+        .filter(users::id.eq_any(&other_ids))
+        .select((users::id, users::nickname))
+        .load::<(Uuid, String)>(conn)?
+        .into_iter()
+        .collect();
+
+    Ok(rows
+        .into_iter()
+        .zip(other_ids)
+        .filter_map(|((exchange_id, listing_title, _, _), other_id)| {
+            Some(PendingReview {
+                exchange_id,
+                listing_title,
+                other: nicknames.get(&other_id)?.clone(),
+            })
+        })
+        .collect())
+}
+
+/// records the reviewer's rating of the other side of the exchange. returns `false` when the
+/// reviewer didn't take part in it, it wasn't accepted yet or it was already reviewed
+pub fn submit(
+    conn: &mut DbConn,
+    exchange_id: Uuid,
+    reviewer_id: Uuid,
+    rating: i16,
+    comment: &str,
+) -> QueryResult<bool> {
+    let Some((owner_id, partner_id)) = exchanges::table
+        .find(exchange_id)
+        .filter(exchanges::accepted_at.is_not_null())
+        .select((exchanges::owner_id, exchanges::partner_id))
+        .first::<(Uuid, Uuid)>(conn)
+        .optional()?
+    else {
+        return Ok(false);
+    };
+    let reviewed_id = if reviewer_id == owner_id {
+        partner_id
+    } else if reviewer_id == partner_id {
+        owner_id
+    } else {
+        return Ok(false);
+    };
+
+    let inserted = diesel::insert_into(reviews::table)
+        .values((
+            reviews::id.eq(Uuid::new_v4()),
+            reviews::exchange_id.eq(exchange_id),
+            reviews::reviewer_id.eq(reviewer_id),
+            reviews::reviewed_id.eq(reviewed_id),
+            reviews::rating.eq(rating),
+            reviews::comment.eq(comment),
+        ))
+        .on_conflict((reviews::exchange_id, reviews::reviewer_id))
+        .do_nothing()
+        .execute(conn)?;
+    Ok(inserted > 0)
+}
+
+/// removes a review, returning who it was about and its comment. `None` when it doesn't exist
+pub fn remove(conn: &mut DbConn, review_id: Uuid) -> QueryResult<Option<(Uuid, String)>> {
+    diesel::delete(reviews::table.find(review_id))
+        .returning((reviews::reviewed_id, reviews::comment))
+        .get_result::<(Uuid, String)>(conn)
+        .optional()
+}
@@ -0,0 +1,546 @@
+// searches users save to hear about new listings matching them, right away or in a daily
+// digest
+
+use std::{collections::HashMap, fmt, io::Write, sync::OnceLock};
+
+use chrono::Utc;
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    dsl::count_star,
+    expression::AsExpression,
+    pg::Pg,
+    serialize::{IsNull, ToSql},
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+use uuid::Uuid;
+
+use crate::{
+    env_or, exchanges,
+    mail::send_notification_email,
+    notifications::{self, NotificationKind},
+    schema::{
+        job_runs, listings, saved_search_matches, saved_searches, sql_types::SearchFrequency, users,
+    },
+    AccountStatus, Campus, DbConn, DbPool, Type,
+};
+
+pub const MAX_SEARCHES: i64 = 10;
+pub const KEYWORDS_MAX_LENGTH: usize = 255;
+// name of the digest in `job_runs`
+const DIGEST_JOB: &str = "saved_search_digest";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromSqlRow, AsExpression)]
+#[diesel(sql_type = SearchFrequency)]
+pub enum Frequency {
+    Immediate,
+    Daily,
+}
+
+impl Frequency {
+    pub const ALL: [Frequency; 2] = [Frequency::Immediate, Frequency::Daily];
+
+    /// value used in forms
+    pub fn code(&self) -> &'static str {
+        match self {
+            Frequency::Immediate => "imediato",
+            Frequency::Daily => "diario",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|frequency| frequency.code() == code)
+    }
+}
+
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frequency::Immediate => write!(f, "A cada novo item"),
+            Frequency::Daily => write!(f, "Resumo diário"),
+        }
+    }
+}
+
+impl ToSql<SearchFrequency, Pg> for Frequency {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        match *self {
+            Frequency::Immediate => out.write_all(b"IMMEDIATE")?,
+            Frequency::Daily => out.write_all(b"DAILY")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<SearchFrequency, Pg> for Frequency {
+    fn from_sql(
+        bytes: <Pg as diesel::backend::Backend>::RawValue<'_>,
+    ) -> diesel::deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"IMMEDIATE" => Ok(Frequency::Immediate),
+            b"DAILY" => Ok(Frequency::Daily),
+            _ => Err("Unknown search frequency".into()),
+        }
+    }
+}
+
+// lowercase and without accents, so "Cálculo" is found by "calculo"
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'á' | 'à' | 'â' | 'ã' | 'ä' => 'a',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'í' | 'ì' | 'î' | 'ï' => 'i',
+            'ó' | 'ò' | 'ô' | 'õ' | 'ö' => 'o',
+            'ú' | 'ù' | 'û' | 'ü' => 'u',
+            'ç' => 'c',
+            c => c,
+        })
+        .collect()
+}
+
+pub struct SavedSearch {
+    pub id: Uuid,
+    pub keywords: String,
+    pub campus: Option<Campus>,
+    pub listing_type: Option<Type>,
+    pub frequency: Frequency,
+}
+
+impl SavedSearch {
+    /// every keyword has to be in the title or the description, and the filters that are set
+    /// have to be the listing's
+    pub fn matches(
+        &self,
+        title: &str,
+        description: &str,
+        campus: Campus,
+        listing_type: Type,
+    ) -> bool {
+        if self.campus.is_some_and(|c| c != campus)
+            || self.listing_type.is_some_and(|t| t != listing_type)
+        {
+            return false;
+        }
+        let text = normalize(&format!("{}\n{}", title, description));
+        normalize(&self.keywords)
+            .split_whitespace()
+            .all(|keyword| text.contains(keyword))
+    }
+}
+
+type SearchRow = (Uuid, String, Option<Campus>, Option<Type>, Frequency);
+
+fn from_row((id, keywords, campus, listing_type, frequency): SearchRow) -> SavedSearch {
+    SavedSearch {
+        id,
+        keywords,
+        campus,
+        listing_type,
+        frequency,
+    }
+}
+
+/// the user's searches, oldest first
+pub fn list(conn: &mut DbConn, user_id: Uuid) -> QueryResult<Vec<SavedSearch>> {
+    Ok(saved_searches::table
+        .filter(saved_searches::user_id.eq(user_id))
+        .order_by(saved_searches::created_at.asc())
+        .select((
+            saved_searches::id,
+            saved_searches::keywords,
+            saved_searches::campus,
+            saved_searches::listing_type,
+            saved_searches::frequency,
+        ))
+        .load::<SearchRow>(conn)?
+        .into_iter()
+        .map(from_row)
+        .collect())
+}
+
+/// saves a search for the user. returns `false` when they already have `MAX_SEARCHES`
+pub fn create(
+    conn: &mut DbConn,
+    user_id: Uuid,
+    keywords: &str,
+    campus: Option<Campus>,
+    listing_type: Option<Type>,
+    frequency: Frequency,
+) -> QueryResult<bool> {
+    let existing = saved_searches::table
+        .filter(saved_searches::user_id.eq(user_id))
+        .select(count_star())
+        .get_result::<i64>(conn)?;
+    if existing >= MAX_SEARCHES {
+        return Ok(false);
+    }
+
+    diesel::insert_into(saved_searches::table)
+        .values((
+            saved_searches::id.eq(Uuid::new_v4()),
+            saved_searches::user_id.eq(user_id),
+            saved_searches::keywords.eq(keywords),
+            saved_searches::campus.eq(campus),
+            saved_searches::listing_type.eq(listing_type),
+            saved_searches::frequency.eq(frequency),
+        ))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// removes one of the user's searches, along with the matches waiting for its digest
+pub fn delete(conn: &mut DbConn, user_id: Uuid, search_id: Uuid) -> QueryResult<bool> {
+    conn.transaction(|conn| {
+        let owned = saved_searches::table
+            .filter(
+                saved_searches::id
+                    .eq(search_id)
+                    .and(saved_searches::user_id.eq(user_id)),
+            )
+            .select(saved_searches::id)
+            .first::<Uuid>(conn)
+            .optional()?;
+        if owned.is_none() {
+            return Ok(false);
+        }
+
+        diesel::delete(
+            saved_search_matches::table.filter(saved_search_matches::search_id.eq(search_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(saved_searches::table.find(search_id)).execute(conn)?;
+        Ok(true)
+    })
+}
+
+/// a new listing found by someone's search
+pub struct Match {
+    pub user_id: Uuid,
+    pub nickname: String,
+    pub keywords: String,
+    pub listing_id: Uuid,
+    pub listing_title: String,
+}
+
+/// checks a new listing against everyone else's searches. matches of daily searches are kept
+/// for the next digest, the ones to be sent right away are returned
+pub fn match_listing(conn: &mut DbConn, listing_id: Uuid) -> QueryResult<Vec<Match>> {
+    let Some((title, description, campus, listing_type, creator_id)) = listings::table
+        .find(listing_id)
+        .filter(listings::hidden.eq(false))
+        .select((
+            listings::title,
+            listings::description,
+            listings::campus,
+            listings::type_,
+            listings::creator_id,
+        ))
+        .first::<(String, String, Campus, Type, Uuid)>(conn)
+        .optional()?
+    else {
+        return Ok(vec![]);
+    };
+
+    // the filters are checked here, the keywords only after loading
+    let candidates = saved_searches::table
+        .inner_join(users::table)
+        .filter(saved_searches::user_id.ne(creator_id))
+        .filter(users::status.eq(AccountStatus::CONFIRMED))
+        .filter(
+            saved_searches::campus
+                .is_null()
+                .or(saved_searches::campus.eq(campus)),
+        )
+        .filter(
+            saved_searches::listing_type
+                .is_null()
+                .or(saved_searches::listing_type.eq(listing_type)),
+        )
+        .select((
+            (
+                saved_searches::id,
+                saved_searches::keywords,
+                saved_searches::campus,
+                saved_searches::listing_type,
+                saved_searches::frequency,
+            ),
+            users::id,
+            users::nickname,
+        ))
+        .load::<(SearchRow, Uuid, String)>(conn)?;
+
+    let mut immediate = vec![];
+    let mut notified = vec![];
+    for (row, user_id, nickname) in candidates {
+        let search = from_row(row);
+        if !search.matches(&title, &description, campus, listing_type) {
+            continue;
+        }
+        match search.frequency {
+            Frequency::Daily => {
+                diesel::insert_into(saved_search_matches::table)
+                    .values((
+                        saved_search_matches::search_id.eq(search.id),
+                        saved_search_matches::listing_id.eq(listing_id),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+            // one email per user, even when several of their searches match
+            Frequency::Immediate if !notified.contains(&user_id) => {
+                notified.push(user_id);
+                immediate.push(Match {
+                    user_id,
+                    nickname,
+                    keywords: search.keywords,
+                    listing_id,
+                    listing_title: title.clone(),
+                });
+            }
+            Frequency::Immediate => {}
+        }
+    }
+    Ok(immediate)
+}
+
+/// notifies every match, in the app and by email. failures are only logged, the listing was
+/// already published
+pub async fn send_matches(conn: &mut DbConn, mg_api_key: &str, matches: &[Match]) {
+    for found in matches {
+        let listing_id = found.listing_id.to_string();
+        if let Err(e) = notifications::notify(
+            conn,
+            found.user_id,
+            NotificationKind::SavedSearch,
+            &format!(
+                "Novo item para \"{}\": {}",
+                found.keywords, found.listing_title
+            ),
+            &format!("/item/{}", listing_id),
+        ) {
+            log::error!("Não foi possível notificar {}: {:?}", found.nickname, e);
+        }
+
+        if send_notification_email(
+            conn,
+            mg_api_key,
+            found.user_id,
+            NotificationKind::SavedSearch,
+            "busca salva",
+            &format!("Novo item para \"{}\"", found.keywords),
+            &[
+                ("nickname", found.nickname.as_str()),
+                ("keywords", found.keywords.as_str()),
+                ("title", found.listing_title.as_str()),
+                ("listing_id", listing_id.as_str()),
+            ],
+        )
+        .await
+        .is_err()
+        {
+            log::error!(
+                "Não foi possível avisar {} sobre o item {}",
+                found.nickname,
+                found.listing_id
+            );
+        }
+    }
+}
+
+/// checks a listing that was just published against the saved searches and sends the
+/// immediate matches. runs in the background with its own connection, so the request doesn't
+/// wait on the emails. failures are only logged, the listing was already published
+pub fn announce_listing(pool: DbPool, mg_api_key: String, listing_id: Uuid) {
+    actix_web::rt::spawn(async move {
+        let Ok(mut conn) = pool.get() else {
+            log::error!(
+                "Não foi possível conectar ao banco de dados para anunciar o item {}",
+                listing_id
+            );
+            return;
+        };
+        match match_listing(&mut conn, listing_id) {
+            Ok(matches) => send_matches(&mut conn, &mg_api_key, &matches).await,
+            Err(e) => log::error!(
+                "Não foi possível verificar as buscas salvas para o item {}: {:?}",
+                listing_id,
+                e
+            ),
+        }
+    });
+}
+
+/// the hour of the day (UTC) the digest goes out at (`SAVED_SEARCH_DIGEST_HOUR`), 11 by
+/// default, 8 in Brasília
+pub fn digest_hour() -> u32 {
+    static HOUR: OnceLock<u32> = OnceLock::new();
+    *HOUR.get_or_init(|| {
+        let hour = env_or("SAVED_SEARCH_DIGEST_HOUR", 11);
+        assert!(hour < 24, "SAVED_SEARCH_DIGEST_HOUR has an invalid value");
+        hour
+    })
+}
+
+/// whether today's digest is due and wasn't sent yet, marking it as sent when so. only one
+/// instance gets `true` a day, even across restarts
+pub fn claim_digest(conn: &mut DbConn) -> QueryResult<bool> {
+    let now = Utc::now();
+    let scheduled = now
+        .date_naive()
+        .and_hms_opt(digest_hour(), 0, 0)
+        .expect("digest_hour is checked to be a valid hour")
+        .and_utc();
+    if now < scheduled {
+        return Ok(false);
+    }
+
+    // postgres checks the condition again once the row is unlocked, only one update succeeds
+    let claimed = diesel::update(
+        job_runs::table
+            .filter(job_runs::name.eq(DIGEST_JOB))
+            .filter(job_runs::last_run_at.lt(scheduled)),
+    )
+    .set(job_runs::last_run_at.eq(now))
+    .execute(conn)?;
+    if claimed > 0 {
+        return Ok(true);
+    }
+    // the first digest ever, or today's was already sent
+    let claimed = diesel::insert_into(job_runs::table)
+        .values((job_runs::name.eq(DIGEST_JOB), job_runs::last_run_at.eq(now)))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(claimed > 0)
+}
+
+/// the listings matched by a user's daily searches since the last digest
+pub struct Digest {
+    pub user_id: Uuid,
+    pub nickname: String,
+    pub listings: Vec<(Uuid, String)>,
+}
+
+/// collects the queued matches into one digest per user and clears the queue. listings that
+/// were hidden or completed in the meantime are left out
+pub fn take_digests(conn: &mut DbConn) -> QueryResult<Vec<Digest>> {
+    conn.transaction(|conn| {
+        let rows = saved_search_matches::table
+            .inner_join(saved_searches::table.inner_join(users::table))
+            .inner_join(listings::table)
+            .filter(listings::hidden.eq(false))
+            .filter(exchanges::is_active())
+            .filter(users::status.eq(AccountStatus::CONFIRMED))
+            .order_by((users::id, saved_search_matches::created_at.asc()))
+            .select((users::id, users::nickname, listings::id, listings::title))
+            .load::<(Uuid, String, Uuid, String)>(conn)?;
+        diesel::delete(saved_search_matches::table).execute(conn)?;
+
+        let mut digests: HashMap<Uuid, Digest> = HashMap::new();
+        for (user_id, nickname, listing_id, title) in rows {
+            let digest = digests.entry(user_id).or_insert_with(|| Digest {
+                user_id,
+                nickname,
+                listings: vec![],
+            });
+            // the same listing can match more than one of the user's searches
+            if !digest.listings.iter().any(|(id, _)| *id == listing_id) {
+                digest.listings.push((listing_id, title));
+            }
+        }
+        Ok(digests.into_values().collect())
+    })
+}
+
+/// notifies the digests, in the app and by email. failures are only logged, the matches
+/// aren't sent again
+pub async fn send_digests(conn: &mut DbConn, mg_api_key: &str, digests: &[Digest]) {
+    for digest in digests {
+        let count = digest.listings.len().to_string();
+        let message = match digest.listings.len() {
+            1 => "1 novo item para as suas buscas salvas".to_string(),
+            n => format!("{} novos itens para as suas buscas salvas", n),
+        };
+        if let Err(e) = notifications::notify(
+            conn,
+            digest.user_id,
+            NotificationKind::SavedSearch,
+            &message,
+            "/buscas",
+        ) {
+            log::error!("Não foi possível notificar {}: {:?}", digest.nickname, e);
+        }
+
+        let listings = digest
+            .listings
+            .iter()
+            .map(|(_, title)| format!("• {}", title))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if send_notification_email(
+            conn,
+            mg_api_key,
+            digest.user_id,
+            NotificationKind::SavedSearch,
+            "resumo de buscas salvas",
+            "Novos itens para as suas buscas salvas",
+            &[
+                ("nickname", digest.nickname.as_str()),
+                ("count", count.as_str()),
+                ("listings", listings.as_str()),
+            ],
+        )
+        .await
+        .is_err()
+        {
+            log::error!(
+                "Não foi possível enviar o resumo de buscas salvas para {}",
+                digest.nickname
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(keywords: &str, campus: Option<Campus>, listing_type: Option<Type>) -> SavedSearch {
+        SavedSearch {
+            id: Uuid::new_v4(),
+            keywords: keywords.to_string(),
+            campus,
+            listing_type,
+            frequency: Frequency::Immediate,
+        }
+    }
+
+    #[test]
+    fn every_keyword_has_to_match() {
+        let search = search("livro cálculo", None, None);
+        assert!(search.matches("Livro de Cálculo 1", "", Campus::Gama, Type::Donation));
+        // the keywords can be split between the title and the description
+        assert!(search.matches("Livro", "Cálculo 1", Campus::Gama, Type::Donation));
+        assert!(!search.matches("Livro de física", "", Campus::Gama, Type::Donation));
+    }
+
+    #[test]
+    fn keywords_ignore_accents_and_case() {
+        assert!(search("CALCULO", None, None).matches("Cálculo", "", Campus::Gama, Type::Donation));
+        assert!(search("cálculo", None, None).matches("calculo", "", Campus::Gama, Type::Donation));
+    }
+
+    #[test]
+    fn filters_that_are_set_have_to_match() {
+        let search = search("livro", Some(Campus::Gama), Some(Type::Loan));
+        assert!(search.matches("Livro", "", Campus::Gama, Type::Loan));
+        assert!(!search.matches("Livro", "", Campus::Planaltina, Type::Loan));
+        assert!(!search.matches("Livro", "", Campus::Gama, Type::Donation));
+    }
+}
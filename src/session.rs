@@ -0,0 +1,444 @@
+// session configuration: signing key, where the session state lives and how long logins last
+
+use std::{collections::HashMap, env};
+
+#[cfg(feature = "redis-session")]
+use actix_session::storage::RedisSessionStore;
+use actix_session::{
+    config::PersistentSession,
+    storage::{
+        generate_session_key, CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore,
+        UpdateError,
+    },
+    Session,
+};
+use actix_web::{
+    cookie::{time, Key},
+    HttpRequest,
+};
+use chrono::{Duration, Utc};
+use diesel::{dsl::exists, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::{
+    client_ip, env_or,
+    schema::{session_states, user_sessions, users},
+    AccountStatus, DbConn, DbPool,
+};
+
+// session key entry holding the unix timestamp after which the login is no longer valid
+const EXPIRES_AT_KEY: &str = "expires_at";
+// session key entry holding the id of the row in `user_sessions` for this login
+const LOGIN_ID_KEY: &str = "login_id";
+// last seen is only written again after this many minutes, to avoid a write on every request
+const LAST_SEEN_RESOLUTION_MINUTES: i64 = 5;
+
+/// loads the cookie signing key from `SESSION_SECRET_KEY`, which must have at least 64 bytes.
+/// without it a random key is used, which logs everyone out whenever the server restarts
+pub fn secret_key() -> Key {
+    match env::var("SESSION_SECRET_KEY") {
+        Ok(secret) => Key::try_from(secret.as_bytes())
+            .expect("SESSION_SECRET_KEY must have at least 64 bytes"),
+        Err(_) => {
+            log::warn!("SESSION_SECRET_KEY não definida, usando uma chave temporária");
+            Key::generate()
+        }
+    }
+}
+
+/// how long a login lasts when "lembrar de mim" is not checked (`SESSION_TTL_HOURS`)
+pub fn session_ttl() -> Duration {
+    Duration::hours(env_or("SESSION_TTL_HOURS", 12))
+}
+
+/// how long a login lasts when "lembrar de mim" is checked (`SESSION_REMEMBER_DAYS`)
+pub fn remember_ttl() -> Duration {
+    Duration::days(env_or("SESSION_REMEMBER_DAYS", 30))
+}
+
+/// whether the session cookie is only sent over https (`COOKIE_SECURE`), only worth
+/// disabling when developing without https
+pub fn cookie_secure() -> bool {
+    env_or("COOKIE_SECURE", true)
+}
+
+/// the cookie (and the server-side state) lives as long as the longest login allowed,
+/// shorter logins are enforced by `is_expired`
+pub fn lifecycle() -> PersistentSession {
+    PersistentSession::default().session_ttl(time::Duration::seconds(remember_ttl().num_seconds()))
+}
+
+// how long a login lasts, by whether "lembrar de mim" was checked
+fn login_ttl(remember: bool) -> Duration {
+    if remember {
+        remember_ttl()
+    } else {
+        session_ttl()
+    }
+}
+
+/// sessions without an expiration have no login that can still be valid, so they count as
+/// expired too
+pub fn is_expired(session: &Session) -> bool {
+    match session.get::<i64>(EXPIRES_AT_KEY) {
+        Ok(Some(expires_at)) => expires_at < Utc::now().timestamp(),
+        Ok(None) | Err(_) => true,
+    }
+}
+
+/// records the login made in this session so it can be listed and revoked from the sessions
+/// page. must be called right after `Identity::login` since it renews the session
+pub fn start_login(
+    conn: &mut DbConn,
+    req: &HttpRequest,
+    session: &Session,
+    user_id: Uuid,
+    remember: bool,
+) -> diesel::QueryResult<Uuid> {
+    let user_agent = req
+        .headers()
+        .get("User-Agent")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("Desconhecido")
+        .chars()
+        .take(512)
+        .collect::<String>();
+    let ip = client_ip(req)
+        .unwrap_or_else(|| "Desconhecido".to_string())
+        .chars()
+        .take(64)
+        .collect::<String>();
+
+    let expires_at = Utc::now() + login_ttl(remember);
+
+    let login_id = diesel::insert_into(user_sessions::table)
+        .values((
+            user_sessions::id.eq(Uuid::new_v4()),
+            user_sessions::user_id.eq(user_id),
+            user_sessions::user_agent.eq(user_agent),
+            user_sessions::ip.eq(ip),
+            user_sessions::expires_at.eq(expires_at),
+        ))
+        .returning(user_sessions::id)
+        .get_result::<Uuid>(conn)?;
+
+    if let Err(e) = session.insert(LOGIN_ID_KEY, login_id) {
+        log::error!("Não foi possível salvar o login na sessão: {:?}", e);
+    }
+    if let Err(e) = session.insert(EXPIRES_AT_KEY, expires_at.timestamp()) {
+        log::error!("Não foi possível salvar a validade da sessão: {:?}", e);
+    }
+
+    Ok(login_id)
+}
+
+/// id of the login made in this session, if any
+pub fn login_id(session: &Session) -> Option<Uuid> {
+    session.get::<Uuid>(LOGIN_ID_KEY).ok().flatten()
+}
+
+/// checks that the login made in this session belongs to the user, wasn't revoked and didn't
+/// expire, updating when it was last seen
+pub fn touch_login(conn: &mut DbConn, session: &Session, user_id: Uuid) -> bool {
+    let Some(login_id) = login_id(session) else {
+        return false;
+    };
+
+    match diesel::update(
+        user_sessions::table
+            .filter(user_sessions::id.eq(login_id))
+            .filter(user_sessions::user_id.eq(user_id))
+            .filter(user_sessions::expires_at.gt(Utc::now()))
+            .filter(
+                user_sessions::last_seen_at
+                    .lt(Utc::now() - Duration::minutes(LAST_SEEN_RESOLUTION_MINUTES)),
+            ),
+    )
+    .set(user_sessions::last_seen_at.eq(Utc::now()))
+    .execute(conn)
+    {
+        Ok(1) => true,
+        // nothing updated: either seen recently, revoked or expired
+        Ok(_) => user_sessions::table
+            .filter(user_sessions::id.eq(login_id))
+            .filter(user_sessions::user_id.eq(user_id))
+            .filter(user_sessions::expires_at.gt(Utc::now()))
+            .select(user_sessions::id)
+            .first::<Uuid>(conn)
+            .optional()
+            .map(|login| login.is_some())
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// whether the login is still valid: not revoked, not expired and its account not disabled.
+/// for connections that stay open long after the request that checked them
+pub fn login_is_active(
+    conn: &mut DbConn,
+    login_id: Uuid,
+    user_id: Uuid,
+) -> diesel::QueryResult<bool> {
+    diesel::select(exists(
+        user_sessions::table
+            .inner_join(users::table)
+            .filter(user_sessions::id.eq(login_id))
+            .filter(user_sessions::user_id.eq(user_id))
+            .filter(user_sessions::expires_at.gt(Utc::now()))
+            .filter(users::status.ne(AccountStatus::DISABLED)),
+    ))
+    .get_result(conn)
+}
+
+/// removes the login made in this session, used when logging out
+pub fn end_login(conn: &mut DbConn, session: &Session) -> diesel::QueryResult<()> {
+    if let Some(login_id) = login_id(session) {
+        diesel::delete(user_sessions::table.filter(user_sessions::id.eq(login_id)))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// removes every login of the user, so all their sessions stop working on the next request
+pub fn end_all_logins(conn: &mut DbConn, user_id: Uuid) -> diesel::QueryResult<usize> {
+    diesel::delete(user_sessions::table.filter(user_sessions::user_id.eq(user_id))).execute(conn)
+}
+
+/// removes the logins that expired, run once a day
+pub fn purge_expired_logins(conn: &mut DbConn) -> diesel::QueryResult<usize> {
+    diesel::delete(user_sessions::table.filter(user_sessions::expires_at.lt(Utc::now())))
+        .execute(conn)
+}
+
+/// session state stored in the `session_states` table, shared by every instance using the
+/// same database
+#[derive(Clone)]
+pub struct PgSessionStore {
+    pool: DbPool,
+}
+
+impl PgSessionStore {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+}
+
+fn expiration(ttl: &time::Duration) -> chrono::DateTime<Utc> {
+    Utc::now() + Duration::seconds(ttl.whole_seconds())
+}
+
+impl SessionStore for PgSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| LoadError::Other(anyhow::anyhow!(e)))?;
+
+        let state = session_states::table
+            .filter(session_states::session_key.eq(session_key.as_ref()))
+            .filter(session_states::expires_at.gt(Utc::now()))
+            .select(session_states::state)
+            .first::<String>(&mut conn)
+            .optional()
+            .map_err(|e| LoadError::Other(anyhow::anyhow!(e)))?;
+
+        match state {
+            Some(state) => serde_json::from_str(&state)
+                .map(Some)
+                .map_err(|e| LoadError::Deserialization(anyhow::anyhow!(e))),
+            None => Ok(None),
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &time::Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| SaveError::Serialization(anyhow::anyhow!(e)))?;
+        let session_key = generate_session_key();
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| SaveError::Other(anyhow::anyhow!(e)))?;
+
+        // new sessions are a good moment to get rid of the expired ones
+        diesel::delete(session_states::table.filter(session_states::expires_at.lt(Utc::now())))
+            .execute(&mut conn)
+            .map_err(|e| SaveError::Other(anyhow::anyhow!(e)))?;
+
+        diesel::insert_into(session_states::table)
+            .values((
+                session_states::session_key.eq(session_key.as_ref()),
+                session_states::state.eq(state),
+                session_states::expires_at.eq(expiration(ttl)),
+            ))
+            .execute(&mut conn)
+            .map_err(|e| SaveError::Other(anyhow::anyhow!(e)))?;
+
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &time::Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| UpdateError::Serialization(anyhow::anyhow!(e)))?;
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| UpdateError::Other(anyhow::anyhow!(e)))?;
+
+        let updated = diesel::update(
+            session_states::table.filter(session_states::session_key.eq(session_key.as_ref())),
+        )
+        .set((
+            session_states::state.eq(&state),
+            session_states::expires_at.eq(expiration(ttl)),
+        ))
+        .execute(&mut conn)
+        .map_err(|e| UpdateError::Other(anyhow::anyhow!(e)))?;
+
+        // the state was removed in the meantime (expired or deleted), so start a new one
+        if updated == 0 {
+            return self
+                .save(session_state, ttl)
+                .await
+                .map_err(|e| UpdateError::Other(anyhow::anyhow!(e)));
+        }
+
+        Ok(session_key)
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &time::Duration,
+    ) -> Result<(), anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        diesel::update(
+            session_states::table.filter(session_states::session_key.eq(session_key.as_ref())),
+        )
+        .set(session_states::expires_at.eq(expiration(ttl)))
+        .execute(&mut conn)?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        diesel::delete(
+            session_states::table.filter(session_states::session_key.eq(session_key.as_ref())),
+        )
+        .execute(&mut conn)?;
+        Ok(())
+    }
+}
+
+/// where the session state is kept, chosen with `SESSION_STORE`:
+/// - `cookie` (default): everything in the signed cookie
+/// - `postgres`: in the `session_states` table
+/// - `redis`: in the server at `REDIS_URL`, needs the `redis-session` feature
+#[derive(Clone)]
+pub enum SessionBackend {
+    Cookie,
+    Postgres(PgSessionStore),
+    #[cfg(feature = "redis-session")]
+    Redis(RedisSessionStore),
+}
+
+impl SessionBackend {
+    pub async fn from_env(pool: DbPool) -> Self {
+        let store = env::var("SESSION_STORE").unwrap_or_else(|_| "cookie".to_string());
+        match store.as_str() {
+            "cookie" => SessionBackend::Cookie,
+            "postgres" => SessionBackend::Postgres(PgSessionStore::new(pool)),
+            #[cfg(feature = "redis-session")]
+            "redis" => {
+                let redis_url = env::var("REDIS_URL").expect("REDIS_URL must be set");
+                let store = RedisSessionStore::new(redis_url)
+                    .await
+                    .expect("Failed to connect to redis");
+                SessionBackend::Redis(store)
+            }
+            _ => panic!("Unknown SESSION_STORE: {}", store),
+        }
+    }
+}
+
+impl SessionStore for SessionBackend {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().load(session_key).await,
+            SessionBackend::Postgres(store) => store.load(session_key).await,
+            #[cfg(feature = "redis-session")]
+            SessionBackend::Redis(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &time::Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().save(session_state, ttl).await,
+            SessionBackend::Postgres(store) => store.save(session_state, ttl).await,
+            #[cfg(feature = "redis-session")]
+            SessionBackend::Redis(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &time::Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            SessionBackend::Cookie => {
+                CookieSessionStore::default()
+                    .update(session_key, session_state, ttl)
+                    .await
+            }
+            SessionBackend::Postgres(store) => store.update(session_key, session_state, ttl).await,
+            #[cfg(feature = "redis-session")]
+            SessionBackend::Redis(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &time::Duration,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Cookie => {
+                CookieSessionStore::default()
+                    .update_ttl(session_key, ttl)
+                    .await
+            }
+            SessionBackend::Postgres(store) => store.update_ttl(session_key, ttl).await,
+            #[cfg(feature = "redis-session")]
+            SessionBackend::Redis(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Cookie => CookieSessionStore::default().delete(session_key).await,
+            SessionBackend::Postgres(store) => store.delete(session_key).await,
+            #[cfg(feature = "redis-session")]
+            SessionBackend::Redis(store) => store.delete(session_key).await,
+        }
+    }
+}
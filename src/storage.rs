@@ -0,0 +1,121 @@
+use actix_multipart::form::tempfile::TempFile;
+use aws_sdk_s3::{primitives::ByteStream, Client};
+use diesel::{ExpressionMethods, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::{schema::attachments, DbConn};
+
+pub const BUCKET: &str = "coisandocoisas";
+
+/// key of an attachment in the bucket, images are grouped by uploader
+pub fn attachment_key(user_id: Uuid, attachment_id: Uuid) -> String {
+    format!("{}/{}", user_id, attachment_id)
+}
+
+/// address the attachment is served from, which redirects to a short-lived link to the bucket
+pub fn attachment_url(user_id: Uuid, attachment_id: Uuid) -> String {
+    format!("/attachments/{}/{}", user_id, attachment_id)
+}
+
+/// uploads an image of the listing and records it, returning its id. failures are only
+/// logged, the listing is kept without the image
+pub async fn upload_attachment(
+    s3_client: &Client,
+    conn: &mut DbConn,
+    uploader_id: Uuid,
+    listing_id: Uuid,
+    image: TempFile,
+) -> Option<Uuid> {
+    let img_id = Uuid::new_v4();
+    let path = format!("/tmp/{}", img_id);
+
+    // persist image file
+    if let Err(e) = image.file.persist(&path) {
+        log::error!(
+            "Não foi possível salvar o anexo {} no item {}: {:?}",
+            img_id,
+            listing_id,
+            e
+        );
+        return None;
+    }
+
+    // create ByteStream from path
+    let stream = match ByteStream::from_path(&path).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::error!(
+                "Não foi possível criar ByteStream para o anexo {} no item {}: {:?}",
+                img_id,
+                listing_id,
+                e
+            );
+            return None;
+        }
+    };
+
+    // upload image using s3 sdk
+    if let Err(e) = s3_client
+        .put_object()
+        .bucket(BUCKET)
+        .key(attachment_key(uploader_id, img_id))
+        .body(stream)
+        .send()
+        .await
+    {
+        log::error!(
+            "Não foi possível enviar o anexo {} do item {}: {:?}",
+            img_id,
+            listing_id,
+            e
+        );
+        return None;
+    }
+
+    // insert attachment into database
+    if let Err(e) = diesel::insert_into(attachments::table)
+        .values((
+            attachments::id.eq(img_id),
+            attachments::listing_id.eq(listing_id),
+        ))
+        .execute(conn)
+    {
+        log::error!(
+            "Não foi possível inserir informações do anexo {}, item {} no banco de dados: {:?}",
+            img_id,
+            listing_id,
+            e
+        );
+        return None;
+    }
+
+    Some(img_id)
+}
+
+/// removes the given attachments from the bucket, returning how many could not be removed.
+/// failures are only logged, the database rows are already gone by the time this is called
+pub async fn delete_attachments(
+    s3_client: &Client,
+    user_id: Uuid,
+    attachment_ids: &[Uuid],
+) -> usize {
+    let mut failures = 0;
+    for attachment_id in attachment_ids {
+        if let Err(e) = s3_client
+            .delete_object()
+            .bucket(BUCKET)
+            .key(attachment_key(user_id, *attachment_id))
+            .send()
+            .await
+        {
+            log::error!(
+                "Não foi possível remover o anexo {} do usuário {}: {:?}",
+                attachment_id,
+                user_id,
+                e
+            );
+            failures += 1;
+        }
+    }
+    failures
+}
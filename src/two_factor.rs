@@ -0,0 +1,271 @@
+// optional second step at login with an authenticator app (TOTP), plus single use recovery
+// codes for when the app is lost
+
+use actix_session::Session;
+use chrono::Utc;
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+use qrcode::{render::svg, QrCode};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::{
+    schema::{recovery_codes, totp_credentials},
+    DbConn,
+};
+
+// shown as the account's name in the authenticator app, must not contain ':'
+const ISSUER: &str = "Coisando Coisas";
+/// how many recovery codes are issued when 2FA is enabled
+pub const RECOVERY_CODE_COUNT: usize = 10;
+// session key entry holding the user that typed the right password but still has to send a code
+const PENDING_LOGIN_KEY: &str = "pending_2fa_login";
+// how long the code can be sent for after the password was accepted
+const PENDING_LOGIN_EXPIRATION_SECONDS: i64 = 300;
+// seconds each code is valid for, and how many periods before and after the current one are
+// accepted for clocks that are a bit off
+const TOTP_STEP: u64 = 30;
+const TOTP_SKEW: i64 = 1;
+
+/// a new random secret, base32-encoded as authenticator apps expect
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+fn totp(secret: &str, nickname: &str) -> Option<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        TOTP_STEP,
+        bytes,
+        Some(ISSUER.to_string()),
+        nickname.to_string(),
+    )
+    .ok()
+}
+
+/// the `otpauth://` url rendered as an svg QR code, ready to be put in the page
+pub fn qr_code_svg(secret: &str, nickname: &str) -> Option<String> {
+    let url = totp(secret, nickname)?.get_url();
+    let code = QrCode::new(url.as_bytes()).ok()?;
+    Some(
+        code.render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .quiet_zone(true)
+            .build(),
+    )
+}
+
+// the time step the code belongs to, checking the periods around the one of `time`
+fn totp_step(secret: &str, code: &str, time: u64) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let totp = totp(secret, "")?;
+    let current = (time / TOTP_STEP) as i64;
+    (current - TOTP_SKEW..=current + TOTP_SKEW)
+        .find(|step| totp.check(&code, *step as u64 * TOTP_STEP))
+}
+
+/// checks a 6 digit code against the secret, accepting the previous and next periods too.
+/// returns the time step it belongs to, so it isn't accepted again
+pub fn verify_totp(secret: &str, code: &str) -> Option<i64> {
+    totp_step(secret, code, Utc::now().timestamp().try_into().ok()?)
+}
+
+/// turns 2FA on once the first code was checked, which can't be used again to log in
+pub fn enable(conn: &mut DbConn, user_id: Uuid, step: i64) -> QueryResult<()> {
+    diesel::update(totp_credentials::table.find(user_id))
+        .set((
+            totp_credentials::enabled.eq(true),
+            totp_credentials::last_used_step.eq(step),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+// codes are compared without dashes, spaces or case, so they can be typed however
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+/// the secret of the user, along with whether it was already confirmed
+pub fn credentials(conn: &mut DbConn, user_id: Uuid) -> QueryResult<Option<(String, bool)>> {
+    totp_credentials::table
+        .find(user_id)
+        .select((totp_credentials::secret, totp_credentials::enabled))
+        .first::<(String, bool)>(conn)
+        .optional()
+}
+
+/// whether the login has to go through the second step
+pub fn is_enabled(conn: &mut DbConn, user_id: Uuid) -> QueryResult<bool> {
+    Ok(matches!(credentials(conn, user_id)?, Some((_, true))))
+}
+
+/// how many recovery codes are still unused
+pub fn remaining_recovery_codes(conn: &mut DbConn, user_id: Uuid) -> QueryResult<i64> {
+    recovery_codes::table
+        .filter(recovery_codes::user_id.eq(user_id))
+        .filter(recovery_codes::used_at.is_null())
+        .count()
+        .get_result(conn)
+}
+
+/// replaces the user's recovery codes with new ones, returned in clear text to be shown once
+pub fn replace_recovery_codes(conn: &mut DbConn, user_id: Uuid) -> QueryResult<Vec<String>> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let random = Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &random[..5], &random[5..10])
+        })
+        .collect();
+
+    conn.transaction(|conn| {
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+            .execute(conn)?;
+        let rows: Vec<_> = codes
+            .iter()
+            .map(|code| {
+                (
+                    recovery_codes::id.eq(Uuid::new_v4()),
+                    recovery_codes::user_id.eq(user_id),
+                    recovery_codes::code_hash.eq(hash_recovery_code(code)),
+                )
+            })
+            .collect();
+        diesel::insert_into(recovery_codes::table)
+            .values(&rows)
+            .execute(conn)?;
+        Ok::<_, diesel::result::Error>(())
+    })?;
+
+    Ok(codes)
+}
+
+/// checks the code sent in the second step of the login: either the current TOTP code or an
+/// unused recovery code, which is then spent. a TOTP code is only accepted once, and never
+/// one older than the last accepted
+pub fn verify_code(conn: &mut DbConn, user_id: Uuid, code: &str) -> QueryResult<bool> {
+    let Some((secret, true)) = credentials(conn, user_id)? else {
+        return Ok(false);
+    };
+    if let Some(step) = verify_totp(&secret, code) {
+        let accepted = diesel::update(
+            totp_credentials::table.find(user_id).filter(
+                totp_credentials::last_used_step
+                    .is_null()
+                    .or(totp_credentials::last_used_step.lt(step)),
+            ),
+        )
+        .set(totp_credentials::last_used_step.eq(step))
+        .execute(conn)?;
+        return Ok(accepted > 0);
+    }
+
+    let used = diesel::update(
+        recovery_codes::table
+            .filter(recovery_codes::user_id.eq(user_id))
+            .filter(recovery_codes::code_hash.eq(hash_recovery_code(code)))
+            .filter(recovery_codes::used_at.is_null()),
+    )
+    .set(recovery_codes::used_at.eq(Utc::now()))
+    .execute(conn)?;
+    Ok(used > 0)
+}
+
+/// turns 2FA off, forgetting the secret and the recovery codes
+pub fn disable(conn: &mut DbConn, user_id: Uuid) -> QueryResult<()> {
+    conn.transaction(|conn| {
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+            .execute(conn)?;
+        diesel::delete(totp_credentials::table.filter(totp_credentials::user_id.eq(user_id)))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// remembers that the password was accepted, so the second step knows who is logging in
+pub fn start_pending_login(session: &Session, user_id: Uuid, remember: bool) {
+    if let Err(e) = session.insert(
+        PENDING_LOGIN_KEY,
+        (user_id, remember, Utc::now().timestamp()),
+    ) {
+        log::error!(
+            "Não foi possível salvar o login pendente na sessão: {:?}",
+            e
+        );
+    }
+}
+
+/// the user waiting for the second step and whether they asked to be remembered
+pub fn pending_login(session: &Session) -> Option<(Uuid, bool)> {
+    let (user_id, remember, started_at) = session
+        .get::<(Uuid, bool, i64)>(PENDING_LOGIN_KEY)
+        .ok()
+        .flatten()?;
+    if started_at + PENDING_LOGIN_EXPIRATION_SECONDS < Utc::now().timestamp() {
+        session.remove(PENDING_LOGIN_KEY);
+        return None;
+    }
+    Some((user_id, remember))
+}
+
+pub fn end_pending_login(session: &Session) {
+    session.remove(PENDING_LOGIN_KEY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // any time well past the epoch, in the middle of a period
+    const NOW: u64 = 1_800_000_015;
+
+    fn code_at(secret: &str, time: u64) -> String {
+        totp(secret, "").unwrap().generate(time)
+    }
+
+    #[test]
+    fn code_is_matched_to_its_step() {
+        let secret = generate_secret();
+        let step = (NOW / TOTP_STEP) as i64;
+        assert_eq!(totp_step(&secret, &code_at(&secret, NOW), NOW), Some(step));
+        // spaces typed along with the code are ignored
+        let code = code_at(&secret, NOW);
+        let spaced = format!("{} {}", &code[..3], &code[3..]);
+        assert_eq!(totp_step(&secret, &spaced, NOW), Some(step));
+    }
+
+    #[test]
+    fn neighbouring_periods_are_accepted() {
+        let secret = generate_secret();
+        let step = (NOW / TOTP_STEP) as i64;
+        let previous = code_at(&secret, NOW - TOTP_STEP);
+        let next = code_at(&secret, NOW + TOTP_STEP);
+        assert_eq!(totp_step(&secret, &previous, NOW), Some(step - 1));
+        assert_eq!(totp_step(&secret, &next, NOW), Some(step + 1));
+    }
+
+    #[test]
+    fn codes_outside_the_skew_are_refused() {
+        let secret = generate_secret();
+        let old = code_at(&secret, NOW - 2 * TOTP_STEP);
+        // two different periods can share a code by chance
+        if old != code_at(&secret, NOW)
+            && old != code_at(&secret, NOW - TOTP_STEP)
+            && old != code_at(&secret, NOW + TOTP_STEP)
+        {
+            assert_eq!(totp_step(&secret, &old, NOW), None);
+        }
+        assert_eq!(totp_step(&secret, "", NOW), None);
+    }
+}
@@ -0,0 +1,127 @@
+// signed links that turn off one kind of email without logging in, sent in the footer and in
+// the `List-Unsubscribe` header of every email the user can turn off (RFC 8058)
+
+use std::{env, sync::OnceLock};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::notifications::NotificationKind;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// loads the signing key from `UNSUBSCRIBE_SECRET_KEY`. it has to stay the same across restarts,
+/// or the links in emails already sent stop working. called at startup so a missing key stops
+/// the server before the first email
+pub fn secret_key() -> &'static [u8] {
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        let key = env::var("UNSUBSCRIBE_SECRET_KEY").expect("UNSUBSCRIBE_SECRET_KEY must be set");
+        assert!(
+            key.len() >= 32,
+            "UNSUBSCRIBE_SECRET_KEY must have at least 32 bytes"
+        );
+        key.into_bytes()
+    })
+}
+
+/// public address of the site, used in links sent by email (`SITE_URL`)
+pub fn site_url() -> String {
+    env::var("SITE_URL").unwrap_or_else(|_| "https://coisandocoisas.cc".to_string())
+}
+
+fn signature(payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret_key()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// token for the user's link of that kind, as `{user id}.{kind}.{signature}`
+pub fn token(user_id: Uuid, kind: NotificationKind) -> String {
+    let payload = format!("{}.{}", user_id.simple(), kind.code());
+    let signature = hex::encode(signature(&payload).finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+/// the user and kind of a token, `None` when it was changed or wasn't made here
+pub fn verify(token: &str) -> Option<(Uuid, NotificationKind)> {
+    let (payload, signature_hex) = token.rsplit_once('.')?;
+    let (user_id, kind) = payload.split_once('.')?;
+    let user_id = Uuid::parse_str(user_id).ok()?;
+    let kind = NotificationKind::from_code(kind)?;
+
+    // the comparison inside `verify_slice` takes the same time however much of it matches
+    let received = hex::decode(signature_hex).ok()?;
+    signature(payload).verify_slice(&received).ok()?;
+    Some((user_id, kind))
+}
+
+/// the address in the `List-Unsubscribe` header, which mail clients post to directly
+pub fn one_click_url(user_id: Uuid, kind: NotificationKind) -> String {
+    format!("{}/unsubscribe?token={}", site_url(), token(user_id, kind))
+}
+
+/// the address of the link in the footer, a page asking to confirm. opening a link doesn't
+/// unsubscribe, since link scanners open them too
+pub fn page_url(user_id: Uuid, kind: NotificationKind) -> String {
+    format!(
+        "{}/cancelar-inscri%C3%A7%C3%A3o?token={}",
+        site_url(),
+        token(user_id, kind)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Once;
+
+    use super::*;
+
+    // the key is read once, every test sets the same one before it's loaded
+    fn with_key() {
+        static SET: Once = Once::new();
+        SET.call_once(|| env::set_var("UNSUBSCRIBE_SECRET_KEY", "k".repeat(32)));
+    }
+
+    #[test]
+    fn token_is_verified() {
+        with_key();
+        let user_id = Uuid::new_v4();
+        let token = token(user_id, NotificationKind::SavedSearch);
+        assert_eq!(
+            verify(&token),
+            Some((user_id, NotificationKind::SavedSearch))
+        );
+    }
+
+    #[test]
+    fn changed_token_is_refused() {
+        with_key();
+        let user_id = Uuid::new_v4();
+        let token = token(user_id, NotificationKind::SavedSearch);
+
+        // another kind with the same signature
+        let other_kind = token.replacen("buscas", "reservas", 1);
+        assert_eq!(verify(&other_kind), None);
+        // another user with the same signature
+        let other_user = token.replacen(
+            &user_id.simple().to_string(),
+            &Uuid::new_v4().simple().to_string(),
+            1,
+        );
+        assert_eq!(verify(&other_user), None);
+        // a signature that was cut short
+        assert_eq!(verify(&token[..token.len() - 2]), None);
+    }
+
+    #[test]
+    fn malformed_token_is_refused() {
+        with_key();
+        assert_eq!(verify(""), None);
+        assert_eq!(verify("not a token"), None);
+        assert_eq!(verify("a.b.c"), None);
+        let user_id = Uuid::new_v4().simple();
+        assert_eq!(verify(&format!("{}.buscas.zz", user_id)), None);
+    }
+}